@@ -11,6 +11,7 @@ use serde_json::json;
 pub fn benchmarking(c: &mut Criterion)
 {
     let mut opt = args::Arguments::from_iter(Vec::<String>::new().into_iter());
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let sample_size = 500;
@@ -18,10 +18,78 @@ pub struct Arguments
     #[structopt(short, long)]
     pub ignore_config: bool,
 
+    /// Do not load the databases listed in the config database at startup
+    #[structopt(long)]
+    pub no_autoload: bool,
+
+    /// Start even if some of the databases listed in the config database could not be autoloaded
+    #[structopt(long)]
+    pub allow_autoload_errors: bool,
+
     /// Allow all database versions to be loaded (may cause instability)
     #[structopt(short, long)]
     pub allow_all: bool,
 
+    /// Record every change to a database in a journal, which is replayed when the database is next read from disk
+    #[structopt(short, long)]
+    pub journal: bool,
+
+    /// Write every database with unsaved changes to disk at this interval, in seconds
+    #[structopt(long)]
+    pub autosave: Option<u64>,
+
+    /// Number of seconds the session tokens handed out on authentication can be used to resume a session
+    #[structopt(long, default_value = "3600")]
+    pub session_lifetime: u64,
+
+    /// Number of seconds events queued for offline users are held, unless the event gives its own `ttl`
+    #[structopt(long, default_value = "86400")]
+    pub event_queue_ttl: u64,
+
+    /// Longest number of seconds an event may be queued for, whatever `ttl` the event gives
+    #[structopt(long, default_value = "604800")]
+    pub event_queue_max_ttl: u64,
+
+    /// Most events which may be queued for a single offline user
+    #[structopt(long, default_value = "1000")]
+    pub event_queue_user_limit: usize,
+
+    /// Most events which may be queued for every offline user together
+    #[structopt(long, default_value = "100000")]
+    pub event_queue_limit: usize,
+
+    /// Number of seconds to wait for the reply to an rpc event, unless the event gives its own `timeout`
+    #[structopt(long, default_value = "30")]
+    pub rpc_timeout: u64,
+
+    /// Longest number of seconds to wait for the reply to an rpc event, whatever `timeout` the event gives
+    #[structopt(long, default_value = "3600")]
+    pub rpc_max_timeout: u64,
+
+    /// OAuth client id Google id tokens must be issued for, required unless g_auth is disabled
+    #[structopt(long, env = "OAUTH_CLIENT_ID")]
+    pub google_client_id: Option<String>,
+
+    /// Disable authentication with Google id tokens
+    #[structopt(long)]
+    pub no_g_auth: bool,
+
+    /// File path or URL of the JWKS Google id tokens are verified against
+    #[structopt(long, default_value = "https://www.googleapis.com/oauth2/v3/certs")]
+    pub jwks: String,
+
+    /// Number of seconds the keys loaded from the JWKS are cached for
+    #[structopt(long, default_value = "3600")]
+    pub jwks_cache: u64,
+
+    /// Issuers Google id tokens are accepted from
+    #[structopt(long, use_delimiter = true, default_value = "accounts.google.com,https://accounts.google.com")]
+    pub google_issuers: Vec<String>,
+
+    /// Only accept Google id tokens for accounts of this hosted (G Suite) domain
+    #[structopt(long)]
+    pub google_hosted_domain: Option<String>,
+
     /// IP address to connect the server to (overrides the config database)
     #[structopt(long)]
     pub ip: Option<String>,
@@ -37,4 +105,33 @@ pub struct Arguments
     /// Config Path
     #[structopt(parse(from_os_str), default_value = "databases-dev/")]
     pub config_path: std::path::PathBuf,
+
+    #[structopt(subcommand)]
+    pub subcommand: Option<Subcommand>,
+}
+
+/// Maintenance tasks run in place of starting the server
+#[derive(Debug, StructOpt, Clone)]
+#[structopt(rename_all = "kebab-case")]
+pub enum Subcommand
+{
+    /// Mint a new token for an a_user, storing its hash in the config database and printing the token, the server must
+    /// be stopped as it would overwrite the config database
+    MintToken
+    {
+        /// Name of the a_user the token is for
+        user: String,
+
+        /// Existing token to store the hash of in place of a new token, which migrates a token stored in plaintext
+        #[structopt(long)]
+        token: Option<String>,
+
+        /// Label stored alongside the token, to tell the tokens of a user apart
+        #[structopt(long)]
+        label: Option<String>,
+
+        /// Number of seconds until the token expires, the token never expires if this is not given
+        #[structopt(long)]
+        expires_in: Option<u64>,
+    }
 }
\ No newline at end of file
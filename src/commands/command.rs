@@ -22,7 +22,30 @@ pub enum Commands
     CreateDatabase,
     AcronymAuth,
     GoogleAuth,
-    Event
+    Event,
+    Subscribe,
+    Unsubscribe,
+    Transaction,
+    CompareAndSet,
+    Increment,
+    Decrement,
+    Toggle,
+    GetPath,
+    SetPath,
+    DeletePath,
+    PatchValue,
+    DeleteKey,
+    UnloadDatabase,
+    DeleteDatabase,
+    GetPermissions,
+    SetPermissions,
+    GetMetadata,
+    Resume,
+    Logout,
+    Auth,
+    GetQueue,
+    PurgeQueue,
+    EventReply
 }
 
 /// Errors from parsing
@@ -93,6 +116,29 @@ impl Command
                             "a_auth" => Commands::AcronymAuth,
                             "g_auth" => Commands::GoogleAuth,
                             "event" => Commands::Event,
+                            "subscribe" => Commands::Subscribe,
+                            "unsubscribe" => Commands::Unsubscribe,
+                            "transaction" => Commands::Transaction,
+                            "compare_and_set" => Commands::CompareAndSet,
+                            "increment" => Commands::Increment,
+                            "decrement" => Commands::Decrement,
+                            "toggle" => Commands::Toggle,
+                            "get_path" => Commands::GetPath,
+                            "set_path" => Commands::SetPath,
+                            "delete_path" => Commands::DeletePath,
+                            "patch_value" => Commands::PatchValue,
+                            "delete_key" => Commands::DeleteKey,
+                            "unload_database" => Commands::UnloadDatabase,
+                            "delete_database" => Commands::DeleteDatabase,
+                            "get_permissions" => Commands::GetPermissions,
+                            "set_permissions" => Commands::SetPermissions,
+                            "get_metadata" => Commands::GetMetadata,
+                            "resume" => Commands::Resume,
+                            "logout" => Commands::Logout,
+                            "auth" => Commands::Auth,
+                            "get_queue" => Commands::GetQueue,
+                            "purge_queue" => Commands::PurgeQueue,
+                            "event_reply" => Commands::EventReply,
                             _ => 
                             {
                                 let msg = format!("cmd field of an unknown type {:?}", cmd);
@@ -19,13 +19,43 @@ fn test_command_parsing(test_output: bool)
         "{\"cmd\": \"create_database\", \"db_key\": \"DB_KEY\"}",
         "{\"cmd\": \"a_auth\", \"id\": \"ID\", \"token\":\"TOKEN\"}",
         "{\"cmd\": \"g_auth\", \"id_token\": \"ID_TOKEN\"}",
-        "{\"cmd\": \"event\", \"event_id\":\"ID\", \"destination\":\"DEST\", \"origin\": \"ORIGIN\", \"data\": \"DATA\"}"];
+        "{\"cmd\": \"event\", \"event_id\":\"ID\", \"destination\":\"DEST\", \"origin\": \"ORIGIN\", \"data\": \"DATA\"}",
+        "{\"cmd\": \"subscribe\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"unsubscribe\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"transaction\", \"cmds\": []}",
+        "{\"cmd\": \"compare_and_set\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\", \"expected\":\"DATA\", \"val\":\"DATA\"}",
+        "{\"cmd\": \"increment\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"decrement\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\", \"by\": 2}",
+        "{\"cmd\": \"toggle\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"get_path\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\", \"path\": \"/PATH\"}",
+        "{\"cmd\": \"set_path\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\", \"path\": \"/PATH\", \"val\":\"DATA\"}",
+        "{\"cmd\": \"delete_path\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\", \"path\": \"/PATH\"}",
+        "{\"cmd\": \"patch_value\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\", \"val\": {}}",
+        "{\"cmd\": \"delete_key\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"unload_database\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"delete_database\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"get_permissions\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"set_permissions\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\", \"val\": {}}",
+        "{\"cmd\": \"get_metadata\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"resume\", \"session\": \"SESSION\"}",
+        "{\"cmd\": \"logout\"}",
+        "{\"cmd\": \"auth\", \"domain\": \"DOMAIN\"}",
+        "{\"cmd\": \"get_queue\"}",
+        "{\"cmd\": \"purge_queue\", \"event_id\": \"ID\"}",
+        "{\"cmd\": \"event_reply\", \"event_id\": \"ID\", \"data\": \"DATA\"}"];
 
     let cmd_types = vec![Commands::WriteToDisk, Commands::ReadFromDisk, Commands::ListKeys, Commands::ListDatabases,
                                     Commands::GetValue, Commands::SetValue, Commands::GetIndex,
                                     Commands::SetIndex, Commands::AppendIndex, Commands::GetLengthIndex,
                                     Commands::GetRecentIndex, Commands::CreateDatabase, Commands::AcronymAuth,
-                                    Commands::GoogleAuth, Commands::Event];
+                                    Commands::GoogleAuth, Commands::Event, Commands::Subscribe,
+                                    Commands::Unsubscribe, Commands::Transaction, Commands::CompareAndSet,
+                                    Commands::Increment, Commands::Decrement, Commands::Toggle, Commands::GetPath,
+                                    Commands::SetPath, Commands::DeletePath, Commands::PatchValue,
+                                    Commands::DeleteKey, Commands::UnloadDatabase, Commands::DeleteDatabase,
+                                    Commands::GetPermissions, Commands::SetPermissions, Commands::GetMetadata,
+                                    Commands::Resume, Commands::Logout, Commands::Auth,
+                                    Commands::GetQueue, Commands::PurgeQueue, Commands::EventReply];
 
     for (example, desired) in examples.iter().zip(cmd_types.iter())
     {
@@ -154,7 +184,29 @@ pub fn test_command_parsing_packet_arguments_failure()
         "{\"cmd\": \"event\", \"event_i\":\"ID\", \"destination\":\"DEST\", \"origin\": \"ORIGIN\", \"data\": \"DATA\"}",
         "{\"cmd\": \"event\", \"event_id\":\"ID\", \"destinatio\":\"DEST\", \"origin\": \"ORIGIN\", \"data\": \"DATA\"}",
         "{\"cmd\": \"event\", \"event_id\":\"ID\", \"destination\":\"DEST\", \"origi\": \"ORIGIN\", \"data\": \"DATA\"}",
-        "{\"cmd\": \"event\", \"event_id\":\"ID\", \"destination\":\"DEST\", \"origin\": \"ORIGIN\", \"dat\": \"DATA\"}"];
+        "{\"cmd\": \"event\", \"event_id\":\"ID\", \"destination\":\"DEST\", \"origin\": \"ORIGIN\", \"dat\": \"DATA\"}",
+
+        "{\"cmd\": \"subscribe\",\"ke\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"subscribe\",\"key\":\"KEY\", \"db_ke\": \"DB_KEY\"}",
+
+        "{\"cmd\": \"unsubscribe\",\"ke\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"unsubscribe\",\"key\":\"KEY\", \"db_ke\": \"DB_KEY\"}",
+        "{\"cmd\": \"transaction\", \"commands\": []}",
+        "{\"cmd\": \"compare_and_set\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\", \"val\":\"DATA\"}",
+        "{\"cmd\": \"increment\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"toggle\",\"key\":\"KEY\"}",
+        "{\"cmd\": \"get_path\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"set_path\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\", \"path\": \"/PATH\"}",
+        "{\"cmd\": \"patch_value\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"delete_key\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"unload_database\"}",
+        "{\"cmd\": \"delete_database\", \"key\": \"DB_KEY\"}",
+        "{\"cmd\": \"get_permissions\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"set_permissions\",\"key\":\"KEY\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"get_metadata\", \"db_key\": \"DB_KEY\"}",
+        "{\"cmd\": \"resume\", \"token\": \"SESSION\"}",
+        "{\"cmd\": \"auth\", \"id\": \"ID\"}",
+        "{\"cmd\": \"event_reply\", \"data\": \"DATA\"}"];
 
     for example in examples
     {
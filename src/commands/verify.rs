@@ -9,18 +9,41 @@ pub fn verify_command(command: &Command) -> Result<(), CommandParsingError>
     {
         Commands::AcronymAuth => {["id", "token", "token", "token"]},
         Commands::AppendIndex => {["db_key", "key", "val", "val"]},
+        Commands::Auth => {["domain", "domain", "domain", "domain"]},
+        Commands::CompareAndSet => {["db_key", "key", "expected", "val"]},
         Commands::CreateDatabase => {["db_key", "db_key", "db_key", "db_key"]},
+        Commands::Decrement => {["db_key", "key", "key", "key"]},
+        Commands::DeleteDatabase => {["db_key", "db_key", "db_key", "db_key"]},
+        Commands::DeleteKey => {["db_key", "key", "key", "key"]},
+        Commands::DeletePath => {["db_key", "key", "path", "path"]},
         Commands::Event => {["event_id", "destination", "origin", "data"]},
+        Commands::EventReply => {["event_id", "data", "data", "data"]},
         Commands::GetIndex => {["db_key", "key", "index", "index"]},
         Commands::GetLengthIndex => {["db_key", "key", "key", "key"]},
+        Commands::GetMetadata => {["db_key", "key", "key", "key"]},
+        Commands::GetQueue => {["cmd", "cmd", "cmd", "cmd"]},
+        Commands::GetPath => {["db_key", "key", "path", "path"]},
+        Commands::GetPermissions => {["db_key", "key", "key", "key"]},
         Commands::GetRecentIndex => {["db_key", "key", "num", "num"]},
         Commands::GetValue => {["db_key", "key", "key", "key"]},
         Commands::GoogleAuth => {["id_token", "id_token", "id_token", "id_token"]},
+        Commands::Increment => {["db_key", "key", "key", "key"]},
         Commands::ListKeys => {["db_key", "db_key", "db_key", "db_key"]},
         Commands::ListDatabases => {["cmd", "cmd", "cmd", "cmd"]},
+        Commands::Logout => {["cmd", "cmd", "cmd", "cmd"]},
+        Commands::PatchValue => {["db_key", "key", "val", "val"]},
+        Commands::PurgeQueue => {["cmd", "cmd", "cmd", "cmd"]},
         Commands::ReadFromDisk => {["db_key", "db_key", "db_key", "db_key"]},
+        Commands::Resume => {["session", "session", "session", "session"]},
         Commands::SetIndex => {["db_key", "key", "val", "index"]},
+        Commands::SetPermissions => {["db_key", "key", "val", "val"]},
+        Commands::SetPath => {["db_key", "key", "path", "val"]},
         Commands::SetValue => {["db_key", "key", "val", "val"]},
+        Commands::Subscribe => {["db_key", "key", "key", "key"]},
+        Commands::Toggle => {["db_key", "key", "key", "key"]},
+        Commands::Transaction => {["cmds", "cmds", "cmds", "cmds"]},
+        Commands::Unsubscribe => {["db_key", "key", "key", "key"]},
+        Commands::UnloadDatabase => {["db_key", "db_key", "db_key", "db_key"]},
         Commands::WriteToDisk => {["db_key", "db_key", "db_key", "db_key"]}
     };
 
@@ -9,10 +9,14 @@ use tokio::sync::Mutex;
 
 use serde_json::json;
 
-use chashmap::CHashMap;
-
 type SendingChannel = std::sync::Arc<tokio::sync::mpsc::UnboundedSender<std::result::Result<tokio_tungstenite::tungstenite::Message, tokio_tungstenite::tungstenite::Error>>>;
 
+/// Held by every task which must finish before the server shuts down
+type TaskTracker = tokio::sync::mpsc::Sender<()>;
+
+/// Set to true once the server begins shutting down
+type ShutdownSignal = tokio::sync::watch::Receiver<bool>;
+
 macro_rules! checked_send {
     ($tx:expr, $msg:expr) => {
         if let Err(e) = $tx.send($msg)
@@ -33,6 +37,14 @@ macro_rules! checked_send {
 pub async fn run(opt: args::Arguments) -> Result<(), String>
 {
     log::info!("Starting ACI Server");
+
+    if opt.google_client_id.is_none() && !opt.no_g_auth
+    {
+        let msg = "A Google OAuth client id must be given with --google-client-id or OAUTH_CLIENT_ID, or g_auth disabled with --no-g-auth".to_string();
+        log::error!("{}", msg);
+        return Err(msg);
+    }
+
     let aci = std::sync::Arc::new(server::Server::new(&opt)?);
 
     let ip = if opt.ignore_config || opt.ip.is_some()
@@ -71,8 +83,6 @@ pub async fn run(opt: args::Arguments) -> Result<(), String>
         aci.config_get_port()?
     };
 
-    let connections_hashmap: std::sync::Arc<CHashMap<String, SendingChannel>> = std::sync::Arc::new(CHashMap::new());
-
     let addr = format!("{}:{}", ip, port);
     log::info!("Connecting to address `{}`", addr);
 
@@ -84,16 +94,132 @@ pub async fn run(opt: args::Arguments) -> Result<(), String>
         return Err(msg);
     };
 
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let (tracker, mut tracker_rx) = tokio::sync::mpsc::channel::<()>(1);
+
+    // Periodically write any changed databases to disk, on the blocking thread pool so the file IO does not hold up
+    // the connections, until the server shuts down
+    let autosave = opt.autosave.map(|seconds|
+    {
+        let aci = aci.clone();
+        let mut shutdown_rx = shutdown_rx.clone();
+        tokio::spawn(async move
+        {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(seconds.max(1)));
+            interval.tick().await;
+
+            loop
+            {
+                tokio::select!
+                {
+                    _ = interval.tick() => {},
+                    _ = shutdown_rx.changed() => break
+                }
+
+                let aci = aci.clone();
+                match tokio::task::spawn_blocking(move || aci.write_dirty_databases()).await
+                {
+                    Ok(Ok(written)) if !written.is_empty() => log::info!("Autosaved databases {:?}", written),
+                    Ok(Ok(_)) => {},
+                    Ok(Err(e)) => log::error!("Autosave failed: {}", e),
+                    Err(e) => log::error!("Autosave task failed: {}", e)
+                }
+            }
+        })
+    });
+
+    let shutdown = wait_for_shutdown();
+    tokio::pin!(shutdown);
+
     // Reading loop
-    while let Ok((stream, _)) = conn.accept().await
+    loop
     {
-        tokio::spawn(handle_stream(stream, aci.clone(), connections_hashmap.clone()));
+        tokio::select!
+        {
+            accepted = conn.accept() =>
+            {
+                match accepted
+                {
+                    Ok((stream, _)) =>
+                    {
+                        tokio::spawn(handle_stream(stream, aci.clone(), tracker.clone(), shutdown_rx.clone()));
+                    },
+                    Err(e) =>
+                    {
+                        log::error!("Unable to accept connection ({})", e);
+                        break;
+                    }
+                }
+            },
+            _ = &mut shutdown =>
+            {
+                log::info!("Shutting down, no longer accepting connections");
+                break;
+            }
+        }
+    }
+
+    // Stop every connection from reading, and wait for the messages already received to be handled
+    if shutdown_tx.send(true).is_err()
+    {
+        log::debug!("No connections to notify of shutdown");
+    }
+
+    drop(tracker);
+    tracker_rx.recv().await;
+
+    // Let any autosave already writing finish, so it does not race the final write
+    if let Some(autosave) = autosave
+    {
+        if let Err(e) = autosave.await
+        {
+            log::error!("Autosave task failed: {}", e);
+        }
+    }
+
+    log::info!("Writing unsaved databases to disk");
+    let aci = aci.clone();
+    match tokio::task::spawn_blocking(move || aci.write_dirty_databases()).await
+    {
+        Ok(result) => result.map(|_| ()),
+        Err(e) =>
+        {
+            let msg = format!("Unable to write unsaved databases to disk: {}", e);
+            log::error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Wait for the process to be asked to stop, either by SIGINT or SIGTERM
+async fn wait_for_shutdown()
+{
+    #[cfg(unix)]
+    {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        {
+            Ok(mut terminate) =>
+            {
+                tokio::select!
+                {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = terminate.recv() => {}
+                }
+
+                return;
+            },
+            Err(e) => log::error!("Unable to listen for SIGTERM ({})", e)
+        }
     }
 
-    Ok(())
+    if let Err(e) = tokio::signal::ctrl_c().await
+    {
+        log::error!("Unable to listen for SIGINT ({})", e);
+        futures_util::future::pending::<()>().await;
+    }
 }
 
-pub async fn handle_stream(stream: TcpStream, aci: std::sync::Arc<server::Server>, connections_hashmap: std::sync::Arc<CHashMap<String, SendingChannel>>)
+pub async fn handle_stream(stream: TcpStream, aci: std::sync::Arc<server::Server>, tracker: TaskTracker, mut shutdown: ShutdownSignal)
 {
     let interface = std::sync::Arc::new(Mutex::new(server::ServerInterface::new(&aci)));
 
@@ -101,11 +227,32 @@ pub async fn handle_stream(stream: TcpStream, aci: std::sync::Arc<server::Server
 
     log::info!("Got peer connection from '{}'", addr);
 
-    let ws_stream = if let Ok(s) = tokio_tungstenite::accept_async(stream).await {s} else
+    if *shutdown.borrow()
     {
-        let msg = format!("Unable to open websocket with peer at `{}`", addr);
-        log::error!("{}", msg);
+        log::info!("Dropping connection from `{}` as the server is shutting down", addr);
         return;
+    }
+
+    let ws_stream = tokio::select!
+    {
+        ws_stream = tokio_tungstenite::accept_async(stream) =>
+        {
+            match ws_stream
+            {
+                Ok(s) => s,
+                Err(_) =>
+                {
+                    let msg = format!("Unable to open websocket with peer at `{}`", addr);
+                    log::error!("{}", msg);
+                    return;
+                }
+            }
+        },
+        _ = shutdown.changed() =>
+        {
+            log::info!("Dropping connection from `{}` as the server is shutting down", addr);
+            return;
+        }
     };
 
     let (wstx, mut rx) = ws_stream.split();
@@ -116,8 +263,47 @@ pub async fn handle_stream(stream: TcpStream, aci: std::sync::Arc<server::Server
 
     let tx = std::sync::Arc::new(stx);
 
-    while let Some(msg) = rx.next().await
+    // Forward subscription updates for this connection over the websocket
+    let (utx, mut urx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+    interface.lock().await.attach_updates(utx);
+
+    let update_tx = tx.clone();
+    tokio::spawn(async move
+    {
+        while let Some(update) = urx.recv().await
+        {
+            checked_send!(update_tx, Ok(tokio_tungstenite::tungstenite::Message::Text(update.to_string())));
+        }
+    });
+
+    // Forward events addressed to this connection over the websocket
+    let (etx, mut erx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+    interface.lock().await.attach_events(etx);
+
+    let event_tx = tx.clone();
+    tokio::spawn(async move
     {
+        while let Some(event) = erx.recv().await
+        {
+            checked_send!(event_tx, Ok(tokio_tungstenite::tungstenite::Message::Text(event.to_string())));
+        }
+    });
+
+    loop
+    {
+        let msg = tokio::select!
+        {
+            msg = rx.next() =>
+            {
+                if let Some(msg) = msg {msg} else {break}
+            },
+            _ = shutdown.changed() =>
+            {
+                log::info!("Closing connection with `{}` as the server is shutting down", addr);
+                break;
+            }
+        };
+
         match msg
         {
             Ok(msg) =>
@@ -130,7 +316,7 @@ pub async fn handle_stream(stream: TcpStream, aci: std::sync::Arc<server::Server
                         {
                             if let Ok(val) = serde_json::from_str::<serde_json::Value>(&text)
                             {
-                                tokio::spawn(handle_message(tx.clone(), val, interface.clone(), connections_hashmap.clone()));
+                                tokio::spawn(handle_message(tx.clone(), val, interface.clone(), aci.clone(), tracker.clone()));
                             }
                             else
                             {
@@ -167,23 +353,19 @@ pub async fn handle_stream(stream: TcpStream, aci: std::sync::Arc<server::Server
         }
     }
 
-    let id = interface.lock().await.user_profile.name.clone();
+    interface.lock().await.detach_updates();
+    interface.lock().await.detach_events();
 
-    log::info!("Removing user `{}`", id);
-
-    if connections_hashmap.contains_key(&id)
-    {
-        connections_hashmap.remove(&id);
-    }
+    log::info!("Removing connection {} with `{}`", interface.lock().await.connection_id(), addr);
 }
 
-async fn handle_message(tx: SendingChannel, val: serde_json::Value, aci_interface: std::sync::Arc<Mutex<server::ServerInterface>>, connections_hashmap: std::sync::Arc<CHashMap<String, SendingChannel>>)
+async fn handle_message(tx: SendingChannel, val: serde_json::Value, aci_interface: std::sync::Arc<Mutex<server::ServerInterface>>, aci: std::sync::Arc<server::Server>, _tracker: TaskTracker)
 {
     match val
     {
         serde_json::Value::Object(obj) => 
         {
-            let json = handle_individual(tx.clone(), serde_json::Value::Object(obj), aci_interface, connections_hashmap).await;
+            let json = handle_individual(serde_json::Value::Object(obj), aci_interface, aci).await;
 
             if let Ok(Some(json)) = json
             {
@@ -195,7 +377,7 @@ async fn handle_message(tx: SendingChannel, val: serde_json::Value, aci_interfac
             let mut result = vec![];
             for value in values
             {
-                let json = handle_individual(tx.clone(), value, aci_interface.clone(), connections_hashmap.clone()).await;
+                let json = handle_individual(value, aci_interface.clone(), aci.clone()).await;
 
                 if let Ok(Some(json)) = json
                 {
@@ -215,7 +397,7 @@ async fn handle_message(tx: SendingChannel, val: serde_json::Value, aci_interfac
     }
 }
 
-async fn handle_individual(tx: SendingChannel, val: serde_json::Value, aci_interface: std::sync::Arc<Mutex<server::ServerInterface>>, connections_hashmap: std::sync::Arc<CHashMap<String, SendingChannel>>) -> Result<Option<serde_json::Value>, ()>
+async fn handle_individual(val: serde_json::Value, aci_interface: std::sync::Arc<Mutex<server::ServerInterface>>, aci: std::sync::Arc<server::Server>) -> Result<Option<serde_json::Value>, ()>
 {
     let no_ack = if let serde_json::Value::Object(map) = &val
     {
@@ -244,45 +426,44 @@ async fn handle_individual(tx: SendingChannel, val: serde_json::Value, aci_inter
         {
             if let serde_json::Value::Object(map) = command.data
             {
-                if let Some(serde_json::Value::String(dest)) = map.get("destination")
+                let (origin_connection, sender) =
                 {
-                    if let Some(conn) = connections_hashmap.get(dest)
-                    {
-                        checked_send!(conn, Ok(tokio_tungstenite::tungstenite::Message::Text(
-                            val.to_string()
-                        )), Err(()));
+                    let interface = aci_interface.lock().await;
+                    (interface.connection_id(), interface.user_profile.clone())
+                };
 
-                        if no_ack
-                        {
-                            return Ok(None);
-                        }
-                        {   
-                            if let (Some(event_id), Some(origin)) = (map.get("event_id"), map.get("origin"))
-                            {
-                                return Ok(Some(serde_json::json!({"cmd": "event", "mode": "ack", "event_id": event_id, "origin": origin})));
-                            }
-                        }   
-                    }
-                    else
+                let result = aci.relay_event(origin_connection, &sender, &map);
+
+                // Give up on an rpc event if its destination does not reply in time
+                if let (Ok((_, Some((number, timeout)))), Some(serde_json::Value::String(event_id))) = (&result, map.get("event_id"))
+                {
+                    let (aci, event_id, number) = (aci.clone(), event_id.clone(), *number);
+                    let timeout = std::time::Duration::from_secs(*timeout);
+
+                    tokio::spawn(async move
                     {
-                        log::warn!("Attempted to forward event to `{}`, however, this user is not connected", dest);
-                        let msg = format!("Unable to connect to user `{}`", dest);
-                        
-                        if no_ack
-                        {
-                            return Ok(None);
-                        }
-                        {   
-                            if let (Some(event_id), Some(origin)) = (map.get("event_id"), map.get("origin"))
-                            {
-                            return Ok(Some(serde_json::json!({"cmd": "event", "mode": "error", "msg": msg, "event_id": event_id, "origin": origin})));
-                            }
-                        } 
-                    }
+                        tokio::time::sleep(timeout).await;
+                        aci.expire_rpc(origin_connection, &event_id, number);
+                    });
                 }
-                else
+
+                if no_ack
                 {
-                    log::error!("Event command does not include destination, ignoring packet");
+                    return Ok(None);
+                }
+
+                // The ack names the sender as the origin, whatever origin the event claimed
+                if let Some(event_id) = map.get("event_id")
+                {
+                    let origin = &sender.name;
+
+                    return Ok(Some(match result
+                    {
+                        Ok((delivery, _)) => serde_json::json!({"cmd": "event", "mode": "ack", "event_id": event_id, "origin": origin,
+                                                                "delivered": delivery.delivered, "offline": delivery.offline, "queued": delivery.queued,
+                                                                "denied": delivery.denied}),
+                        Err(msg) => serde_json::json!({"cmd": "event", "mode": "error", "msg": msg, "event_id": event_id, "origin": origin})
+                    }));
                 }
             }
             else
@@ -293,8 +474,6 @@ async fn handle_individual(tx: SendingChannel, val: serde_json::Value, aci_inter
             return Err(());
         }
 
-        let is_auth_command = command.cmd == commands::Commands::AcronymAuth || command.cmd == commands::Commands::GoogleAuth;
-
         let result = aci_interface.lock().await.execute_command(command);
 
         let json_msg = match result
@@ -317,14 +496,6 @@ async fn handle_individual(tx: SendingChannel, val: serde_json::Value, aci_inter
         };
 
         log::debug!("Sending data back {:?}", json_msg);
-        
-        if is_auth_command
-        {
-            let id = aci_interface.lock().await.user_profile.name.clone();
-            connections_hashmap.insert(id.clone(), tx.clone());
-
-            log::debug!("Adding Connection to user `{}` to connections map", id);
-        }
 
         if no_ack
         {
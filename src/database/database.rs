@@ -1,17 +1,52 @@
-use serde_json::Value;
+use serde_json::{Value, Number};
 use chashmap::CHashMap;
 use log::{trace, error, debug};
 
+use std::convert::TryFrom;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use std::ops::DerefMut;
 
+use super::{pointer_get, pointer_set, pointer_delete, apply_patch};
+
+/// Add or subtract two json numbers, staying an integer unless either side is a float, an integer result which does not
+/// fit in an i64 or u64 is an error rather than losing precision
+fn combine_numbers(a: &Number, b: &Number, subtract: bool) -> Result<Number, String>
+{
+    let as_integer = |n: &Number| n.as_i64().map(i128::from).or_else(|| n.as_u64().map(i128::from));
+
+    if let (Some(a), Some(b)) = (as_integer(a), as_integer(b))
+    {
+        let result = if subtract {a - b} else {a + b};
+
+        if let Ok(result) = i64::try_from(result)
+        {
+            return Ok(Number::from(result));
+        }
+
+        if let Ok(result) = u64::try_from(result)
+        {
+            return Ok(Number::from(result));
+        }
+
+        return Err(format!("the result {} overflows a 64 bit integer", result));
+    }
+
+    match (a.as_f64(), b.as_f64())
+    {
+        (Some(a), Some(b)) => Number::from_f64(if subtract {a - b} else {a + b}).ok_or_else(|| "the result is not a finite number".to_string()),
+        _ => Err("the numbers cannot be combined".to_string())
+    }
+}
+
 /// Database object per ACI documentation
 #[derive(Debug, Clone)]
 pub struct Database
 {
     name: String,
-    data: Arc<CHashMap<String, Value>>
+    data: Arc<CHashMap<String, Value>>,
+    dirty: Arc<AtomicBool>
 }
 
 impl Database
@@ -24,7 +59,8 @@ impl Database
         Self
         {
             name: name.to_string(),
-            data: Arc::new(CHashMap::new())
+            data: Arc::new(CHashMap::new()),
+            dirty: Arc::new(AtomicBool::new(true))
         }
     }
 
@@ -36,7 +72,8 @@ impl Database
         Self
         {
             name: name.to_string(),
-            data: Arc::new(data)
+            data: Arc::new(data),
+            dirty: Arc::new(AtomicBool::new(false))
         }
     }
 
@@ -46,6 +83,7 @@ impl Database
         trace!("Writing {} to `{}` in database {}", data, key, self.name);
 
         self.data.insert(key.to_string(), data);
+        self.mark_dirty();
         Ok(())
     }
 
@@ -138,6 +176,7 @@ impl Database
             }
 
             array[index] = data;
+            self.mark_dirty();
             Ok(())
         }
         else
@@ -159,6 +198,7 @@ impl Database
         {
             let l = array.len();
             array.push(data);
+            self.mark_dirty();
 
             Ok(l)
         }
@@ -213,6 +253,258 @@ impl Database
         }
     }
 
+    /// Write to a key only if its current value equals the expected value, returns whether the value was written along
+    /// with the value stored after the operation
+    pub fn compare_and_set(&self, key: &str, expected: &Value, data: Value) -> Result<(bool, Value), String>
+    {
+        trace!("Comparing `{}` in database {} against {} before writing {}", key, self.name, expected, data);
+
+        if let Some(mut current) = self.data.get_mut(key)
+        {
+            if *current != *expected
+            {
+                return Ok((false, current.clone()));
+            }
+
+            *current = data;
+            self.mark_dirty();
+
+            Ok((true, current.clone()))
+        }
+        else
+        {
+            let msg = format!("Key `{}` not found in database", key);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+
+    /// Add to a number stored in the hashmap, returning the new value
+    pub fn increment(&self, key: &str, by: &Value) -> Result<Value, String>
+    {
+        trace!("Incrementing `{}` in database {} by {}", key, self.name, by);
+
+        self.add_to_number(key, by, false)
+    }
+
+    /// Subtract from a number stored in the hashmap, returning the new value
+    pub fn decrement(&self, key: &str, by: &Value) -> Result<Value, String>
+    {
+        trace!("Decrementing `{}` in database {} by {}", key, self.name, by);
+
+        self.add_to_number(key, by, true)
+    }
+
+    /// Add or subtract from a number stored in the hashmap while holding the lock on the key
+    fn add_to_number(&self, key: &str, by: &Value, subtract: bool) -> Result<Value, String>
+    {
+        let by = if let Value::Number(by) = by
+        {
+            by
+        }
+        else
+        {
+            let msg = format!("The amount to change `{}` by is not a number", key);
+            error!("{}", msg);
+            return Err(msg);
+        };
+
+        if let Some(mut current) = self.data.get_mut(key)
+        {
+            let result = if let Value::Number(n) = current.deref_mut()
+            {
+                combine_numbers(n, by, subtract)
+            }
+            else
+            {
+                let msg = format!("The value for key `{}` is not a number", key);
+                error!("{}", msg);
+                return Err(msg);
+            };
+
+            match result
+            {
+                Ok(n) =>
+                {
+                    *current = Value::Number(n);
+                    self.mark_dirty();
+
+                    Ok(current.clone())
+                },
+                Err(e) =>
+                {
+                    let msg = format!("The value for key `{}` cannot be changed by {}, {}", key, by, e);
+                    error!("{}", msg);
+                    Err(msg)
+                }
+            }
+        }
+        else
+        {
+            let msg = format!("Key `{}` not found in database", key);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+
+    /// Invert a boolean stored in the hashmap, returning the new value
+    pub fn toggle(&self, key: &str) -> Result<bool, String>
+    {
+        trace!("Toggling `{}` in database {}", key, self.name);
+
+        if let Some(mut current) = self.data.get_mut(key)
+        {
+            if let Value::Bool(b) = current.deref_mut()
+            {
+                *b = !*b;
+                let result = *b;
+                self.mark_dirty();
+
+                Ok(result)
+            }
+            else
+            {
+                let msg = format!("The value for key `{}` is not a boolean", key);
+                error!("{}", msg);
+                Err(msg)
+            }
+        }
+        else
+        {
+            let msg = format!("Key `{}` not found in database", key);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+
+    /// Read the value a JSON pointer refers to inside the value stored in the hashmap
+    pub fn read_path(&self, key: &str, pointer: &str) -> Result<Value, String>
+    {
+        trace!("Reading `{}` from `{}` in database {}", pointer, key, self.name);
+
+        if let Some(data) = self.data.get(key)
+        {
+            pointer_get(&data, pointer)
+        }
+        else
+        {
+            let msg = format!("Key `{}` not found in database", key);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+
+    /// Set the value a JSON pointer refers to inside the value stored in the hashmap
+    pub fn write_path(&self, key: &str, pointer: &str, data: Value) -> Result<(), String>
+    {
+        trace!("Writing {} to `{}` in `{}` in database {}", data, pointer, key, self.name);
+
+        if let Some(mut current) = self.data.get_mut(key)
+        {
+            pointer_set(current.deref_mut(), pointer, data)?;
+            self.mark_dirty();
+
+            Ok(())
+        }
+        else
+        {
+            let msg = format!("Key `{}` not found in database", key);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+
+    /// Remove the value a JSON pointer refers to inside the value stored in the hashmap, returning the removed value
+    pub fn delete_path(&self, key: &str, pointer: &str) -> Result<Value, String>
+    {
+        trace!("Deleting `{}` from `{}` in database {}", pointer, key, self.name);
+
+        if let Some(mut current) = self.data.get_mut(key)
+        {
+            let removed = pointer_delete(current.deref_mut(), pointer)?;
+            self.mark_dirty();
+
+            Ok(removed)
+        }
+        else
+        {
+            let msg = format!("Key `{}` not found in database", key);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+
+    /// Apply a JSON Patch or JSON Merge Patch to the value stored in the hashmap, returning the new value
+    pub fn patch(&self, key: &str, patch: &Value) -> Result<Value, String>
+    {
+        trace!("Patching `{}` in database {} with {}", key, self.name, patch);
+
+        if let Some(mut current) = self.data.get_mut(key)
+        {
+            apply_patch(current.deref_mut(), patch)?;
+            self.mark_dirty();
+
+            Ok(current.clone())
+        }
+        else
+        {
+            let msg = format!("Key `{}` not found in database", key);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+
+    /// Check if a key is stored in the database
+    pub fn contains_key(&self, key: &str) -> bool
+    {
+        self.data.contains_key(key)
+    }
+
+    /// Remove a key from the database, returning the value it held
+    pub fn remove(&self, key: &str) -> Result<Value, String>
+    {
+        trace!("Removing `{}` from database `{}`", key, self.name);
+
+        if let Some(data) = self.data.remove(key)
+        {
+            self.mark_dirty();
+            Ok(data)
+        }
+        else
+        {
+            let msg = format!("Key `{}` not found in database", key);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+
+    /// Remove every key from the database
+    pub fn clear(&self)
+    {
+        trace!("Clearing all data from database {}", self.name);
+
+        self.data.clear();
+        self.mark_dirty();
+    }
+
+    /// Flag the database as having changes which have not been written to disk
+    pub fn mark_dirty(&self)
+    {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Flag the database as matching the copy on disk, returning whether it was dirty
+    pub fn mark_clean(&self) -> bool
+    {
+        self.dirty.swap(false, Ordering::SeqCst)
+    }
+
+    /// Check if the database has changes which have not been written to disk
+    pub fn is_dirty(&self) -> bool
+    {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
     /// Gets the name of the database
     pub fn get_name(&self) -> String
     {
@@ -0,0 +1,113 @@
+use serde_json::Value;
+use log::{trace, warn};
+
+use super::{Database, UserAuthentication, entry_domain};
+
+use std::sync::{Arc, RwLock};
+
+/// Group memberships, read from the `groups` item of the config database
+#[derive(Debug, Clone)]
+pub struct GroupDirectory
+{
+    config: Arc<RwLock<Option<Database>>>
+}
+
+impl GroupDirectory
+{
+    /// Create a directory without any groups
+    pub fn new() -> Self
+    {
+        Self
+        {
+            config: Arc::new(RwLock::new(None))
+        }
+    }
+
+    /// Set the config database groups are read from
+    pub fn set_config(&self, config: &Database)
+    {
+        match self.config.write()
+        {
+            Ok(mut guard) => *guard = Some(config.clone()),
+            Err(poisoned) => *poisoned.into_inner() = Some(config.clone())
+        }
+    }
+
+    /// Get the members of a group, each as a `[domain, name]` entry
+    fn members(&self, group: &str) -> Vec<Value>
+    {
+        let guard = match self.config.read()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        };
+
+        let config = match &*guard
+        {
+            Some(config) if config.contains_key("groups") => config,
+            _ => return vec![]
+        };
+
+        match config.read("groups")
+        {
+            Ok(Value::Object(mut groups)) => match groups.remove(group)
+            {
+                Some(Value::Array(members)) => members,
+                Some(other) =>
+                {
+                    warn!("Members of group `{}` are not in an array, got {}", group, other);
+                    vec![]
+                },
+                None => vec![]
+            },
+            _ =>
+            {
+                warn!("Groups in the config database are not an object");
+                vec![]
+            }
+        }
+    }
+
+    /// Check if a user is a member of a group
+    pub fn is_member(&self, group: &str, user: &UserAuthentication) -> bool
+    {
+        if !user.is_authed
+        {
+            return false;
+        }
+
+        trace!("Checking if user `{}`:`{}` is in group `{}`", user.domain, user.name, group);
+
+        let domain = match entry_domain(&user.domain)
+        {
+            Some(domain) => domain,
+            None => return false
+        };
+
+        self.members(group).iter().any(|member| member[0] == domain.as_str() && member[1] == user.name.as_str())
+    }
+
+    /// Get the members of a group as `(domain, name)` pairs, with the domain naming them in permissions
+    pub fn member_entries(&self, group: &str) -> Vec<(String, String)>
+    {
+        self.members(group).iter().filter_map(|member| match (member[0].as_str(), member[1].as_str())
+        {
+            (Some(domain), Some(name)) => Some((domain.to_string(), name.to_string())),
+            _ => None
+        }).collect()
+    }
+
+    /// Check if a user is a member of any of the given groups
+    pub fn is_member_of_any(&self, groups: &[String], user: &UserAuthentication) -> bool
+    {
+        groups.iter().any(|group| self.is_member(group, user))
+    }
+}
+
+impl std::default::Default for GroupDirectory
+{
+    fn default() -> GroupDirectory
+    {
+        GroupDirectory::new()
+    }
+}
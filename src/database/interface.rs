@@ -1,19 +1,43 @@
 use super::Database;
 use super::Permission;
+use super::DefaultPermissions;
+use super::Owner;
 use super::UserAuthentication;
+use super::SubscriberRegistry;
+use super::Subscriber;
+use super::GroupDirectory;
+use super::Journal;
+use super::item_type;
 
 use log::{trace, error, warn};
 use chashmap::CHashMap;
-use serde_json::Value;
+use serde_json::{Value, json};
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+
+/// Function hiding part of the value of a key from every reader
+pub type Redaction = fn(&Value) -> Value;
+
+/// Function checking a whole new value of a key before it is written
+pub type Validation = fn(&Value) -> Result<(), String>;
+
+/// Keys changed by committing a staged copy of a database, each with its previous value so the commit can be reverted
+pub type StagedChanges = Vec<(String, Option<Value>)>;
 
 /// Database interface
 #[derive(Debug, Clone)]
 pub struct DatabaseInterface
 {
     pub database: Arc<Database>,
-    pub permissions: Arc<CHashMap<String, Permission>>
+    pub permissions: Arc<CHashMap<String, Permission>>,
+    pub defaults: Arc<RwLock<DefaultPermissions>>,
+    pub owners: Arc<CHashMap<String, Owner>>,
+    pub subscriptions: Arc<CHashMap<String, Vec<Subscriber>>>,
+    pub subscribers: SubscriberRegistry,
+    pub groups: GroupDirectory,
+    pub redactions: Arc<CHashMap<String, Redaction>>,
+    pub validations: Arc<CHashMap<String, Validation>>,
+    pub journal: Option<Arc<Journal>>
 }
 
 impl DatabaseInterface
@@ -26,16 +50,59 @@ impl DatabaseInterface
         Self
         {
             database: Arc::new(database),
-            permissions: Arc::new(permissions)
+            permissions: Arc::new(permissions),
+            defaults: Arc::new(RwLock::new(DefaultPermissions::default())),
+            owners: Arc::new(CHashMap::new()),
+            subscriptions: Arc::new(CHashMap::new()),
+            subscribers: SubscriberRegistry::new(),
+            groups: GroupDirectory::new(),
+            redactions: Arc::new(CHashMap::new()),
+            validations: Arc::new(CHashMap::new()),
+            journal: None
+        }
+    }
+
+    /// Get the permissions new keys in the database are created with
+    pub fn default_permissions(&self) -> DefaultPermissions
+    {
+        match self.defaults.read()
+        {
+            Ok(guard) => guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Replace the permissions new keys in the database are created with, existing keys are unchanged
+    pub fn set_default_permissions(&self, defaults: DefaultPermissions)
+    {
+        match self.defaults.write()
+        {
+            Ok(mut guard) => *guard = defaults,
+            Err(poisoned) => *poisoned.into_inner() = defaults
+        }
+    }
+
+    /// Check if a user is the owner of a key, owners can always read, write and change the permissions of their keys
+    pub fn is_owner(&self, key: &str, user: &UserAuthentication) -> bool
+    {
+        match self.owners.get(key)
+        {
+            Some(owner) => owner.is_user(user),
+            None => false
         }
     }
 
     /// Verify a user can read from a key
-    fn check_read(&self, key: &str, user: &UserAuthentication) -> Result<(), String>
+    pub fn check_read(&self, key: &str, user: &UserAuthentication) -> Result<(), String>
     {
+        if self.is_owner(key, user)
+        {
+            return Ok(());
+        }
+
         if let Some(permissions) = self.permissions.get(key)
         {
-            if !permissions.check_user_read(user)?
+            if !permissions.check_user_read(user, &self.groups)?
             {
                 let msg = format!("User not authenticated {:?}", user);
                 warn!("{}", msg);
@@ -52,31 +119,108 @@ impl DatabaseInterface
         Ok(())
     }
 
-    /// Verify a user can write to a key
-    fn check_write(&self, key: &str, user: &UserAuthentication, add_new_permission: bool) -> Result<(), String>
+    /// Verify a user can write to a key without registering any permissions, returns true if the key does not yet
+    /// have permissions, in which case the user must be allowed to write by the permissions the key would inherit
+    pub fn verify_write(&self, key: &str, user: &UserAuthentication, allow_new: bool) -> Result<bool, String>
     {
+        if self.is_owner(key, user)
+        {
+            return Ok(false);
+        }
+
         if let Some(permissions) = self.permissions.get(key)
         {
-            if !permissions.check_user_write(user)?
+            if !permissions.check_user_write(user, &self.groups)?
             {
                 let msg = format!("User not authenticated {:?}", user);
                 warn!("{}", msg);
                 return Err(msg);
             }
+
+            Ok(false)
+        }
+        else if !allow_new
+        {
+            let msg = format!("Key `{}` does not have permissions", key);
+            error!("{}", msg);
+            Err(msg)
+        }
+        else if !self.default_permissions().for_key(key).check_user_write(user, &self.groups)?
+        {
+            let msg = format!("User not authenticated to create key `{}` {:?}", key, user);
+            warn!("{}", msg);
+            Err(msg)
         }
         else
         {
-            if !add_new_permission
+            Ok(true)
+        }
+    }
+
+    /// Verify a user can write to a key, returns true if permissions were created for a new key
+    fn check_write(&self, key: &str, user: &UserAuthentication, add_new_permission: bool) -> Result<bool, String>
+    {
+        if self.verify_write(key, user, add_new_permission)?
+        {
+            warn!("Key `{}` does not yet exist, creating default permissions for it", key);
+            self.register_new_permission(key)?;
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Verify a user can change the permissions of a key
+    pub fn check_admin(&self, key: &str, user: &UserAuthentication) -> Result<(), String>
+    {
+        if self.is_owner(key, user)
+        {
+            return Ok(());
+        }
+
+        if let Some(permissions) = self.permissions.get(key)
+        {
+            if !permissions.check_user_admin(user, &self.groups)?
             {
-                let msg = format!("Key `{}` does not have permissions", key);
-                error!("{}", msg);
+                let msg = format!("User not authenticated {:?}", user);
+                warn!("{}", msg);
                 return Err(msg);
             }
-            else
+        }
+        else
+        {
+            let msg = format!("Key `{}` does not have permissions", key);
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        Ok(())
+    }
+
+    /// Verify a user can write to every key in the database
+    pub fn check_write_all(&self, user: &UserAuthentication) -> Result<(), String>
+    {
+        for key in self.database.get_all_keys()?
+        {
+            self.check_write(&key, user, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the user creating a key as its owner
+    fn register_owner(&self, key: &str, user: &UserAuthentication) -> Result<(), String>
+    {
+        if let Some(owner) = Owner::from_user(user)
+        {
+            trace!("Registering `{}` as the owner of `{}` in database `{}`", owner.name, key, self.database.get_name());
+
+            self.journaled(|| json!({"op": "set_owner", "key": key, "val": owner.create_json()}), ||
             {
-                warn!("Key `{}` does not yet exist, creating default permissions for it", key);
-                self.register_new_permission(key)?;
-            }
+                self.owners.insert(key.to_string(), owner.clone());
+                Ok(())
+            })?;
         }
 
         Ok(())
@@ -86,7 +230,452 @@ impl DatabaseInterface
     fn register_new_permission(&self, name: &str) -> Result<(), String>
     {
         trace!("Registering a new permission `{}` to the interface for database `{}`", name, self.database.get_name());
-        self.permissions.insert(name.to_string(), Permission::default());
+        self.permissions.insert(name.to_string(), self.default_permissions().for_key(name));
+
+        Ok(())
+    }
+
+    /// Apply a mutation to the database, recording it in the journal once it succeeds if a journal is attached
+    fn journaled<T>(&self, entry: impl FnOnce() -> Value, apply: impl FnOnce() -> Result<T, String>) -> Result<T, String>
+    {
+        self.journaled_if(|_| true, entry, apply)
+    }
+
+    /// Apply a mutation to the database, only recording it in the journal if it succeeds and its result is accepted by
+    /// the filter, the journal is locked across both so entries are recorded in the order they were applied
+    fn journaled_if<T>(&self, filter: impl FnOnce(&T) -> bool, entry: impl FnOnce() -> Value, apply: impl FnOnce() -> Result<T, String>) -> Result<T, String>
+    {
+        match &self.journal
+        {
+            Some(journal) =>
+            {
+                let _guard = journal.lock();
+                let result = apply()?;
+
+                // If the entry cannot be recorded the change is still held in memory, and the database is dirty so it
+                // is written by the next save
+                if filter(&result)
+                {
+                    journal.record(&entry())?;
+                }
+
+                Ok(result)
+            },
+            None => apply()
+        }
+    }
+
+    /// Apply the entries of a journal to the database, without recording them again or checking permissions
+    pub fn replay_journal(&self, entries: &[Value]) -> Result<(), String>
+    {
+        for entry in entries
+        {
+            let op = entry.get("op").and_then(Value::as_str).unwrap_or_default();
+            if op == "batch"
+            {
+                self.replay_journal(entry["entries"].as_array().map(Vec::as_slice).unwrap_or_default())?;
+                continue;
+            }
+
+            if op == "clear"
+            {
+                self.database.clear();
+                self.permissions.clear();
+                self.subscriptions.clear();
+                self.owners.clear();
+                self.set_default_permissions(DefaultPermissions::new(entry.get("default_permissions"), entry.get("prefix_permissions"))?);
+                continue;
+            }
+
+            let key = match entry.get("key").and_then(Value::as_str)
+            {
+                Some(key) => key,
+                None =>
+                {
+                    warn!("Journal entry {} does not have a key, skipping", entry);
+                    continue;
+                }
+            };
+            let val = entry.get("val").cloned().unwrap_or(Value::Null);
+            let index = entry.get("index").and_then(Value::as_u64).map(|i| i as usize);
+
+            let result = match (op, index)
+            {
+                ("set", _) =>
+                {
+                    if !self.permissions.contains_key(key)
+                    {
+                        self.register_new_permission(key)?;
+                    }
+
+                    self.database.write(key, val)
+                },
+                ("set_index", Some(index)) => self.database.write_index(key, index, val),
+                ("append", _) => self.database.append(key, val).map(|_| ()),
+                ("compare_and_set", _) =>
+                {
+                    let expected = entry.get("expected").cloned().unwrap_or(Value::Null);
+                    self.database.compare_and_set(key, &expected, val).map(|_| ())
+                },
+                ("increment", _) => self.database.increment(key, &entry["by"]).map(|_| ()),
+                ("decrement", _) => self.database.decrement(key, &entry["by"]).map(|_| ()),
+                ("toggle", _) => self.database.toggle(key).map(|_| ()),
+                ("set_path", _) => self.database.write_path(key, entry["path"].as_str().unwrap_or_default(), val),
+                ("delete", _) =>
+                {
+                    self.permissions.remove(key);
+                    self.subscriptions.remove(key);
+                    self.owners.remove(key);
+                    self.database.remove(key).map(|_| ())
+                },
+                ("set_owner", _) => Owner::from_json(&val).map(|owner|
+                {
+                    if let Some(owner) = owner
+                    {
+                        self.owners.insert(key.to_string(), owner);
+                    }
+                }),
+                ("set_permissions", _) => Permission::new(&val, key).map(|permission| {self.permissions.insert(key.to_string(), permission);}),
+                ("patch", _) => self.database.patch(key, &val).map(|_| ()),
+                ("delete_path", _) => self.database.delete_path(key, entry["path"].as_str().unwrap_or_default()).map(|_| ()),
+                _ => Err(format!("Unknown journal entry {}", entry))
+            };
+
+            if let Err(e) = result
+            {
+                warn!("Unable to replay journal entry {} on database `{}` ({})", entry, self.database.get_name(), e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the redaction applied to reads of a key, if it has one
+    fn redaction(&self, key: &str) -> Option<Redaction>
+    {
+        self.redactions.get(key).map(|redaction| *redaction)
+    }
+
+    /// Apply the redaction for a key to a value read from it
+    fn visible(&self, key: &str, value: Value) -> Value
+    {
+        match self.redaction(key)
+        {
+            Some(redact) => redact(&value),
+            None => value
+        }
+    }
+
+    /// Get the database reads of a key should be made from, a key with a redaction is read from a redacted copy
+    fn readable(&self, key: &str) -> Result<Database, String>
+    {
+        match self.redaction(key)
+        {
+            Some(redact) =>
+            {
+                let copy = Database::new(&self.database.get_name());
+                copy.write(key, redact(&self.database.read(key)?))?;
+
+                Ok(copy)
+            },
+            None => Ok((*self.database).clone())
+        }
+    }
+
+    /// Check a whole new value for a key against the validation for the key, if it has one
+    fn validate(&self, key: &str, value: &Value) -> Result<(), String>
+    {
+        match self.validations.get(key)
+        {
+            Some(validate) => validate(value),
+            None => Ok(())
+        }
+    }
+
+    /// Check the value a change would leave in a key against the validation for the key, by applying the change to a
+    /// scratch copy of the key first, so keys without a validation are never copied
+    fn validate_change<T>(&self, key: &str, change: impl FnOnce(&Database) -> Result<T, String>) -> Result<(), String>
+    {
+        if !self.validations.contains_key(key)
+        {
+            return Ok(());
+        }
+
+        let scratch = Database::new(&self.database.get_name());
+        scratch.write(key, self.database.read(key)?)?;
+        change(&scratch)?;
+
+        self.validate(key, &scratch.read(key)?)
+    }
+
+    /// Push an update to every user subscribed to a key, a key with a redaction always sends its full redacted value
+    fn notify_subscribers(&self, key: &str, update: impl FnOnce() -> Value)
+    {
+        if let Some(subs) = self.subscriptions.get(key)
+        {
+            if subs.is_empty()
+            {
+                return;
+            }
+
+            let update = match (self.redaction(key), self.database.read(key))
+            {
+                (Some(redact), Ok(value)) =>
+                    json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": self.database.get_name(), "key": key, "val": redact(&value)}),
+                _ => update()
+            };
+
+            // Read permissions are checked for every update, so users lose their subscriptions along with their access
+            for subscriber in subs.iter()
+            {
+                self.subscribers.notify(subscriber, &update, |user| self.check_read(key, user).is_ok());
+            }
+        }
+    }
+
+    /// Subscribe a user to updates on a key
+    pub fn subscribe(&self, key: &str, user: &UserAuthentication) -> Result<(), String>
+    {
+        self.check_read(key, user)?;
+
+        trace!("Subscribing `{}` to key `{}` in database `{}`", user.name, key, self.database.get_name());
+
+        let subscriber = Subscriber::from_user(user);
+        self.subscriptions.upsert(key.to_string(), || vec![subscriber.clone()], |subs|
+        {
+            if !subs.contains(&subscriber)
+            {
+                subs.push(subscriber.clone());
+            }
+        });
+
+        // Subscriptions are stored with the items
+        self.database.mark_dirty();
+
+        Ok(())
+    }
+
+    /// Unsubscribe a user from updates on a key
+    pub fn unsubscribe(&self, key: &str, user: &UserAuthentication) -> Result<(), String>
+    {
+        trace!("Unsubscribing `{}` from key `{}` in database `{}`", user.name, key, self.database.get_name());
+
+        let mut is_empty = false;
+
+        if let Some(mut subs) = self.subscriptions.get_mut(key)
+        {
+            subs.retain(|subscriber| !subscriber.is_user(user));
+            is_empty = subs.is_empty();
+        }
+
+        if is_empty
+        {
+            self.subscriptions.remove(key);
+        }
+
+        self.database.mark_dirty();
+
+        Ok(())
+    }
+
+    /// Get the users subscribed to a key
+    pub fn get_subscribers(&self, key: &str) -> Vec<Subscriber>
+    {
+        match self.subscriptions.get(key)
+        {
+            Some(subs) => subs.clone(),
+            None => vec![]
+        }
+    }
+
+    /// Create a detached copy of the given keys and their permissions, for changes to be staged against
+    pub fn stage_keys(&self, keys: &[String]) -> Result<DatabaseInterface, String>
+    {
+        trace!("Staging keys {:?} from database `{}`", keys, self.database.get_name());
+
+        let database = Database::create(&self.database.get_name(), CHashMap::new());
+        let permissions = CHashMap::new();
+
+        for key in keys
+        {
+            if self.database.contains_key(key)
+            {
+                database.write(key, self.database.read(key)?)?;
+            }
+
+            if let Some(permission) = self.permissions.get(key)
+            {
+                permissions.insert(key.clone(), permission.clone());
+            }
+        }
+
+        let mut staged = DatabaseInterface::new(database, permissions);
+        staged.groups = self.groups.clone();
+        staged.defaults = self.defaults.clone();
+        staged.redactions = self.redactions.clone();
+        staged.validations = self.validations.clone();
+
+        for key in keys
+        {
+            if let Some(owner) = self.owners.get(key)
+            {
+                staged.owners.insert(key.clone(), owner.clone());
+            }
+        }
+
+        Ok(staged)
+    }
+
+    /// Apply every key changed in a staged copy of the database, the changes must have already passed the permission
+    /// checks on the staged copy, returns the keys changed along with their previous values so the commit can be
+    /// reverted, subscribers are not notified until `notify_committed` is called
+    pub fn commit_staged(&self, staged: &DatabaseInterface) -> Result<StagedChanges, String>
+    {
+        let mut entries = vec![];
+        let mut changes = vec![];
+
+        for key in staged.database.get_all_keys()?
+        {
+            let data = staged.database.read(&key)?;
+
+            let previous = if self.database.contains_key(&key)
+            {
+                Some(self.database.read(&key)?)
+            }
+            else
+            {
+                None
+            };
+
+            if previous.as_ref() == Some(&data)
+            {
+                continue;
+            }
+
+            self.validate(&key, &data)?;
+
+            let owner = staged.owners.get(&key).map(|owner| owner.clone()).filter(|_| previous.is_none());
+
+            if let Some(owner) = &owner
+            {
+                entries.push(json!({"op": "set_owner", "key": key, "val": owner.create_json()}));
+            }
+
+            entries.push(json!({"op": "set", "key": key, "val": data}));
+            changes.push((key, previous, data, owner));
+        }
+
+        let reverts: StagedChanges = changes.iter().map(|(key, previous, _, _)| (key.clone(), previous.clone())).collect();
+
+        if changes.is_empty()
+        {
+            return Ok(reverts);
+        }
+
+        // The changes are recorded as a single entry, so the journal never holds part of them
+        let _guard = self.journal.as_ref().map(|journal| journal.lock());
+
+        for (key, previous, data, owner) in changes
+        {
+            if previous.is_none()
+            {
+                let permission = match staged.permissions.get(&key)
+                {
+                    Some(permission) => permission.clone(),
+                    None => Permission::default()
+                };
+
+                self.permissions.insert(key.clone(), permission);
+
+                if let Some(owner) = owner
+                {
+                    self.owners.insert(key.clone(), owner);
+                }
+            }
+
+            if let Err(e) = self.database.write(&key, data)
+            {
+                self.restore(&reverts);
+                return Err(e);
+            }
+        }
+
+        if let Some(journal) = &self.journal
+        {
+            if let Err(e) = journal.record(&json!({"op": "batch", "entries": entries}))
+            {
+                self.restore(&reverts);
+                return Err(e);
+            }
+        }
+
+        Ok(reverts)
+    }
+
+    /// Put keys back to their previous values, removing any which did not exist
+    fn restore(&self, previous: &[(String, Option<Value>)])
+    {
+        for (key, value) in previous
+        {
+            let result = match value
+            {
+                Some(value) => self.database.write(key, value.clone()),
+                None =>
+                {
+                    self.permissions.remove(key);
+                    self.owners.remove(key);
+                    self.database.remove(key).map(|_| ())
+                }
+            };
+
+            if let Err(e) = result
+            {
+                error!("Unable to restore key `{}` in database `{}` ({})", key, self.database.get_name(), e);
+            }
+        }
+    }
+
+    /// Undo a commit made by `commit_staged`, used when a transaction fails to commit to a later database
+    pub fn revert_staged(&self, previous: &[(String, Option<Value>)]) -> Result<(), String>
+    {
+        if previous.is_empty()
+        {
+            return Ok(());
+        }
+
+        let entries: Vec<Value> = previous.iter().map(|(key, value)| match value
+        {
+            Some(value) => json!({"op": "set", "key": key, "val": value}),
+            None => json!({"op": "delete", "key": key})
+        }).collect();
+
+        self.journaled(|| json!({"op": "batch", "entries": entries}), ||
+        {
+            self.restore(previous);
+            Ok(())
+        })
+    }
+
+    /// Send subscribers the values of the keys changed by a commit made by `commit_staged`
+    pub fn notify_committed(&self, changed: &[(String, Option<Value>)])
+    {
+        for (key, _) in changed
+        {
+            if let Ok(data) = self.database.read(key)
+            {
+                self.notify_subscribers(key, ||
+                    json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": self.database.get_name(), "key": key, "val": data}));
+            }
+        }
+    }
+
+    /// Write to a key in the database once permissions have been checked
+    fn store(&self, key: &str, data: Value) -> Result<(), String>
+    {
+        self.journaled(|| json!({"op": "set", "key": key, "val": data}), || self.database.write(key, data.clone()))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": self.database.get_name(), "key": key, "val": data}));
 
         Ok(())
     }
@@ -94,36 +683,250 @@ impl DatabaseInterface
     /// Write to a key in the database
     pub fn write_to_key(&self, key: &str, data: Value, user: &UserAuthentication) -> Result<(), String>
     {
-        self.check_write(key, user, true)?;
-        self.database.write(key, data)
+        self.validate(key, &data)?;
+
+        if self.check_write(key, user, true)?
+        {
+            self.register_owner(key, user)?;
+        }
+
+        self.store(key, data)
+    }
+
+    /// Remove a key from the database, along with its permissions and subscriptions
+    pub fn delete_key(&self, key: &str, user: &UserAuthentication) -> Result<(), String>
+    {
+        self.check_write(key, user, false)?;
+        self.journaled(|| json!({"op": "delete", "key": key}), || self.database.remove(key))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "delete", "db_key": self.database.get_name(), "key": key}));
+
+        self.permissions.remove(key);
+        self.subscriptions.remove(key);
+        self.owners.remove(key);
+
+        Ok(())
+    }
+
+    /// Get the permissions of a key in the database
+    pub fn get_permissions(&self, key: &str, user: &UserAuthentication) -> Result<Value, String>
+    {
+        self.check_admin(key, user)?;
+        self.read_permissions(key)
+    }
+
+    /// Get the permissions of a key in the database, the caller must have checked the user may administer the key
+    pub fn read_permissions(&self, key: &str) -> Result<Value, String>
+    {
+        match self.permissions.get(key)
+        {
+            Some(permission) => permission.create_json(),
+            None =>
+            {
+                let msg = format!("Key `{}` does not have permissions", key);
+                error!("{}", msg);
+                Err(msg)
+            }
+        }
+    }
+
+    /// Replace the permissions of a key in the database
+    pub fn set_permissions(&self, key: &str, data: &Value, user: &UserAuthentication) -> Result<(), String>
+    {
+        self.check_admin(key, user)?;
+        self.replace_permissions(key, data)
+    }
+
+    /// Replace the permissions of a key in the database, the caller must have checked the user may administer the key
+    pub fn replace_permissions(&self, key: &str, data: &Value) -> Result<(), String>
+    {
+        if !self.permissions.contains_key(key)
+        {
+            let msg = format!("Key `{}` does not have permissions", key);
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        let permission = Permission::new(data, key)?;
+
+        trace!("Setting permissions of `{}` in database `{}` to {:?}", key, self.database.get_name(), permission);
+
+        self.journaled(|| json!({"op": "set_permissions", "key": key, "val": data}), ||
+        {
+            self.permissions.insert(key.to_string(), permission);
+            Ok(())
+        })?;
+
+        // Permissions are stored with the items
+        self.database.mark_dirty();
+
+        Ok(())
+    }
+
+    /// Get the owner and type of a key in the database
+    pub fn get_metadata(&self, key: &str, user: &UserAuthentication) -> Result<Value, String>
+    {
+        self.check_read(key, user)?;
+
+        let value = self.database.read(key)?;
+        let owner = match self.owners.get(key)
+        {
+            Some(owner) => owner.create_json(),
+            None => Value::Null
+        };
+
+        Ok(json!({"owner": owner, "type": item_type(&value)}))
     }
 
     /// Read from a key in the database
     pub fn read_from_key(&self, key: &str, user: &UserAuthentication) -> Result<Value, String>
     {
         self.check_read(key, user)?;
-        self.database.read(key)
+        self.readable(key)?.read(key)
     }
 
     /// Write to an index in a key in the database
     pub fn write_to_key_index(&self, key: &str, index: usize, data: Value, user: &UserAuthentication) -> Result<(), String>
     {
         self.check_write(key, user, false)?;
-        self.database.write_index(key, index, data)
+        self.validate_change(key, |scratch| scratch.write_index(key, index, data.clone()))?;
+        self.journaled(|| json!({"op": "set_index", "key": key, "index": index, "val": data}), || self.database.write_index(key, index, data.clone()))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "set_index", "db_key": self.database.get_name(), "key": key, "index": index, "val": data}));
+
+        Ok(())
     }
 
     /// Read from an index into a key in the database
     pub fn read_from_key_index(&self, key: &str, index: usize, user: &UserAuthentication) -> Result<Value, String>
     {
         self.check_read(key, user)?;
-        self.database.read_index(key, index)
+        self.readable(key)?.read_index(key, index)
     }
 
     /// Append to an array in a key in the database
     pub fn append_to_key(&self, key: &str, data: Value, user: &UserAuthentication) -> Result<usize, String>
     {
         self.check_write(key, user, false)?;
-        self.database.append(key, data)
+        self.validate_change(key, |scratch| scratch.append(key, data.clone()))?;
+        let index = self.journaled(|| json!({"op": "append", "key": key, "val": data}), || self.database.append(key, data.clone()))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "append", "db_key": self.database.get_name(), "key": key, "index": index, "val": data}));
+
+        Ok(index)
+    }
+
+    /// Write to a key in the database only if its current value equals the expected value, returns whether the value was
+    /// written along with the value stored after the operation
+    pub fn compare_and_set_key(&self, key: &str, expected: &Value, data: Value, user: &UserAuthentication) -> Result<(bool, Value), String>
+    {
+        self.check_read(key, user)?;
+        self.check_write(key, user, false)?;
+        self.validate(key, &data)?;
+        let (written, current) = self.journaled_if(|(written, _)| *written, || json!({"op": "compare_and_set", "key": key, "expected": expected, "val": data}),
+                                                   || self.database.compare_and_set(key, expected, data.clone()))?;
+
+        if written
+        {
+            self.notify_subscribers(key, ||
+                json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": self.database.get_name(), "key": key, "val": current}));
+        }
+
+        Ok((written, self.visible(key, current)))
+    }
+
+    /// Add to a number in a key in the database, returning the new value
+    pub fn increment_key(&self, key: &str, by: &Value, user: &UserAuthentication) -> Result<Value, String>
+    {
+        self.check_write(key, user, false)?;
+        self.validate_change(key, |scratch| scratch.increment(key, by))?;
+        let data = self.journaled(|| json!({"op": "increment", "key": key, "by": by}), || self.database.increment(key, by))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": self.database.get_name(), "key": key, "val": data}));
+
+        Ok(self.visible(key, data))
+    }
+
+    /// Subtract from a number in a key in the database, returning the new value
+    pub fn decrement_key(&self, key: &str, by: &Value, user: &UserAuthentication) -> Result<Value, String>
+    {
+        self.check_write(key, user, false)?;
+        self.validate_change(key, |scratch| scratch.decrement(key, by))?;
+        let data = self.journaled(|| json!({"op": "decrement", "key": key, "by": by}), || self.database.decrement(key, by))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": self.database.get_name(), "key": key, "val": data}));
+
+        Ok(self.visible(key, data))
+    }
+
+    /// Invert a boolean in a key in the database, returning the new value
+    pub fn toggle_key(&self, key: &str, user: &UserAuthentication) -> Result<bool, String>
+    {
+        self.check_write(key, user, false)?;
+        self.validate_change(key, |scratch| scratch.toggle(key))?;
+        let data = self.journaled(|| json!({"op": "toggle", "key": key}), || self.database.toggle(key))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": self.database.get_name(), "key": key, "val": data}));
+
+        Ok(data)
+    }
+
+    /// Read the value a JSON pointer refers to inside a key in the database
+    pub fn read_from_key_path(&self, key: &str, pointer: &str, user: &UserAuthentication) -> Result<Value, String>
+    {
+        self.check_read(key, user)?;
+        self.readable(key)?.read_path(key, pointer)
+    }
+
+    /// Write to the value a JSON pointer refers to inside a key in the database
+    pub fn write_to_key_path(&self, key: &str, pointer: &str, data: Value, user: &UserAuthentication) -> Result<(), String>
+    {
+        self.check_write(key, user, false)?;
+        self.validate_change(key, |scratch| scratch.write_path(key, pointer, data.clone()))?;
+        self.journaled(|| json!({"op": "set_path", "key": key, "path": pointer, "val": data}), || self.database.write_path(key, pointer, data.clone()))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "set_path", "db_key": self.database.get_name(), "key": key, "path": pointer, "val": data}));
+
+        Ok(())
+    }
+
+    /// Remove the value a JSON pointer refers to inside a key in the database, returning the removed value
+    pub fn delete_from_key_path(&self, key: &str, pointer: &str, user: &UserAuthentication) -> Result<Value, String>
+    {
+        self.check_write(key, user, false)?;
+        self.validate_change(key, |scratch| scratch.delete_path(key, pointer))?;
+        let removed = self.journaled(|| json!({"op": "delete_path", "key": key, "path": pointer}), || self.database.delete_path(key, pointer))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "delete_path", "db_key": self.database.get_name(), "key": key, "path": pointer}));
+
+        // Only part of the value was removed, so a redaction for the whole key cannot be applied to it
+        if self.redaction(key).is_some()
+        {
+            return Ok(Value::Null);
+        }
+
+        Ok(removed)
+    }
+
+    /// Apply a JSON Patch or JSON Merge Patch to a key in the database
+    pub fn patch_key(&self, key: &str, patch: &Value, user: &UserAuthentication) -> Result<(), String>
+    {
+        self.check_write(key, user, false)?;
+        self.validate_change(key, |scratch| scratch.patch(key, patch))?;
+        let data = self.journaled(|| json!({"op": "patch", "key": key, "val": patch}), || self.database.patch(key, patch))?;
+
+        self.notify_subscribers(key, ||
+            json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": self.database.get_name(), "key": key, "val": data}));
+
+        Ok(())
     }
 
     /// Get the length of an array in a key in the database
@@ -137,6 +940,6 @@ impl DatabaseInterface
     pub fn read_last_n_from_key(&self, key: &str, n: usize, user: &UserAuthentication) -> Result<Value, String>
     {
         self.check_read(key, user)?;
-        self.database.get_last_n(key, n)
+        self.readable(key)?.get_last_n(key, n)
     }
 }
\ No newline at end of file
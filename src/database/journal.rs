@@ -0,0 +1,117 @@
+use serde_json::Value;
+use log::{error, warn, debug};
+
+use std::io::Write;
+use std::sync::{Mutex, MutexGuard};
+
+/// Append-only record of the mutations made to a database since it was last written to disk
+#[derive(Debug)]
+pub struct Journal
+{
+    path: String,
+    lock: Mutex<()>
+}
+
+impl Journal
+{
+    /// Create a journal for the database with the given name stored in the given root directory
+    pub fn new(root: &str, name: &str) -> Result<Self, String>
+    {
+        let directory = format!("{}{}/", root, name);
+
+        if let Err(e) = std::fs::create_dir_all(&directory)
+        {
+            let msg = format!("Unable to create database directory `{}` for journal, {}", directory, e);
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        Ok(Self
+        {
+            path: journal_path(root, name),
+            lock: Mutex::new(())
+        })
+    }
+
+    /// Get the path to the journal file
+    pub fn get_path(&self) -> String
+    {
+        self.path.clone()
+    }
+
+    /// Block other mutations from being recorded until the guard is dropped
+    pub fn lock(&self) -> MutexGuard<'_, ()>
+    {
+        match self.lock.lock()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Append an entry to the journal and flush it to disk, the caller should hold the journal lock
+    pub fn record(&self, entry: &Value) -> Result<(), String>
+    {
+        let mut line = entry.to_string();
+        line.push('\n');
+
+        let result = std::fs::OpenOptions::new().create(true).append(true).open(&self.path).and_then(|mut file|
+        {
+            file.write_all(line.as_bytes())?;
+            file.sync_data()
+        });
+
+        match result
+        {
+            Ok(()) => Ok(()),
+            Err(e) =>
+            {
+                let msg = format!("Unable to append to journal `{}`, {}", self.path, e);
+                error!("{}", msg);
+                Err(msg)
+            }
+        }
+    }
+}
+
+/// Path to the journal file for a database in the given root directory
+pub fn journal_path(root: &str, name: &str) -> String
+{
+    format!("{0}{1}/{1}.journal", root, name)
+}
+
+/// Read every entry from a journal file, an entry which cannot be parsed (such as one cut short by a crash) is skipped
+pub fn read_journal(path: &str) -> Result<Vec<Value>, String>
+{
+    let data = match std::fs::read_to_string(path)
+    {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) =>
+        {
+            let msg = format!("Unable to read journal `{}` ({})", path, e);
+            error!("{}", msg);
+            return Err(msg);
+        }
+    };
+
+    let mut entries = vec![];
+
+    for (i, line) in data.lines().enumerate()
+    {
+        if line.trim().is_empty()
+        {
+            continue;
+        }
+
+        match serde_json::from_str(line)
+        {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!("Skipping unreadable entry {} in journal `{}` ({})", i, path, e)
+        }
+    }
+
+    debug!("Read {} entries from journal `{}`", entries.len(), path);
+
+    Ok(entries)
+}
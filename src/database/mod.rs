@@ -1,14 +1,29 @@
 pub mod database;
 pub use database::*;
 
+pub mod groups;
+pub use groups::*;
+
 pub mod interface;
 pub use interface::*;
 
+pub mod journal;
+pub use journal::*;
+
 pub mod parsing;
 pub use parsing::*;
 
+pub mod patch;
+pub use patch::*;
+
+pub mod pointer;
+pub use pointer::*;
+
 pub mod permissions;
 pub use permissions::*;
 
+pub mod subscriptions;
+pub use subscriptions::*;
+
 #[cfg(test)]
 pub mod tests;
\ No newline at end of file
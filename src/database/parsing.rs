@@ -1,7 +1,9 @@
 use serde_json::Value;
 use log::{error, warn, info, debug, trace};
 
-use super::{Database, Permission, DatabaseInterface};
+use std::io::Write;
+
+use super::{Database, Permission, DefaultPermissions, Owner, Subscriber, DatabaseInterface, Journal, journal_path, read_journal};
 use crate::args::Arguments;
 
 use chashmap::CHashMap;
@@ -83,11 +85,27 @@ fn read_json(path: &str) -> Result<Value, String>
     Ok(value)
 }
 
+/// Check a database name can be used as the name of its directory, names which are empty, start with a `.` or contain
+/// a path separator or `..` could reach outside of the database root directory
+pub fn check_database_name(name: &str) -> Result<(), String>
+{
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') || name.contains("..")
+    {
+        let msg = format!("Invalid database name `{}`", name);
+        error!("{}", msg);
+        return Err(msg);
+    }
+
+    Ok(())
+}
+
 /// Load a database from disk
 pub fn database_from_disk(path: &std::path::PathBuf, name: &str, opt: &Arguments) -> Result<DatabaseInterface, String>
 {
     info!("Loading database `{}` from {:?}", name, path);
 
+    check_database_name(name)?;
+
     // Convert path to a string, and correct if it does not end with a '/'
     let mut path = if let Some(p) = path.to_str()
     {
@@ -105,10 +123,20 @@ pub fn database_from_disk(path: &std::path::PathBuf, name: &str, opt: &Arguments
         path += "/";
     }
 
+    // If a previous write was interrupted part way through, put the last complete copy back in place
+    recover_interrupted_write(&path, name)?;
+
     // Load database file
     let database_path = format!("{0}{1}/{1}.database", path, name);
     debug!("Loading from `{}`", database_path);
 
+    // A database which has been changed but never written only has its journal on disk
+    if opt.journal && !std::path::Path::new(&database_path).exists() && std::path::Path::new(&journal_path(&path, name)).exists()
+    {
+        warn!("Database `{}` has a journal but has never been written to disk, rebuilding it from the journal", name);
+        return attach_journal(DatabaseInterface::new(Database::new(name), CHashMap::new()), &path, name);
+    }
+
     // Ensure the database file is a map
     let map = extract_object(&read_json(&database_path)?, "Database file data")?;
 
@@ -157,6 +185,8 @@ pub fn database_from_disk(path: &std::path::PathBuf, name: &str, opt: &Arguments
 
     let database_data: CHashMap<String, Value> = CHashMap::new();
     let permissions: CHashMap<String, Permission> = CHashMap::new();
+    let subscriptions: CHashMap<String, Vec<Subscriber>> = CHashMap::new();
+    let owners: CHashMap<String, Owner> = CHashMap::new();
 
     // Read each item in from its own files
     for key in item_keys
@@ -185,20 +215,276 @@ pub fn database_from_disk(path: &std::path::PathBuf, name: &str, opt: &Arguments
 
         // Create the permissions
         permissions.insert(found_key.clone(), Permission::new(map.get("permissions").unwrap(), &found_key)?);
+
+        // Load the subscribers, if any are recorded
+        if let Some(subs) = map.get("subs")
+        {
+            let subs = match subs
+            {
+                Value::Array(subs) => subs.iter().map(Subscriber::from_json).collect::<Result<Vec<Subscriber>, String>>()?,
+                _ =>
+                {
+                    let msg = format!("Subscribers for item `{}` is not an array", found_key);
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+            };
+
+            if !subs.is_empty()
+            {
+                subscriptions.insert(found_key.clone(), subs);
+            }
+        }
+
+        // Load the owner, items written before owners were recorded are owned by `"self"`
+        if let Some(owner) = Owner::from_json(map.get("owner").unwrap_or(&Value::Null))?
+        {
+            owners.insert(found_key.clone(), owner);
+        }
+    }
+
+    let mut interface = DatabaseInterface::new(Database::create(&database_key, database_data), permissions);
+    interface.set_default_permissions(DefaultPermissions::new(map.get("default_permissions"), map.get("prefix_permissions"))?);
+    interface.subscriptions = std::sync::Arc::new(subscriptions);
+    interface.owners = std::sync::Arc::new(owners);
+
+    if opt.journal
+    {
+        interface = attach_journal(interface, &path, name)?;
+    }
+
+    Ok(interface)
+}
+
+/// Create a new, empty journal for a database stored in the given root directory
+pub fn create_journal(path: &std::path::PathBuf, name: &str) -> Result<Journal, String>
+{
+    check_database_name(name)?;
+
+    // Convert path to a string, and correct if it does not end with a '/'
+    let mut root = if let Some(p) = path.to_str()
+    {
+        p.to_string()
+    }
+    else
+    {
+        let msg = format!("Unable to interpret path {:?}", path);
+        error!("{}", msg);
+        return Err(msg);
+    };
+
+    if !root.ends_with('/')
+    {
+        root += "/";
+    }
+
+    Journal::new(&root, name)
+}
+
+/// Remove every copy of a database stored in the given root directory, including any staged or backup copies
+pub fn remove_database_from_disk(path: &std::path::PathBuf, name: &str) -> Result<(), String>
+{
+    info!("Removing database `{}` from {:?}", name, path);
+
+    check_database_name(name)?;
+
+    // Convert path to a string, and correct if it does not end with a '/'
+    let mut root = if let Some(p) = path.to_str()
+    {
+        p.to_string()
     }
+    else
+    {
+        let msg = format!("Unable to interpret path {:?}", path);
+        error!("{}", msg);
+        return Err(msg);
+    };
 
-    Ok(DatabaseInterface::new(Database::create(&database_key, database_data), permissions))
+    if !root.ends_with('/')
+    {
+        root += "/";
+    }
+
+    remove_directory(&staging_path(&root, name))?;
+    remove_directory(&backup_path(&root, name))?;
+    remove_directory(&format!("{}{}/", root, name))?;
+
+    sync_directory(&root);
+
+    Ok(())
+}
+
+/// Replay the journal for a database over the data loaded from disk, and attach the journal to record further changes
+fn attach_journal(mut interface: DatabaseInterface, root: &str, name: &str) -> Result<DatabaseInterface, String>
+{
+    let entries = read_journal(&journal_path(root, name))?;
+
+    if !entries.is_empty()
+    {
+        info!("Replaying {} journal entries for database `{}`", entries.len(), name);
+        interface.replay_journal(&entries)?;
+    }
+
+    interface.journal = Some(std::sync::Arc::new(Journal::new(root, name)?));
+
+    Ok(interface)
+}
+
+/// Name of the type of a value, as stored in the `type` field of an item
+pub fn item_type(value: &Value) -> &'static str
+{
+    match value
+    {
+        Value::Array(_) => "table",
+        Value::Object(_) => "obj",
+        _ => "string"
+    }
+}
+
+/// Write data to a file and flush it through to the disk
+fn write_synced(path: &str, data: &str) -> Result<(), String>
+{
+    let result = std::fs::File::create(path).and_then(|mut file|
+    {
+        file.write_all(data.as_bytes())?;
+        file.sync_all()
+    });
+
+    match result
+    {
+        Ok(()) => Ok(()),
+        Err(e) =>
+        {
+            let msg = format!("Unable to write to file `{}`, {}", path, e);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Replace a file with new data, which is written to a temporary file beside it and renamed over it so the file is
+/// never left partly written
+pub fn write_replacing(path: &std::path::Path, data: &str) -> Result<(), String>
+{
+    let path = match path.to_str()
+    {
+        Some(path) => path,
+        None =>
+        {
+            let msg = format!("Unable to interpret path {:?}", path);
+            error!("{}", msg);
+            return Err(msg);
+        }
+    };
+
+    let temporary = format!("{}.tmp", path);
+
+    write_synced(&temporary, data)?;
+    rename(&temporary, path)?;
+
+    if let Some(parent) = std::path::Path::new(path).parent().and_then(std::path::Path::to_str).filter(|parent| !parent.is_empty())
+    {
+        sync_directory(parent);
+    }
+
+    Ok(())
+}
+
+/// Flush a directory entry to disk, this is not supported on every platform so failures are only logged
+fn sync_directory(path: &str)
+{
+    if let Err(e) = std::fs::File::open(path).and_then(|dir| dir.sync_all())
+    {
+        debug!("Unable to sync directory `{}` ({})", path, e);
+    }
+}
+
+/// Rename a file or directory, or throw an error
+fn rename(from: &str, to: &str) -> Result<(), String>
+{
+    match std::fs::rename(from, to)
+    {
+        Ok(()) => Ok(()),
+        Err(e) =>
+        {
+            let msg = format!("Unable to rename `{}` to `{}`, {}", from, to, e);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Remove a directory and its contents if it exists, or throw an error
+fn remove_directory(path: &str) -> Result<(), String>
+{
+    if !std::path::Path::new(path).exists()
+    {
+        return Ok(());
+    }
+
+    match std::fs::remove_dir_all(path)
+    {
+        Ok(()) => Ok(()),
+        Err(e) =>
+        {
+            let msg = format!("Unable to remove directory `{}`, {}", path, e);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Path to the directory a database is staged in before being moved into place
+fn staging_path(root: &str, name: &str) -> String
+{
+    format!("{}.{}.tmp/", root, name)
+}
+
+/// Path the previous copy of a database is moved to while the new copy is moved into place
+fn backup_path(root: &str, name: &str) -> String
+{
+    format!("{}.{}.old/", root, name)
+}
+
+/// Restore the previous copy of a database if a write was interrupted while swapping directories
+fn recover_interrupted_write(root: &str, name: &str) -> Result<(), String>
+{
+    let database_path = format!("{}{}/", root, name);
+    let backup = backup_path(root, name);
+
+    if std::path::Path::new(&backup).exists()
+    {
+        if std::path::Path::new(&format!("{}{}.database", database_path, name)).exists()
+        {
+            debug!("Removing stale backup `{}`", backup);
+            remove_directory(&backup)?;
+        }
+        else
+        {
+            warn!("Database `{}` was interrupted while being written, restoring the previous copy from `{}`", name, backup);
+            remove_directory(&database_path)?;
+            rename(&backup, &database_path)?;
+            sync_directory(root);
+        }
+    }
+
+    Ok(())
 }
 
 /// Write a database to disk
+///
+/// The database is first written in full to a staging directory next to the database directory, which is then swapped
+/// into place, so a failure part way through leaves the previous copy on disk untouched.
 pub fn database_to_disk(path: &std::path::PathBuf, database: DatabaseInterface, _: &Arguments) -> Result<(), String>
 {
     let name = database.database.get_name();
 
     info!("Writing database `{}` to {:?}", name, path);
 
+    check_database_name(&name)?;
+
     // Convert path to a string, and correct if it does not end with a '/'
-    let mut path = if let Some(p) = path.to_str()
+    let mut root = if let Some(p) = path.to_str()
     {
         p.to_string()
     }
@@ -209,15 +495,23 @@ pub fn database_to_disk(path: &std::path::PathBuf, database: DatabaseInterface,
         return Err(msg);
     };
 
-    if !path.ends_with("/")
+    if !root.ends_with('/')
     {
-        path += "/";
+        root += "/";
     }
 
-    // Create the root path to the database
-    path = format!("{}{}/", path, name);
+    // Hold back any further changes from the journal until the new copy is in place, since the journal is replaced along
+    // with the rest of the directory
+    let _journal_guard = database.journal.as_ref().map(|journal| journal.lock());
 
-    debug!("Using path `{}`", path);
+    // Create the staging path for the database
+    let path = staging_path(&root, &name);
+
+    debug!("Staging database in `{}`", path);
+
+    // Clear out anything left behind by a previous failed write
+    recover_interrupted_write(&root, &name)?;
+    remove_directory(&path)?;
 
     // Make sure the directory exists
     match std::fs::create_dir_all(&path)
@@ -231,31 +525,39 @@ pub fn database_to_disk(path: &std::path::PathBuf, database: DatabaseInterface,
         }
     }
 
-    // Produce the database JSON
-    let keys = database.database.get_all_keys()?;
-    let database_json = serde_json::json!({"dbKey": name, "ver": BUILD_VERSION, "keys": &keys});
-    let database_file_path = format!("{}{}.database", path, name);
+    // Any change made from here on will be picked up by the next write
+    let was_dirty = database.database.mark_clean();
 
-    info!("Writing database data to `{}`", database_file_path);
+    let result = write_staged_database(&root, &name, &path, &database);
 
-    match std::fs::write(&database_file_path, database_json.to_string())
+    if result.is_err()
     {
-        Ok(()) => {},
-        Err(e) => 
+        if was_dirty
         {
-            let msg = format!("Unable to write to file `{}`, {}", database_file_path, e);
-            error!("{}", msg);
-            return Err(msg);
+            database.database.mark_dirty();
         }
+
+        // Throw away the partially written copy
+        remove_directory(&path)?;
     }
 
+    result
+}
+
+/// Write the contents of a database to its staging directory and swap it into place
+fn write_staged_database(root: &str, name: &str, path: &str, database: &DatabaseInterface) -> Result<(), String>
+{
+    let database_path = format!("{}{}/", root, name);
+
+    let keys = database.database.get_all_keys()?;
+
     // Produce the files for each key
-    for key in keys
+    for key in &keys
     {
         trace!("Writing data for key `{}` in database `{}`", key, name);
 
         // Get the permissions
-        let perm = match database.permissions.get(&key)
+        let perm = match database.permissions.get(key)
         {
             Some(v) => v,
             None => 
@@ -270,33 +572,64 @@ pub fn database_to_disk(path: &std::path::PathBuf, database: DatabaseInterface,
         let perm_json = perm.create_json()?;
 
         // Produce the json for the file
-        let value = database.database.read(&key)?;
+        let value = database.database.read(key)?;
+
+        let type_str = item_type(&value);
 
-        let type_str = match value
+        let subs: Vec<Value> = database.get_subscribers(key).iter().map(Subscriber::create_json).collect();
+
+        let owner = match database.owners.get(key)
         {
-            Value::Array(_) => "table",
-            Value::Object(_) => "obj",
-            _ => "string"
+            Some(owner) => owner.create_json(),
+            None => Value::String("self".to_string())
         };
 
-        let item_json = serde_json::json!({"key": key, "value": value, "owner": "self", "permissions": perm_json, "subs": [], "type": type_str});
+        let item_json = serde_json::json!({"key": key, "value": value, "owner": owner, "permissions": perm_json, "subs": subs, "type": type_str});
 
         let item_file_path = format!("{}{}.item", path, key);
 
         info!("Writing item data to `{}`", item_file_path);
 
         // Write json data to the file
-        match std::fs::write(&item_file_path, item_json.to_string())
+        write_synced(&item_file_path, &item_json.to_string())?;
+    }
+
+    // Produce the database JSON, this is written last so the staged copy is only complete once every item is present
+    let mut database_json = database.default_permissions().create_json()?;
+    database_json.insert("dbKey".to_string(), serde_json::json!(name));
+    database_json.insert("ver".to_string(), serde_json::json!(BUILD_VERSION));
+    database_json.insert("keys".to_string(), serde_json::json!(&keys));
+    let database_json = Value::Object(database_json);
+    let database_file_path = format!("{}{}.database", path, name);
+
+    info!("Writing database data to `{}`", database_file_path);
+
+    write_synced(&database_file_path, &database_json.to_string())?;
+
+    sync_directory(path);
+
+    // Swap the staged copy into place, keeping the previous copy until the new one is in place
+    let backup = backup_path(root, name);
+    let has_previous = std::path::Path::new(&database_path).exists();
+
+    if has_previous
+    {
+        rename(&database_path, &backup)?;
+    }
+
+    if let Err(e) = rename(path, &database_path)
+    {
+        if has_previous
         {
-            Ok(()) => {},
-            Err(e) => 
-            {
-                let msg = format!("Unable to write to file `{}`, {}", item_file_path, e);
-                error!("{}", msg);
-                return Err(msg);
-            }
+            rename(&backup, &database_path)?;
         }
+
+        return Err(e);
     }
 
+    sync_directory(root);
+
+    remove_directory(&backup)?;
+
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+//! RFC 7396 JSON Merge Patch and RFC 6902 JSON Patch
+use serde_json::Value;
+use log::{trace, error};
+
+use super::{pointer_get, pointer_set, pointer_add, pointer_delete};
+
+/// Apply a JSON Merge Patch to a value, members set to null in the patch are removed
+pub fn merge_patch(target: &mut Value, patch: &Value)
+{
+    if let Value::Object(patch) = patch
+    {
+        if !target.is_object()
+        {
+            *target = Value::Object(serde_json::Map::new());
+        }
+
+        let map = target.as_object_mut().unwrap();
+
+        for (name, value) in patch
+        {
+            if value.is_null()
+            {
+                map.remove(name);
+            }
+            else
+            {
+                merge_patch(map.entry(name.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+    else
+    {
+        *target = patch.clone();
+    }
+}
+
+/// Get a string field from a JSON Patch operation
+fn operation_field<'a>(operation: &'a Value, field: &str) -> Result<&'a str, String>
+{
+    match operation.get(field)
+    {
+        Some(Value::String(s)) => Ok(s),
+        _ =>
+        {
+            let msg = format!("Patch operation {} does not have a string `{}` field", operation, field);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Get the value field from a JSON Patch operation
+fn operation_value(operation: &Value) -> Result<Value, String>
+{
+    match operation.get("value")
+    {
+        Some(value) => Ok(value.clone()),
+        None =>
+        {
+            let msg = format!("Patch operation {} does not have a `value` field", operation);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Apply a single JSON Patch operation to a value
+fn apply_operation(target: &mut Value, operation: &Value) -> Result<(), String>
+{
+    trace!("Applying patch operation {}", operation);
+
+    let op = operation_field(operation, "op")?;
+    let path = operation_field(operation, "path")?;
+
+    match op
+    {
+        "add" => pointer_add(target, path, operation_value(operation)?),
+        "remove" => pointer_delete(target, path).map(|_| ()),
+        "replace" =>
+        {
+            pointer_get(target, path)?;
+            pointer_set(target, path, operation_value(operation)?)
+        },
+        "move" =>
+        {
+            let from = operation_field(operation, "from")?;
+
+            if path.starts_with(from) && path[from.len()..].starts_with('/')
+            {
+                let msg = format!("Cannot move `{}` into one of its children `{}`", from, path);
+                error!("{}", msg);
+                return Err(msg);
+            }
+
+            let value = pointer_delete(target, from)?;
+            pointer_add(target, path, value)
+        },
+        "copy" =>
+        {
+            let value = pointer_get(target, operation_field(operation, "from")?)?;
+            pointer_add(target, path, value)
+        },
+        "test" =>
+        {
+            if pointer_get(target, path)? != operation_value(operation)?
+            {
+                let msg = format!("Patch test failed, the value at `{}` does not match", path);
+                error!("{}", msg);
+                return Err(msg);
+            }
+
+            Ok(())
+        },
+        _ =>
+        {
+            let msg = format!("Unknown patch operation `{}`", op);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Apply a JSON Patch to a value, the value is only changed if every operation succeeds
+pub fn json_patch(target: &mut Value, operations: &[Value]) -> Result<(), String>
+{
+    let mut patched = target.clone();
+
+    for operation in operations
+    {
+        apply_operation(&mut patched, operation)?;
+    }
+
+    *target = patched;
+
+    Ok(())
+}
+
+/// Apply a patch to a value, an array is applied as a JSON Patch and anything else as a JSON Merge Patch
+pub fn apply_patch(target: &mut Value, patch: &Value) -> Result<(), String>
+{
+    match patch
+    {
+        Value::Array(operations) => json_patch(target, operations),
+        _ =>
+        {
+            merge_patch(target, patch);
+            Ok(())
+        }
+    }
+}
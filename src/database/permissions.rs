@@ -1,20 +1,145 @@
 use log::{error, trace};
 use serde_json::{Value, json};
 
+use super::GroupDirectory;
+
+/// Names from a permission list, split into groups and `[domain, name]` pairs for users
+type Entries = (Vec<String>, Vec<(String, String)>);
+
+/// Get the domain naming users in permission entries (such as `a_user`) for the domain users authenticate with (such as
+/// `a_auth`)
+pub fn entry_domain(auth_domain: &str) -> Option<String>
+{
+    auth_domain.strip_suffix("_auth").map(|prefix| format!("{}_user", prefix))
+}
+
+/// Get the domain users authenticate with (such as `a_auth`) for the domain naming them in permission entries (such as
+/// `a_user`)
+pub fn auth_domain(entry_domain: &str) -> Option<String>
+{
+    entry_domain.strip_suffix("_user").map(|prefix| format!("{}_auth", prefix))
+}
+
+/// Parse a list of `[domain, name]` permission entries into the group names and the users of every domain
+fn parse_entries(list: &Value, title: &str) -> Result<Entries, String>
+{
+    let mut groups: Vec<String> = vec![];
+    let mut users: Vec<(String, String)> = vec![];
+
+    if let Value::Array(perms) = list
+    {
+        for perm in perms
+        {
+            if let Value::Array(perm) = perm
+            {
+                if perm.len() != 2
+                {
+                    let msg = format!("Permission is not an array of two values, got {:?}", perm);
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+
+                if let Value::String(name) = &perm[1]
+                {
+                    if perm[0] == json!("group")
+                    {
+                        groups.push(name.clone());
+                    }
+                    else if let Some(domain) = perm[0].as_str().filter(|domain| auth_domain(domain).is_some())
+                    {
+                        users.push((domain.to_string(), name.clone()));
+                    }
+                    else
+                    {
+                        let msg = format!("Unknown permission domain {:?}", &perm[0]);
+                        error!("{}", msg);
+                        return Err(msg);
+                    }
+                }
+                else
+                {
+                    let msg = format!("Permission entity is not a string, got {:?}", &perm[1]);
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+            }
+            else
+            {
+                let msg = format!("{} permission is not an array, got {}", title, perm);
+                error!("{}", msg);
+                return Err(msg);
+            }
+        }
+    }
+    else
+    {
+        let msg = format!("{} permissions are not in an array, got {}", title, list);
+        error!("{}", msg);
+        return Err(msg);
+    }
+
+    Ok((groups, users))
+}
+
+/// Check if a user is named in a list of `[domain, name]` users, `["a_user", "any"]` allows anybody, even users who have
+/// not authenticated, while `"any"` and `"authed"` allow any authenticated user of any other domain
+fn check_lists(users: &[(String, String)], is_authed: bool, user: &str, domain: &str) -> Result<bool, String>
+{
+    if users.iter().any(|(entry_domain, name)| entry_domain == "a_user" && name == "any")
+    {
+        return Ok(true)
+    }
+
+    if !is_authed
+    {
+        return Ok(false)
+    }
+
+    if let Some(entry) = entry_domain(domain)
+    {
+        Ok(users.iter().any(|(entry_domain, name)| entry_domain == &entry && (name == "authed" || name == "any" || name == user)))
+    }
+    else
+    {
+        let msg = format!("Unknown permission domain {}", domain);
+        error!("{}", msg);
+        Err(msg)
+    }
+}
+
+/// Produce the `[domain, name]` permission entries for a list of users and groups
+fn create_entries(users: &[(String, String)], groups: &[String]) -> Vec<Value>
+{
+    users.iter().map(|(domain, name)| json!([domain, name]))
+         .chain(groups.iter().map(|name| json!(["group", name])))
+         .collect()
+}
+
+/// Check if a user is named in a list of `[domain, name]` permission entries, resolving any groups
+pub fn check_entries(list: &Value, title: &str, user: &UserAuthentication, groups: &GroupDirectory) -> Result<bool, String>
+{
+    let (group_names, users) = parse_entries(list, title)?;
+
+    Ok(check_lists(&users, user.is_authed, &user.name, &user.domain)? || groups.is_member_of_any(&group_names, user))
+}
+
 /// Permission gating a value
 #[derive(Debug, Clone)]
 pub struct Permission
 {
-    pub read_a_users: Vec<String>,
-    pub read_g_users: Vec<String>,
+    pub read_users: Vec<(String, String)>,
+    pub write_users: Vec<(String, String)>,
+    pub admin_users: Vec<(String, String)>,
 
-    pub write_a_users: Vec<String>,
-    pub write_g_users: Vec<String>
+    pub read_groups: Vec<String>,
+    pub write_groups: Vec<String>,
+    pub admin_groups: Vec<String>
 }
 
 impl Permission
 {
-    /// Generate permissions from a json object
+    /// Generate permissions from a json object, if no admin permissions are given only the owner of the item may change
+    /// its permissions
     pub fn new(object: &Value, name: &str) -> Result<Self, String>
     {
         trace!("Creating premissions for {}", name);
@@ -44,154 +169,44 @@ impl Permission
             return Err(msg);
         }
 
-        let mut read_a_users: Vec<String> = vec![];
-        let mut read_g_users: Vec<String> = vec![];
-
-        let mut write_a_users: Vec<String> = vec![];
-        let mut write_g_users: Vec<String> = vec![];
-
-        if let Value::Array(read_perms) = map.get("read").unwrap()
-        {
-            for perm in read_perms
-            {
-                if let Value::Array(perm) = perm
-                {
-                    if perm.len() != 2
-                    {
-                        let msg = format!("Permission is not an array of two values, got {:?}", perm);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-
-                    if let Value::String(name) = &perm[1]
-                    {
-                        if perm[0] == json!("a_user")
-                        {
-                            read_a_users.push(name.clone());
-                        }
-                        else if perm[0] == json!("g_user")
-                        {
-                            read_g_users.push(name.clone());
-                        }
-                        else
-                        {
-                            let msg = format!("Unknown permission domain {:?}", &perm[0]);
-                            error!("{}", msg);
-                            return Err(msg);
-                        }
-                    }
-                    else
-                    {
-                        let msg = format!("Permission entity is not a string, got {:?}", &perm[1]);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-                }
-                else
-                {
-                    let msg = format!("Read permission is not an array, got {}", perm);
-                    error!("{}", msg);
-                    return Err(msg);
-                }
-            }
-        }
-        else
-        {
-            let msg = format!("Read permissions are not in an array, got {}", object);
-            error!("{}", msg);
-            return Err(msg);
-        }
-
-        if let Value::Array(write_perms) = map.get("write").unwrap()
-        {
-            for perm in write_perms
-            {
-                if let Value::Array(perm) = perm
-                {
-                    if perm.len() != 2
-                    {
-                        let msg = format!("Permission is not an array of two values, got {:?}", perm);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
+        let (read_groups, read_users) = parse_entries(map.get("read").unwrap(), "Read")?;
+        let (write_groups, write_users) = parse_entries(map.get("write").unwrap(), "Write")?;
 
-                    if let Value::String(name) = &perm[1]
-                    {
-                        if perm[0] == json!("a_user")
-                        {
-                            write_a_users.push(name.clone());
-                        }
-                        else if perm[0] == json!("g_user")
-                        {
-                            write_g_users.push(name.clone());
-                        }
-                        else
-                        {
-                            let msg = format!("Unknown permission domain {:?}", &perm[0]);
-                            error!("{}", msg);
-                            return Err(msg);
-                        }
-                    }
-                    else
-                    {
-                        let msg = format!("Permission entity is not a string, got {:?}", &perm[1]);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-                }
-                else
-                {
-                    let msg = format!("Write permission is not an array, got {}", perm);
-                    error!("{}", msg);
-                    return Err(msg);
-                }
-            }
-        }
-        else
+        let (admin_groups, admin_users) = match map.get("admin")
         {
-            let msg = format!("Write permissions are not in an array, got {}", object);
-            error!("{}", msg);
-            return Err(msg);
-        }
+            Some(admin) => parse_entries(admin, "Admin")?,
+            None => (vec![], vec![])
+        };
 
         Ok(
             Self
             {
-                read_a_users, read_g_users,
-                write_a_users, write_g_users
+                read_users, write_users, admin_users,
+                read_groups, write_groups, admin_groups
             }
         )
     }
 
-    /// Check if a user is allowed to read from the gated item
-    pub fn check_read(&self, is_authed: bool, user: &String, domain: &String) -> Result<bool, String>
+    /// Permissions which allow nobody besides the owner of the item
+    pub fn none() -> Self
     {
-        trace!("Checking if {} user `{}`:`{}` can read", if is_authed {"Authed"} else {"Not authed"}, domain, user);
-
-        if self.read_a_users.contains(&"any".to_string())
+        Permission
         {
-            return Ok(true)
+            read_users: vec![],
+            write_users: vec![],
+            admin_users: vec![],
+            read_groups: vec![],
+            write_groups: vec![],
+            admin_groups: vec![]
         }
+    }
 
-        if !is_authed
-        {
-            return Ok(false)
-        }
+    /// Check if a user is allowed to read from the gated item
+    pub fn check_read(&self, is_authed: bool, user: &String, domain: &String) -> Result<bool, String>
+    {
+        trace!("Checking if {} user `{}`:`{}` can read", if is_authed {"Authed"} else {"Not authed"}, domain, user);
 
-        if domain == "a_auth"
-        {
-            return Ok(self.read_a_users.contains(&"authed".to_string()) || self.read_a_users.contains(user)) 
-        }
-        else if domain == "g_auth"
-        {
-            return Ok(self.read_g_users.contains(&"authed".to_string()) || self.read_g_users.contains(user)) 
-        }
-        else
-        {
-            let msg = format!("Unknown permission domain given to check_read {}", domain);
-            error!("{}", msg);
-            return Err(msg);
-        }
+        check_lists(&self.read_users, is_authed, user, domain)
     }
 
     /// Check if a user is allowed to write to the gated item
@@ -199,85 +214,234 @@ impl Permission
     {
         trace!("Checking if {} user `{}`:`{}` can write", if is_authed {"Authed"} else {"Not authed"}, domain, user);
 
-        if self.write_a_users.contains(&"any".to_string())
-        {
-            return Ok(true)
-        }
+        check_lists(&self.write_users, is_authed, user, domain)
+    }
 
-        if !is_authed
-        {
-            return Ok(false)
-        }
+    /// Check if a user is allowed to change the permissions of the gated item
+    pub fn check_admin(&self, is_authed: bool, user: &String, domain: &String) -> Result<bool, String>
+    {
+        trace!("Checking if {} user `{}`:`{}` can administer", if is_authed {"Authed"} else {"Not authed"}, domain, user);
 
-        if domain == "a_auth"
-        {
-            return Ok(self.write_a_users.contains(&"authed".to_string()) || self.write_a_users.contains(user)) 
-        }
-        else if domain == "g_auth"
-        {
-            return Ok(self.write_g_users.contains(&"authed".to_string()) || self.write_g_users.contains(user)) 
-        }
-        else
-        {
-            let msg = format!("Unknown permission domain given to check_write {}", domain);
-            error!("{}", msg);
-            return Err(msg);
-        }
+        check_lists(&self.admin_users, is_authed, user, domain)
     }
 
-    /// Wrapper around check_read for UserAuthentication
-    pub fn check_user_read(&self, user: &UserAuthentication) -> Result<bool, String>
+    /// Wrapper around check_read for UserAuthentication, also resolving group membership
+    pub fn check_user_read(&self, user: &UserAuthentication, groups: &GroupDirectory) -> Result<bool, String>
     {
-        self.check_read(user.is_authed, &user.name, &user.domain)
+        Ok(self.check_read(user.is_authed, &user.name, &user.domain)? || groups.is_member_of_any(&self.read_groups, user))
     }
 
-    /// Wrapper around check_write for UserAuthentication
-    pub fn check_user_write(&self, user: &UserAuthentication) -> Result<bool, String>
+    /// Wrapper around check_write for UserAuthentication, also resolving group membership
+    pub fn check_user_write(&self, user: &UserAuthentication, groups: &GroupDirectory) -> Result<bool, String>
     {
-        self.check_write(user.is_authed, &user.name, &user.domain)
+        Ok(self.check_write(user.is_authed, &user.name, &user.domain)? || groups.is_member_of_any(&self.write_groups, user))
+    }
+
+    /// Wrapper around check_admin for UserAuthentication, also resolving group membership
+    pub fn check_user_admin(&self, user: &UserAuthentication, groups: &GroupDirectory) -> Result<bool, String>
+    {
+        Ok(self.check_admin(user.is_authed, &user.name, &user.domain)? || groups.is_member_of_any(&self.admin_groups, user))
     }
 
     /// Produce json data for the permissions
     pub fn create_json(&self) -> Result<Value, String>
     {
-        let mut reads = vec![];
-        let mut writes = vec![];
+        Ok(json!({"read": create_entries(&self.read_users, &self.read_groups),
+                  "write": create_entries(&self.write_users, &self.write_groups),
+                  "admin": create_entries(&self.admin_users, &self.admin_groups)}))
+    }
+}
+
+impl std::default::Default for Permission
+{
+    fn default() -> Permission
+    {
+        let anybody = vec![("a_user".to_string(), "any".to_string()), ("g_user".to_string(), "any".to_string())];
 
-        for entry in &self.read_a_users
+        Permission
         {
-            reads.push(json!(["a_user", entry]));
+            read_users: anybody.clone(),
+            write_users: anybody,
+            admin_users: vec![],
+            read_groups: vec![],
+            write_groups: vec![],
+            admin_groups: vec![]
         }
+    }
+}
 
-        for entry in &self.read_g_users
+/// Permissions given to new keys in a database, either by the longest matching key prefix or the database default
+#[derive(Debug, Clone, Default)]
+pub struct DefaultPermissions
+{
+    pub default: Option<Permission>,
+    pub prefixes: Vec<(String, Permission)>
+}
+
+impl DefaultPermissions
+{
+    /// Generate default permissions from the optional `default_permissions` and `prefix_permissions` json values
+    pub fn new(default: Option<&Value>, prefixes: Option<&Value>) -> Result<Self, String>
+    {
+        let default = match default
         {
-            reads.push(json!(["g_user", entry]));
+            Some(Value::Null) | None => None,
+            Some(object) => Some(Permission::new(object, "database default")?)
+        };
+
+        let prefixes = match prefixes
+        {
+            Some(Value::Null) | None => vec![],
+            Some(Value::Object(map)) =>
+            {
+                let mut prefixes = vec![];
+
+                for (prefix, object) in map
+                {
+                    prefixes.push((prefix.clone(), Permission::new(object, &format!("prefix `{}`", prefix))?));
+                }
+
+                prefixes
+            },
+            Some(other) =>
+            {
+                let msg = format!("Prefix permissions are not an object, got {}", other);
+                error!("{}", msg);
+                return Err(msg);
+            }
+        };
+
+        Ok(Self
+        {
+            default,
+            prefixes
+        })
+    }
+
+    /// Get the permissions a new key should be created with
+    pub fn for_key(&self, key: &str) -> Permission
+    {
+        let prefix = self.prefixes.iter()
+                        .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+                        .max_by_key(|(prefix, _)| prefix.len());
+
+        match (prefix, &self.default)
+        {
+            (Some((_, permission)), _) => permission.clone(),
+            (None, Some(permission)) => permission.clone(),
+            (None, None) => Permission::default()
         }
+    }
 
-        for entry in &self.write_a_users
+    /// Produce the `default_permissions` and `prefix_permissions` fields, a field is left out when it is not set
+    pub fn create_json(&self) -> Result<serde_json::Map<String, Value>, String>
+    {
+        let mut map = serde_json::Map::new();
+
+        if let Some(default) = &self.default
         {
-            writes.push(json!(["a_user", entry]));
+            map.insert("default_permissions".to_string(), default.create_json()?);
         }
 
-        for entry in &self.write_g_users
+        if !self.prefixes.is_empty()
         {
-            writes.push(json!(["g_user", entry]));
+            let mut prefixes = serde_json::Map::new();
+
+            for (prefix, permission) in &self.prefixes
+            {
+                prefixes.insert(prefix.clone(), permission.create_json()?);
+            }
+
+            map.insert("prefix_permissions".to_string(), Value::Object(prefixes));
         }
 
-        Ok(json!({"read": reads, "write": writes}))
+        Ok(map)
     }
 }
 
-impl std::default::Default for Permission
+/// User which created an item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner
 {
-    fn default() -> Permission
+    pub domain: String,
+    pub name: String
+}
+
+impl Owner
+{
+    /// Get the owner for an item created by an authenticated user
+    pub fn from_user(user: &UserAuthentication) -> Option<Self>
     {
-        Permission
+        if user.is_authed && !user.name.is_empty()
         {
-            read_a_users: vec!["any".to_string()],
-            read_g_users: vec!["any".to_string()],
-            write_a_users: vec!["any".to_string()],
-            write_g_users: vec!["any".to_string()]
+            Some(Self
+            {
+                domain: user.domain.clone(),
+                name: user.name.clone()
+            })
         }
+        else
+        {
+            None
+        }
+    }
+
+    /// Parse the owner field of an item, `"self"` marks an item without an owner
+    pub fn from_json(value: &Value) -> Result<Option<Self>, String>
+    {
+        match value
+        {
+            Value::Null => Ok(None),
+            Value::String(s) if s == "self" => Ok(None),
+            Value::Array(entry) if entry.len() == 2 =>
+            {
+                let domain = match entry[0].as_str().and_then(auth_domain)
+                {
+                    Some(domain) => domain,
+                    None =>
+                    {
+                        let msg = format!("Unknown owner domain {:?}", &entry[0]);
+                        error!("{}", msg);
+                        return Err(msg);
+                    }
+                };
+
+                if let Value::String(name) = &entry[1]
+                {
+                    Ok(Some(Self
+                    {
+                        domain,
+                        name: name.clone()
+                    }))
+                }
+                else
+                {
+                    let msg = format!("Owner name is not a string, got {:?}", &entry[1]);
+                    error!("{}", msg);
+                    Err(msg)
+                }
+            },
+            _ =>
+            {
+                let msg = format!("Owner is not an array of two values, got {}", value);
+                error!("{}", msg);
+                Err(msg)
+            }
+        }
+    }
+
+    /// Produce json data for the owner, in the same form as a permission entry
+    pub fn create_json(&self) -> Value
+    {
+        let domain = entry_domain(&self.domain).unwrap_or_else(|| "a_user".to_string());
+
+        json!([domain, self.name])
+    }
+
+    /// Check if the owner is the given user
+    pub fn is_user(&self, user: &UserAuthentication) -> bool
+    {
+        user.is_authed && user.domain == self.domain && user.name == self.name
     }
 }
 
@@ -0,0 +1,209 @@
+//! RFC 6901 JSON Pointers into stored values
+use serde_json::Value;
+use log::error;
+
+/// Split a pointer into the pointer to its parent and its final unescaped token
+fn split_pointer(pointer: &str) -> Result<(&str, String), String>
+{
+    if !pointer.starts_with('/')
+    {
+        let msg = format!("Pointer `{}` does not start with a `/`", pointer);
+        error!("{}", msg);
+        return Err(msg);
+    }
+
+    let split = pointer.rfind('/').unwrap();
+    let token = pointer[split + 1..].replace("~1", "/").replace("~0", "~");
+
+    Ok((&pointer[..split], token))
+}
+
+/// Parse an array index from a pointer token, `-` refers to the element after the end of the array
+fn parse_index(token: &str, length: usize, pointer: &str) -> Result<usize, String>
+{
+    if token == "-"
+    {
+        return Ok(length);
+    }
+
+    let is_valid = !token.is_empty() && token.chars().all(|c| c.is_ascii_digit()) && (token == "0" || !token.starts_with('0'));
+
+    match token.parse::<usize>()
+    {
+        Ok(index) if is_valid => Ok(index),
+        _ =>
+        {
+            let msg = format!("`{}` in pointer `{}` is not an array index", token, pointer);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Get the value a pointer refers to
+pub fn pointer_get(value: &Value, pointer: &str) -> Result<Value, String>
+{
+    match value.pointer(pointer)
+    {
+        Some(v) => Ok(v.clone()),
+        None =>
+        {
+            let msg = format!("Pointer `{}` does not refer to a value", pointer);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Get a mutable reference to the parent of the value a pointer refers to, along with the final token of the pointer
+fn pointer_parent<'a>(value: &'a mut Value, pointer: &str) -> Result<(&'a mut Value, String), String>
+{
+    let (parent, token) = split_pointer(pointer)?;
+
+    match value.pointer_mut(parent)
+    {
+        Some(v) => Ok((v, token)),
+        None =>
+        {
+            let msg = format!("The parent of pointer `{}` does not refer to a value", pointer);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Set the value a pointer refers to, object members are created or replaced, array elements are replaced or appended
+/// to the end of the array
+pub fn pointer_set(value: &mut Value, pointer: &str, data: Value) -> Result<(), String>
+{
+    if pointer.is_empty()
+    {
+        *value = data;
+        return Ok(());
+    }
+
+    let (parent, token) = pointer_parent(value, pointer)?;
+
+    match parent
+    {
+        Value::Object(map) =>
+        {
+            map.insert(token, data);
+            Ok(())
+        },
+        Value::Array(array) =>
+        {
+            let index = parse_index(&token, array.len(), pointer)?;
+
+            if index < array.len()
+            {
+                array[index] = data;
+                Ok(())
+            }
+            else if index == array.len()
+            {
+                array.push(data);
+                Ok(())
+            }
+            else
+            {
+                let msg = format!("Index {} in pointer `{}` is past the end of the array", index, pointer);
+                error!("{}", msg);
+                Err(msg)
+            }
+        },
+        _ =>
+        {
+            let msg = format!("The parent of pointer `{}` is not an object or an array", pointer);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Add a value at a pointer, object members are created or replaced, array elements are inserted before the element
+/// at the index, shifting the following elements
+pub fn pointer_add(value: &mut Value, pointer: &str, data: Value) -> Result<(), String>
+{
+    if pointer.is_empty()
+    {
+        *value = data;
+        return Ok(());
+    }
+
+    let (parent, token) = pointer_parent(value, pointer)?;
+
+    match parent
+    {
+        Value::Object(map) =>
+        {
+            map.insert(token, data);
+            Ok(())
+        },
+        Value::Array(array) =>
+        {
+            let index = parse_index(&token, array.len(), pointer)?;
+
+            if index <= array.len()
+            {
+                array.insert(index, data);
+                Ok(())
+            }
+            else
+            {
+                let msg = format!("Index {} in pointer `{}` is past the end of the array", index, pointer);
+                error!("{}", msg);
+                Err(msg)
+            }
+        },
+        _ =>
+        {
+            let msg = format!("The parent of pointer `{}` is not an object or an array", pointer);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Remove the value a pointer refers to, returning the removed value
+pub fn pointer_delete(value: &mut Value, pointer: &str) -> Result<Value, String>
+{
+    if pointer.is_empty()
+    {
+        let msg = "Cannot delete the whole value with an empty pointer".to_string();
+        error!("{}", msg);
+        return Err(msg);
+    }
+
+    let (parent, token) = pointer_parent(value, pointer)?;
+
+    let removed = match parent
+    {
+        Value::Object(map) => map.remove(&token),
+        Value::Array(array) =>
+        {
+            let index = parse_index(&token, array.len(), pointer)?;
+
+            if index < array.len()
+            {
+                Some(array.remove(index))
+            }
+            else
+            {
+                None
+            }
+        },
+        _ => None
+    };
+
+    match removed
+    {
+        Some(v) => Ok(v),
+        None =>
+        {
+            let msg = format!("Pointer `{}` does not refer to a value", pointer);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
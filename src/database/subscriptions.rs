@@ -0,0 +1,171 @@
+use super::{UserAuthentication, entry_domain, auth_domain};
+
+use serde_json::{Value, json};
+use log::{error, trace, warn};
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Channel used to push key updates to a connected subscriber
+pub type UpdateSender = tokio::sync::mpsc::UnboundedSender<Value>;
+
+/// Identifier of a single connection to the server, unique for the life of the server
+pub type ConnectionId = u64;
+
+/// User subscribed to a key, by the domain they authenticate with and their name, so users of different domains with
+/// the same name are never sent each other's updates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscriber
+{
+    pub domain: String,
+    pub name: String
+}
+
+impl Subscriber
+{
+    /// Get the subscriber for an authenticated user
+    pub fn from_user(user: &UserAuthentication) -> Self
+    {
+        Self
+        {
+            domain: user.domain.clone(),
+            name: user.name.clone()
+        }
+    }
+
+    /// Parse an entry of the subs field of an item, in the same form as a permission entry, a bare name recorded before
+    /// subscribers had domains is an a_user
+    pub fn from_json(value: &Value) -> Result<Self, String>
+    {
+        match value
+        {
+            Value::String(name) => Ok(Self{domain: "a_auth".to_string(), name: name.clone()}),
+            Value::Array(entry) if entry.len() == 2 => match (entry[0].as_str().and_then(auth_domain), entry[1].as_str())
+            {
+                (Some(domain), Some(name)) => Ok(Self{domain, name: name.to_string()}),
+                _ =>
+                {
+                    let msg = format!("Subscriber is not a known domain and a name, got {}", value);
+                    error!("{}", msg);
+                    Err(msg)
+                }
+            },
+            _ =>
+            {
+                let msg = format!("Subscriber is not an array of two values, got {}", value);
+                error!("{}", msg);
+                Err(msg)
+            }
+        }
+    }
+
+    /// Produce json data for the subscriber, in the same form as a permission entry
+    pub fn create_json(&self) -> Value
+    {
+        let domain = entry_domain(&self.domain).unwrap_or_else(|| "a_user".to_string());
+
+        json!([domain, self.name])
+    }
+
+    /// Check if the subscriber is the given user
+    pub fn is_user(&self, user: &UserAuthentication) -> bool
+    {
+        user.domain == self.domain && user.name == self.name
+    }
+}
+
+/// Live update channels for connected subscribers, indexed by connection along with the user each connection is
+/// authenticated as
+#[derive(Debug, Clone)]
+pub struct SubscriberRegistry
+{
+    channels: Arc<RwLock<HashMap<ConnectionId, (UserAuthentication, UpdateSender)>>>
+}
+
+impl SubscriberRegistry
+{
+    /// Create an empty registry
+    pub fn new() -> Self
+    {
+        Self
+        {
+            channels: Arc::new(RwLock::new(HashMap::new()))
+        }
+    }
+
+    /// Lock the channels for reading
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<ConnectionId, (UserAuthentication, UpdateSender)>>
+    {
+        match self.channels.read()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Lock the channels for writing
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<ConnectionId, (UserAuthentication, UpdateSender)>>
+    {
+        match self.channels.write()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Register the update channel of a connection for a user, replacing any previous channel of the connection
+    pub fn register(&self, connection: ConnectionId, user: &UserAuthentication, sender: UpdateSender)
+    {
+        trace!("Registering update channel for subscriber `{}` on connection {}", user.name, connection);
+        self.write().insert(connection, (user.clone(), sender));
+    }
+
+    /// Remove the update channel of a connection
+    pub fn remove(&self, connection: ConnectionId)
+    {
+        if let Some((user, _)) = self.write().remove(&connection)
+        {
+            trace!("Removing update channel for subscriber `{}` on connection {}", user.name, connection);
+        }
+    }
+
+    /// Push an update to every connection of a subscriber which the filter accepts the user of, returns the number of
+    /// connections the update was sent to
+    pub fn notify(&self, subscriber: &Subscriber, update: &Value, filter: impl Fn(&UserAuthentication) -> bool) -> usize
+    {
+        let name = &subscriber.name;
+        let mut sent = 0;
+
+        for (connection, (user, channel)) in self.read().iter().filter(|(_, (user, _))| subscriber.is_user(user))
+        {
+            if !filter(user)
+            {
+                trace!("Subscriber `{}` on connection {} may no longer receive the update", name, connection);
+                continue;
+            }
+
+            if let Err(e) = channel.send(update.clone())
+            {
+                warn!("Unable to push update to subscriber `{}` on connection {} ({})", name, connection, e);
+                continue;
+            }
+
+            sent += 1;
+        }
+
+        if sent == 0
+        {
+            trace!("Subscriber `{}` is not connected, dropping update", name);
+        }
+
+        sent
+    }
+}
+
+impl std::default::Default for SubscriberRegistry
+{
+    fn default() -> SubscriberRegistry
+    {
+        SubscriberRegistry::new()
+    }
+}
@@ -3,6 +3,10 @@ use serde_json::Value;
 
 use super::Database;
 use super::Permission;
+use super::Owner;
+use super::UserAuthentication;
+use super::GroupDirectory;
+use super::DefaultPermissions;
 
 #[test]
 pub fn test_database_creation()
@@ -206,7 +210,7 @@ pub fn test_database_get_last_n()
 #[test]
 pub fn test_permission_check_read_empty()
 {
-    let perm = Permission{write_a_users: vec![], write_g_users: vec![], read_a_users: vec![], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![], write_users: vec![], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_read(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_read(true, &"user".to_string(), &"a_auth".to_string()), Ok(false));
@@ -216,7 +220,7 @@ pub fn test_permission_check_read_empty()
 #[test]
 pub fn test_permission_check_write_empty()
 {
-    let perm = Permission{write_a_users: vec![], write_g_users: vec![], read_a_users: vec![], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![], write_users: vec![], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_write(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_write(true, &"user".to_string(), &"a_auth".to_string()), Ok(false));
@@ -226,7 +230,7 @@ pub fn test_permission_check_write_empty()
 #[test]
 pub fn test_permission_check_read_authed()
 {
-    let perm = Permission{write_a_users: vec![], write_g_users: vec![], read_a_users: vec!["authed".to_string()], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![("a_user".to_string(), "authed".to_string())], write_users: vec![], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_read(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_read(true, &"user".to_string(), &"a_auth".to_string()), Ok(true));
@@ -234,7 +238,7 @@ pub fn test_permission_check_read_authed()
     assert_eq!(perm.check_read(true, &"".to_string(), &"a_auth".to_string()), Ok(true));
     assert_eq!(perm.check_read(true, &"".to_string(), &"g_auth".to_string()), Ok(false));
 
-    let perm = Permission{write_a_users: vec![], write_g_users: vec![], read_a_users: vec![], read_g_users: vec!["authed".to_string()]};
+    let perm = Permission{read_users: vec![("g_user".to_string(), "authed".to_string())], write_users: vec![], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_read(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_read(true, &"user".to_string(), &"a_auth".to_string()), Ok(false));
@@ -246,7 +250,7 @@ pub fn test_permission_check_read_authed()
 #[test]
 pub fn test_permission_check_write_authed()
 {
-    let perm = Permission{write_a_users: vec!["authed".to_string()], write_g_users: vec![], read_a_users: vec![], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![], write_users: vec![("a_user".to_string(), "authed".to_string())], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_write(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_write(true, &"user".to_string(), &"a_auth".to_string()), Ok(true));
@@ -254,7 +258,7 @@ pub fn test_permission_check_write_authed()
     assert_eq!(perm.check_write(true, &"".to_string(), &"a_auth".to_string()), Ok(true));
     assert_eq!(perm.check_write(true, &"".to_string(), &"g_auth".to_string()), Ok(false));
 
-    let perm = Permission{write_a_users: vec![], write_g_users: vec!["authed".to_string()], read_a_users: vec![], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![], write_users: vec![("g_user".to_string(), "authed".to_string())], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_write(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_write(true, &"user".to_string(), &"a_auth".to_string()), Ok(false));
@@ -266,7 +270,7 @@ pub fn test_permission_check_write_authed()
 #[test]
 pub fn test_permission_check_read_set()
 {
-    let perm = Permission{write_a_users: vec![], write_g_users: vec![], read_a_users: vec!["user".to_string()], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![("a_user".to_string(), "user".to_string())], write_users: vec![], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_read(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_read(true, &"user".to_string(), &"a_auth".to_string()), Ok(true));
@@ -276,7 +280,7 @@ pub fn test_permission_check_read_set()
     assert_eq!(perm.check_read(true, &"name".to_string(), &"a_auth".to_string()), Ok(false));
     assert_eq!(perm.check_read(true, &"name".to_string(), &"g_auth".to_string()), Ok(false));
 
-    let perm = Permission{write_a_users: vec![], write_g_users: vec![], read_a_users: vec![], read_g_users: vec!["user".to_string()]};
+    let perm = Permission{read_users: vec![("g_user".to_string(), "user".to_string())], write_users: vec![], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_read(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_read(true, &"user".to_string(), &"a_auth".to_string()), Ok(false));
@@ -290,7 +294,7 @@ pub fn test_permission_check_read_set()
 #[test]
 pub fn test_permission_check_write_set()
 {
-    let perm = Permission{write_a_users: vec!["user".to_string()], write_g_users: vec![], read_a_users: vec![], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![], write_users: vec![("a_user".to_string(), "user".to_string())], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_write(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_write(true, &"user".to_string(), &"a_auth".to_string()), Ok(true));
@@ -300,7 +304,7 @@ pub fn test_permission_check_write_set()
     assert_eq!(perm.check_write(true, &"name".to_string(), &"a_auth".to_string()), Ok(false));
     assert_eq!(perm.check_write(true, &"name".to_string(), &"g_auth".to_string()), Ok(false));
 
-    let perm = Permission{write_a_users: vec![], write_g_users: vec!["user".to_string()], read_a_users: vec![], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![], write_users: vec![("g_user".to_string(), "user".to_string())], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_write(false, &"".to_string(), &"".to_string()), Ok(false));
     assert_eq!(perm.check_write(true, &"user".to_string(), &"a_auth".to_string()), Ok(false));
@@ -314,7 +318,7 @@ pub fn test_permission_check_write_set()
 #[test]
 pub fn test_permission_check_read_any()
 {
-    let perm = Permission{write_a_users: vec![], write_g_users: vec![], read_a_users: vec!["any".to_string()], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![("a_user".to_string(), "any".to_string())], write_users: vec![], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_read(false, &"".to_string(), &"".to_string()), Ok(true));
     assert_eq!(perm.check_read(true, &"user".to_string(), &"a_auth".to_string()), Ok(true));
@@ -326,11 +330,329 @@ pub fn test_permission_check_read_any()
 #[test]
 pub fn test_permission_check_write_any()
 {
-    let perm = Permission{write_a_users: vec!["any".to_string()], write_g_users: vec![], read_a_users: vec![], read_g_users: vec![]};
+    let perm = Permission{read_users: vec![], write_users: vec![("a_user".to_string(), "any".to_string())], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
 
     assert_eq!(perm.check_write(false, &"".to_string(), &"".to_string()), Ok(true));
     assert_eq!(perm.check_write(true, &"user".to_string(), &"a_auth".to_string()), Ok(true));
     assert_eq!(perm.check_write(true, &"user".to_string(), &"g_auth".to_string()), Ok(true));
     assert_eq!(perm.check_write(true, &"".to_string(), &"a_auth".to_string()), Ok(true));
     assert_eq!(perm.check_write(true, &"".to_string(), &"g_auth".to_string()), Ok(true));
-}
\ No newline at end of file
+}
+#[test]
+pub fn test_permission_check_admin()
+{
+    let perm = Permission{read_users: vec![], write_users: vec![("a_user".to_string(), "any".to_string())], admin_users: vec![("a_user".to_string(), "user".to_string()), ("g_user".to_string(), "authed".to_string())], read_groups: vec![], write_groups: vec![], admin_groups: vec![]};
+
+    assert_eq!(perm.check_admin(false, &"".to_string(), &"".to_string()), Ok(false));
+    assert_eq!(perm.check_admin(true, &"user".to_string(), &"a_auth".to_string()), Ok(true));
+    assert_eq!(perm.check_admin(true, &"other".to_string(), &"a_auth".to_string()), Ok(false));
+    assert_eq!(perm.check_admin(true, &"other".to_string(), &"g_auth".to_string()), Ok(true));
+}
+
+#[test]
+pub fn test_permission_domains()
+{
+    let perm = Permission::new(&json!({"read": [["key_user", "any"]], "write": [["g_user", "authed"], ["key_user", "bot"]], "admin": [["a_user", "user"]]}), "key").unwrap();
+
+    // Only `["a_user", "any"]` allows anybody, `any` in another domain only allows the authenticated users of that domain
+    assert_eq!(perm.check_read(false, &"".to_string(), &"".to_string()), Ok(false));
+    assert_eq!(perm.check_read(true, &"bot".to_string(), &"key_auth".to_string()), Ok(true));
+    assert_eq!(perm.check_read(true, &"bot".to_string(), &"a_auth".to_string()), Ok(false));
+    assert_eq!(perm.check_read(true, &"user@example.com".to_string(), &"g_auth".to_string()), Ok(false));
+
+    let perm_any = Permission::new(&json!({"read": [["g_user", "any"]], "write": [["a_user", "any"]]}), "key").unwrap();
+    assert_eq!(perm_any.check_read(false, &"".to_string(), &"".to_string()), Ok(false));
+    assert_eq!(perm_any.check_read(true, &"user".to_string(), &"a_auth".to_string()), Ok(false));
+    assert_eq!(perm_any.check_read(true, &"user@example.com".to_string(), &"g_auth".to_string()), Ok(true));
+    assert_eq!(perm_any.check_write(false, &"".to_string(), &"".to_string()), Ok(true));
+
+    assert_eq!(perm.check_write(true, &"user@example.com".to_string(), &"g_auth".to_string()), Ok(true));
+    assert_eq!(perm.check_write(true, &"bot".to_string(), &"key_auth".to_string()), Ok(true));
+    assert_eq!(perm.check_write(true, &"user".to_string(), &"a_auth".to_string()), Ok(false));
+    assert_eq!(perm.check_admin(true, &"user".to_string(), &"g_auth".to_string()), Ok(false));
+    assert_eq!(perm.check_admin(true, &"user".to_string(), &"a_auth".to_string()), Ok(true));
+
+    assert_eq!(perm.create_json(), Ok(json!({"read": [["key_user", "any"]], "write": [["g_user", "authed"], ["key_user", "bot"]], "admin": [["a_user", "user"]]})));
+}
+
+#[test]
+pub fn test_permission_json()
+{
+    let perm = Permission::new(&json!({"read": [["a_user", "any"]], "write": [["a_user", "user"], ["g_user", "user@example.com"]]}), "key").unwrap();
+
+    // Without admin permissions only the owner may change the permissions
+    assert!(perm.admin_users.is_empty());
+    assert!(perm.admin_groups.is_empty());
+
+    let perm = Permission::new(&json!({"read": [], "write": [], "admin": [["g_user", "authed"]]}), "key").unwrap();
+    assert_eq!(perm.create_json(), Ok(json!({"read": [], "write": [], "admin": [["g_user", "authed"]]})));
+
+    assert!(Permission::new(&json!({"read": [], "write": [], "admin": [["x_account", "user"]]}), "key").is_err());
+    assert!(Permission::new(&json!({"read": [], "write": [], "admin": "user"}), "key").is_err());
+}
+
+#[test]
+pub fn test_permission_other_domains()
+{
+    let bot = UserAuthentication{is_authed: true, name: "bot".to_string(), domain: "key_auth".to_string()};
+    let other = UserAuthentication{is_authed: true, name: "bot".to_string(), domain: "a_auth".to_string()};
+    let groups = GroupDirectory::new();
+
+    assert_eq!(super::entry_domain("key_auth"), Some("key_user".to_string()));
+    assert_eq!(super::auth_domain("key_user"), Some("key_auth".to_string()));
+    assert_eq!(super::entry_domain("key"), None);
+
+    let perm = Permission::new(&json!({"read": [["key_user", "authed"]], "write": [["key_user", "bot"]]}), "key").unwrap();
+    assert_eq!(perm.check_user_read(&bot, &groups), Ok(true));
+    assert_eq!(perm.check_user_write(&bot, &groups), Ok(true));
+    assert_eq!(perm.check_user_write(&other, &groups), Ok(false));
+    assert_eq!(perm.create_json(), Ok(json!({"read": [["key_user", "authed"]], "write": [["key_user", "bot"]], "admin": []})));
+
+    assert!(Permission::new(&json!({"read": [["key", "bot"]], "write": []}), "key").is_err());
+
+    let owner = Owner::from_json(&json!(["key_user", "bot"])).unwrap().unwrap();
+    assert!(owner.is_user(&bot));
+    assert_eq!(owner.create_json(), json!(["key_user", "bot"]));
+}
+
+#[test]
+pub fn test_owner()
+{
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain: "g_auth".to_string()};
+    let owner = Owner::from_user(&user).unwrap();
+
+    assert!(owner.is_user(&user));
+    assert!(!owner.is_user(&UserAuthentication{is_authed: true, name: "user".to_string(), domain: "a_auth".to_string()}));
+    assert!(!owner.is_user(&UserAuthentication{is_authed: false, name: "user".to_string(), domain: "g_auth".to_string()}));
+    assert!(Owner::from_user(&UserAuthentication::new()).is_none());
+
+    assert_eq!(owner.create_json(), json!(["g_user", "user"]));
+    assert_eq!(Owner::from_json(&json!(["g_user", "user"])), Ok(Some(owner)));
+    assert_eq!(Owner::from_json(&json!("self")), Ok(None));
+    assert!(Owner::from_json(&json!(["x_account", "user"])).is_err());
+    assert!(Owner::from_json(&json!("user")).is_err());
+}
+
+#[test]
+pub fn test_permission_groups()
+{
+    let config = Database::new("config");
+    let groups = GroupDirectory::new();
+
+    let jordan = UserAuthentication{is_authed: true, name: "term.jordan".to_string(), domain: "a_auth".to_string()};
+    let carter = UserAuthentication{is_authed: true, name: "term.carter".to_string(), domain: "g_auth".to_string()};
+
+    let perm = Permission::new(&json!({"read": [["group", "viewers"]], "write": [["group", "operators"]], "admin": [["group", "operators"]]}), "key").unwrap();
+    assert_eq!(perm.create_json(), Ok(json!({"read": [["group", "viewers"]], "write": [["group", "operators"]], "admin": [["group", "operators"]]})));
+
+    // Groups are only resolved once a config database is attached
+    assert_eq!(perm.check_user_write(&jordan, &groups), Ok(false));
+
+    groups.set_config(&config);
+    assert_eq!(perm.check_user_write(&jordan, &groups), Ok(false));
+
+    config.write("groups", json!({"operators": [["a_user", "term.jordan"]], "viewers": [["a_user", "term.jordan"], ["g_user", "term.carter"]]})).unwrap();
+    assert_eq!(perm.check_user_write(&jordan, &groups), Ok(true));
+    assert_eq!(perm.check_user_admin(&jordan, &groups), Ok(true));
+    assert_eq!(perm.check_user_write(&carter, &groups), Ok(false));
+    assert_eq!(perm.check_user_read(&carter, &groups), Ok(true));
+
+    // Members are matched on both the domain and the name
+    let carter_a = UserAuthentication{is_authed: true, name: "term.carter".to_string(), domain: "a_auth".to_string()};
+    assert_eq!(perm.check_user_read(&carter_a, &groups), Ok(false));
+    assert!(!groups.is_member("viewers", &UserAuthentication{is_authed: false, name: "term.jordan".to_string(), domain: "a_auth".to_string()}));
+
+    config.write("groups", json!({"operators": [["a_user", "term.jordan"], ["g_user", "term.carter"]]})).unwrap();
+    assert_eq!(perm.check_user_write(&carter, &groups), Ok(true));
+    assert_eq!(perm.check_user_read(&carter, &groups), Ok(false));
+}
+
+#[test]
+pub fn test_default_permissions()
+{
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain: "a_auth".to_string()};
+    let groups = GroupDirectory::new();
+
+    // Without any rules new keys get the usual default permissions
+    let defaults = DefaultPermissions::new(None, None).unwrap();
+    assert_eq!(defaults.for_key("key").create_json(), Permission::default().create_json());
+    assert_eq!(defaults.create_json(), Ok(serde_json::Map::new()));
+
+    let defaults = DefaultPermissions::new(Some(&json!({"read": [["a_user", "any"]], "write": [["a_user", "bot"]]})),
+                                           Some(&json!({"a.": {"read": [], "write": [["a_user", "user"]]},
+                                                        "a.b.": {"read": [], "write": []}}))).unwrap();
+
+    assert_eq!(defaults.for_key("key").check_user_write(&user, &groups), Ok(false));
+    assert_eq!(defaults.for_key("key").check_user_read(&user, &groups), Ok(true));
+    assert_eq!(defaults.for_key("a.key").check_user_write(&user, &groups), Ok(true));
+
+    // The longest matching prefix wins
+    assert_eq!(defaults.for_key("a.b.key").check_user_write(&user, &groups), Ok(false));
+
+    let json = defaults.create_json().unwrap();
+    assert_eq!(json["default_permissions"], json!({"read": [["a_user", "any"]], "write": [["a_user", "bot"]], "admin": []}));
+    assert_eq!(json["prefix_permissions"]["a."], json!({"read": [], "write": [["a_user", "user"]], "admin": []}));
+
+    assert!(DefaultPermissions::new(None, Some(&json!([]))).is_err());
+    assert!(DefaultPermissions::new(Some(&json!({"read": []})), None).is_err());
+}
+
+#[test]
+pub fn test_database_dirty()
+{
+    let db = Database::create("Database", chashmap::CHashMap::new());
+    assert!(!db.is_dirty());
+
+    db.write("key", json!([0])).unwrap();
+    db.write("other", json!(0)).unwrap();
+    assert!(db.is_dirty());
+    assert!(db.mark_clean());
+    assert!(!db.is_dirty());
+    assert!(!db.mark_clean());
+
+    assert!(db.write_index("other", 0, json!(1)).is_err());
+    assert!(db.append("other", json!(1)).is_err());
+    assert!(!db.is_dirty());
+
+    db.write_index("key", 0, json!(1)).unwrap();
+    assert!(db.mark_clean());
+
+    db.append("key", json!(2)).unwrap();
+    assert!(db.mark_clean());
+
+    assert!(Database::new("New Database").is_dirty());
+}
+
+#[test]
+pub fn test_database_compare_and_set()
+{
+    let db = Database::new("Database");
+
+    assert!(db.compare_and_set("key", &json!(null), json!(0)).is_err());
+
+    db.write("key", json!({"state": "open"})).unwrap();
+    assert_eq!(db.compare_and_set("key", &json!({"state": "closed"}), json!(0)), Ok((false, json!({"state": "open"}))));
+    assert_eq!(db.compare_and_set("key", &json!({"state": "open"}), json!({"state": "closed"})), Ok((true, json!({"state": "closed"}))));
+    assert_eq!(db.read("key"), Ok(json!({"state": "closed"})));
+}
+
+#[test]
+pub fn test_database_increment_decrement()
+{
+    let db = Database::new("Database");
+
+    assert!(db.increment("key", &json!(1)).is_err());
+
+    db.write("key", json!(10)).unwrap();
+    db.write("other", json!("10")).unwrap();
+
+    assert_eq!(db.increment("key", &json!(1)), Ok(json!(11)));
+    assert_eq!(db.decrement("key", &json!(20)), Ok(json!(-9)));
+    assert_eq!(db.increment("key", &json!(0.5)), Ok(json!(-8.5)));
+    assert_eq!(db.decrement("key", &json!(-1)), Ok(json!(-7.5)));
+    assert!(db.increment("key", &json!("1")).is_err());
+    assert!(db.increment("other", &json!(1)).is_err());
+    assert_eq!(db.read("key"), Ok(json!(-7.5)));
+
+    db.write("key", json!(i64::MAX)).unwrap();
+    assert_eq!(db.increment("key", &json!(1)), Ok(json!(i64::MAX as u64 + 1)));
+    assert!(db.increment("key", &json!(u64::MAX)).is_err());
+    assert_eq!(db.read("key"), Ok(json!(i64::MAX as u64 + 1)));
+
+    db.write("key", json!(i64::MIN)).unwrap();
+    assert!(db.decrement("key", &json!(1)).is_err());
+    assert_eq!(db.read("key"), Ok(json!(i64::MIN)));
+}
+
+#[test]
+pub fn test_database_toggle()
+{
+    let db = Database::new("Database");
+
+    assert!(db.toggle("key").is_err());
+
+    db.write("key", json!(false)).unwrap();
+    db.write("other", json!(0)).unwrap();
+
+    assert_eq!(db.toggle("key"), Ok(true));
+    assert_eq!(db.toggle("key"), Ok(false));
+    assert!(db.toggle("other").is_err());
+    assert_eq!(db.read("key"), Ok(json!(false)));
+}
+
+#[test]
+pub fn test_database_paths()
+{
+    let db = Database::new("Database");
+
+    assert!(db.read_path("key", "/a").is_err());
+
+    db.write("key", json!({"term.jordan": {"tokens": ["a", "b"]}, "a/b": 0, "c~d": 1})).unwrap();
+
+    assert_eq!(db.read_path("key", ""), db.read("key"));
+    assert_eq!(db.read_path("key", "/term.jordan/tokens/1"), Ok(json!("b")));
+    assert_eq!(db.read_path("key", "/a~1b"), Ok(json!(0)));
+    assert_eq!(db.read_path("key", "/c~0d"), Ok(json!(1)));
+    assert!(db.read_path("key", "/term.jordan/tokens/2").is_err());
+    assert!(db.read_path("key", "term.jordan").is_err());
+
+    db.write_path("key", "/term.jordan/tokens/0", json!("z")).unwrap();
+    db.write_path("key", "/term.jordan/tokens/-", json!("c")).unwrap();
+    db.write_path("key", "/term.jordan/tokens/3", json!("d")).unwrap();
+    db.write_path("key", "/term.carter", json!({"tokens": []})).unwrap();
+    assert!(db.write_path("key", "/term.jordan/tokens/5", json!("f")).is_err());
+    assert!(db.write_path("key", "/term.jordan/tokens/01", json!("f")).is_err());
+    assert!(db.write_path("key", "/missing/tokens", json!("f")).is_err());
+    assert!(db.write_path("key", "/a~1b/c", json!("f")).is_err());
+
+    assert_eq!(db.delete_path("key", "/term.jordan/tokens/1"), Ok(json!("b")));
+    assert_eq!(db.delete_path("key", "/c~0d"), Ok(json!(1)));
+    assert!(db.delete_path("key", "/c~0d").is_err());
+    assert!(db.delete_path("key", "").is_err());
+
+    assert_eq!(db.read("key"), Ok(json!({"term.jordan": {"tokens": ["z", "c", "d"]}, "term.carter": {"tokens": []}, "a/b": 0})));
+
+    db.write_path("key", "", json!([])).unwrap();
+    assert_eq!(db.read("key"), Ok(json!([])));
+}
+
+#[test]
+pub fn test_database_merge_patch()
+{
+    let db = Database::new("Database");
+
+    assert!(db.patch("key", &json!({})).is_err());
+
+    db.write("key", json!({"title": "Goodbye!", "author": {"givenName": "John", "familyName": "Doe"}, "tags": ["example", "sample"], "content": "text"})).unwrap();
+
+    assert_eq!(db.patch("key", &json!({"title": "Hello!", "phoneNumber": "+01-123-456-7890", "author": {"familyName": null}, "tags": ["example"]})),
+                Ok(json!({"title": "Hello!", "author": {"givenName": "John"}, "tags": ["example"], "content": "text", "phoneNumber": "+01-123-456-7890"})));
+
+    db.write("other", json!(["a"])).unwrap();
+    assert_eq!(db.patch("other", &json!({"a": {"b": "c"}})), Ok(json!({"a": {"b": "c"}})));
+}
+
+#[test]
+pub fn test_database_json_patch()
+{
+    let db = Database::new("Database");
+
+    db.write("key", json!({"foo": ["bar", "baz"], "qux": {"corge": 1}})).unwrap();
+
+    assert_eq!(db.patch("key", &json!([
+                    {"op": "test", "path": "/foo/0", "value": "bar"},
+                    {"op": "add", "path": "/foo/1", "value": "qux"},
+                    {"op": "remove", "path": "/foo/0"},
+                    {"op": "replace", "path": "/qux/corge", "value": 2},
+                    {"op": "copy", "from": "/qux", "path": "/copy"},
+                    {"op": "move", "from": "/foo/1", "path": "/moved"}])),
+                Ok(json!({"foo": ["qux"], "qux": {"corge": 2}, "copy": {"corge": 2}, "moved": "baz"})));
+
+    // A failing operation leaves the value untouched
+    assert!(db.patch("key", &json!([{"op": "remove", "path": "/foo"}, {"op": "test", "path": "/moved", "value": "bar"}])).is_err());
+    assert!(db.patch("key", &json!([{"op": "replace", "path": "/missing", "value": 0}])).is_err());
+    assert!(db.patch("key", &json!([{"op": "move", "from": "/qux", "path": "/qux/inner"}])).is_err());
+    assert!(db.patch("key", &json!([{"op": "add", "value": 0}])).is_err());
+    assert!(db.patch("key", &json!([{"op": "unknown", "path": "/foo"}])).is_err());
+    assert_eq!(db.read("key"), Ok(json!({"foo": ["qux"], "qux": {"corge": 2}, "copy": {"corge": 2}, "moved": "baz"})));
+}
@@ -9,6 +9,17 @@ fn main()
     let opt = args::Arguments::from_args();
     logging::initialize_logging(&opt);
 
+    if let Some(args::Subcommand::MintToken{user, token, label, expires_in}) = &opt.subcommand
+    {
+        match server::mint_token(&opt, user, token.as_deref(), label.as_deref(), *expires_in)
+        {
+            Ok(token) => println!("{}", token),
+            Err(e) => eprintln!("Unable to mint a token: {}", e)
+        }
+
+        return;
+    }
+
     if let Ok(rt) = tokio::runtime::Runtime::new()
     {
         match rt.block_on(async {connect::run(opt).await})
@@ -1,27 +1,214 @@
-pub fn google_authenticate(id: &str) -> Result<Option<String>, String>
+use crate::args::Arguments;
+
+use super::{Server, KeySet, verify_jwt, current_time};
+
+use serde_json::{Value, json};
+
+/// Result of an authentication attempt which could be checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthOutcome
+{
+    /// The user was authenticated with the given name, and the credential kept with their session to check it still
+    /// allows them in when the session is resumed
+    Accepted(String, Value),
+
+    /// The user was refused, for the given reason
+    Refused(String)
+}
+
+/// Scheme users can authenticate with, registered on the server under the domain given to the users it authenticates
+///
+/// Users of a domain ending in `_auth` are named in permissions by the matching domain ending in `_user`, so a provider
+/// for `key_auth` has its users named as `["key_user", "NAME"]`.
+pub trait AuthProvider: std::fmt::Debug + Send + Sync
+{
+    /// Domain given to users authenticated by the provider, such as `a_auth`
+    fn domain(&self) -> &str;
+
+    /// Authenticate a user from the arguments of an authentication command
+    fn authenticate(&self, server: &Server, args: &serde_json::Map<String, Value>) -> Result<AuthOutcome, String>;
+
+    /// Check the credential a user was accepted with still allows them in, when their session is resumed
+    fn revalidate(&self, _server: &Server, _name: &str, _credential: &Value) -> Result<bool, String>
+    {
+        Ok(true)
+    }
+}
+
+/// Get a string argument of an authentication command, or throw an error
+pub fn auth_argument(args: &serde_json::Map<String, Value>, key: &str) -> Result<String, String>
+{
+    match args.get(key)
+    {
+        Some(Value::String(s)) => Ok(s.clone()),
+        _ =>
+        {
+            let msg = format!("Authentication requires the string argument `{}`", key);
+            log::error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Authentication with an id and a token checked against the `a_users` key of the config database
+#[derive(Debug)]
+pub struct AcronymAuthProvider;
+
+impl AuthProvider for AcronymAuthProvider
 {
-    let client_id = match std::env::var("OAUTH_CLIENT_ID")
+    fn domain(&self) -> &str
+    {
+        "a_auth"
+    }
+
+    fn authenticate(&self, server: &Server, args: &serde_json::Map<String, Value>) -> Result<AuthOutcome, String>
+    {
+        let id = auth_argument(args, "id")?;
+        let token = auth_argument(args, "token")?;
+
+        match server.check_a_auth(&id, &token)?
+        {
+            (Some(entry), _) => Ok(AuthOutcome::Accepted(id, entry)),
+            (None, msg) => Ok(AuthOutcome::Refused(msg))
+        }
+    }
+
+    /// The token the user authenticated with must still be listed for them, and not have expired
+    fn revalidate(&self, server: &Server, name: &str, credential: &Value) -> Result<bool, String>
+    {
+        server.check_a_token(name, credential)
+    }
+}
+
+/// Authentication with a Google id token, an RS256 JWT verified against the keys of a JWKS, for the accounts allowed
+/// by the `g_users` and `g_domains` keys of the config database
+#[derive(Debug)]
+pub struct GoogleAuthProvider
+{
+    client_id: String,
+    issuers: Vec<String>,
+    hosted_domain: Option<String>,
+    keys: KeySet
+}
+
+impl GoogleAuthProvider
+{
+    /// Create a provider accepting tokens issued to the given client id, by one of the given issuers and, if given,
+    /// for accounts of the given hosted domain
+    pub fn new(client_id: &str, issuers: Vec<String>, hosted_domain: Option<String>, keys: KeySet) -> Self
+    {
+        Self
+        {
+            client_id: client_id.to_string(),
+            issuers,
+            hosted_domain,
+            keys
+        }
+    }
+
+    /// Create a provider from the command line arguments, if a client id is given
+    pub fn from_arguments(opt: &Arguments) -> Option<Self>
+    {
+        opt.google_client_id.as_ref().map(|client_id|
+        {
+            let keys = KeySet::new(&opt.jwks, opt.jwks_cache);
+            keys.refresh();
+
+            Self::new(client_id, opt.google_issuers.clone(), opt.google_hosted_domain.clone(), keys)
+        })
+    }
+
+    /// Check the claims of a verified token, returning the reason the token is refused if it should be
+    fn check_claims(&self, claims: &Value, now: u64) -> Option<String>
     {
-        Ok(v) => v,
-        Err(_) =>
+        let audience_matches = match &claims["aud"]
         {
-            log::error!("Unable to load Oauth client id from environment variable `OAUTH_CLIENT_ID`");
-            "UNKNOWN".to_string()
+            Value::String(aud) => aud == &self.client_id,
+            Value::Array(auds) => auds.iter().any(|aud| aud == &Value::String(self.client_id.clone())),
+            _ => false
+        };
+
+        if !audience_matches
+        {
+            return Some("Failed, token was not issued for this client".to_string());
         }
-    };
 
-    let mut client = google_signin::Client::new();
-    client.audiences.push(client_id); // required
-    client.hosted_domains.push("https://accounts.google.com".to_string()); // optional
+        match claims["iss"].as_str()
+        {
+            Some(iss) if self.issuers.iter().any(|issuer| issuer == iss) => {},
+            _ => return Some(format!("Failed, token issuer {} is not trusted", claims["iss"]))
+        }
 
-    // Let the crate handle everything for you
-    let id_info = match client.verify(id) {Ok(v) => v, Err(_) => {return Ok(None)}};
-    log::info!("Success! Signed-in as {}", id_info.sub);
+        match claims["exp"].as_u64()
+        {
+            Some(exp) if exp > now => {},
+            _ => return Some("Failed, token has expired".to_string())
+        }
+
+        if let Some(domain) = &self.hosted_domain
+        {
+            if claims["hd"].as_str() != Some(domain)
+            {
+                return Some(format!("Failed, account is not part of the hosted domain `{}`", domain));
+            }
+        }
+
+        if claims["email_verified"] != Value::Bool(true)
+        {
+            return Some("Failed, email address is not verified".to_string());
+        }
 
+        None
+    }
+}
+
+impl AuthProvider for GoogleAuthProvider
+{
+    fn domain(&self) -> &str
+    {
+        "g_auth"
+    }
+
+    fn authenticate(&self, server: &Server, args: &serde_json::Map<String, Value>) -> Result<AuthOutcome, String>
+    {
+        let id = auth_argument(args, "id_token")?;
+
+        let claims = match verify_jwt(&id, &self.keys)
+        {
+            Ok(claims) => claims,
+            Err(msg) =>
+            {
+                log::warn!("Refused Google id token: {}", msg);
+                return Ok(AuthOutcome::Refused(msg));
+            }
+        };
+
+        if let Some(msg) = self.check_claims(&claims, current_time())
+        {
+            log::warn!("Refused Google id token: {}", msg);
+            return Ok(AuthOutcome::Refused(msg));
+        }
+
+        let email = match claims["email"].as_str()
+        {
+            Some(email) => email,
+            None => return Ok(AuthOutcome::Refused("Failed, token does not contain an email address".to_string()))
+        };
+
+        match server.check_g_users(email, claims["hd"].as_str())?
+        {
+            (true, _) =>
+            {
+                log::info!("Success! Signed-in as {}", email);
+                Ok(AuthOutcome::Accepted(email.to_string(), json!({"hd": claims["hd"]})))
+            },
+            (false, msg) => Ok(AuthOutcome::Refused(msg))
+        }
+    }
 
-    match id_info.email
+    /// The account must still be allowed by the `g_users` or `g_domains` keys of the config database
+    fn revalidate(&self, server: &Server, name: &str, credential: &Value) -> Result<bool, String>
     {
-        Some(v) => Ok(Some(v)),
-        None => Err("No email given".to_string())
+        Ok(server.check_g_users(name, credential["hd"].as_str())?.0)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,291 @@
+use crate::database::{UpdateSender, ConnectionId, UserAuthentication, entry_domain, auth_domain};
+
+use serde_json::Value;
+use log::{trace, warn};
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// User events are addressed to, by the domain they authenticated with (such as `a_auth`) and their name, so users of
+/// different domains sharing a name are never sent each other's events
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Recipient
+{
+    pub domain: String,
+    pub name: String
+}
+
+impl Recipient
+{
+    /// Get the recipient for an authenticated user
+    pub fn from_user(user: &UserAuthentication) -> Self
+    {
+        Self
+        {
+            domain: user.domain.clone(),
+            name: user.name.clone()
+        }
+    }
+
+    /// Get the recipient for a `[domain, name]` permission entry, such as a member of a group
+    pub fn from_entry(domain: &str, name: &str) -> Option<Self>
+    {
+        auth_domain(domain).map(|domain| Self{domain, name: name.to_string()})
+    }
+
+    /// Parse the address of a recipient, a bare name addresses an a_user and users of other domains are addressed as
+    /// `DOMAIN:NAME` with the domain naming them in permissions, such as `g_user:user@example.com`
+    pub fn parse(address: &str) -> Self
+    {
+        if let Some((domain, name)) = address.split_once(':')
+        {
+            if let Some(recipient) = Self::from_entry(domain, name)
+            {
+                return recipient;
+            }
+        }
+
+        Self
+        {
+            domain: "a_auth".to_string(),
+            name: address.to_string()
+        }
+    }
+
+    /// Produce the address of the recipient, in the form read by `parse`
+    pub fn address(&self) -> String
+    {
+        match entry_domain(&self.domain)
+        {
+            Some(domain) if self.domain != "a_auth" => format!("{}:{}", domain, self.name),
+            _ => self.name.clone()
+        }
+    }
+}
+
+/// Open connections, by user and by connection id
+#[derive(Debug, Default)]
+struct Connections
+{
+    users: HashMap<Recipient, HashMap<ConnectionId, UpdateSender>>,
+    owners: HashMap<ConnectionId, Recipient>
+}
+
+/// Event channels for the connections of authenticated users, so a user may have any number of connections open at once
+#[derive(Debug, Clone)]
+pub struct EventRouter
+{
+    next_id: Arc<AtomicU64>,
+    connections: Arc<RwLock<Connections>>
+}
+
+/// Recipients of an event sent to several users, by their addresses
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Delivery
+{
+    /// Users the event was sent to at least one connection of
+    pub delivered: Vec<String>,
+
+    /// Users the event was addressed to who have no connections open
+    pub offline: Vec<String>,
+
+    /// Offline users the event was queued for
+    pub queued: Vec<String>,
+
+    /// Users the sender is not permitted to send events to, who were not sent the event
+    pub denied: Vec<String>
+}
+
+impl EventRouter
+{
+    /// Create a router without any connections
+    pub fn new() -> Self
+    {
+        Self
+        {
+            next_id: Arc::new(AtomicU64::new(1)),
+            connections: Arc::new(RwLock::new(Connections::default()))
+        }
+    }
+
+    /// Lock the connections for reading
+    fn read(&self) -> RwLockReadGuard<'_, Connections>
+    {
+        match self.connections.read()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Lock the connections for writing
+    fn write(&self) -> RwLockWriteGuard<'_, Connections>
+    {
+        match self.connections.write()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Allocate the id for a new connection
+    pub fn next_connection_id(&self) -> ConnectionId
+    {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Register the event channel of a connection for a user, replacing any previous registration of the connection
+    pub fn register(&self, connection: ConnectionId, user: &Recipient, sender: UpdateSender)
+    {
+        self.remove(connection);
+
+        trace!("Registering connection {} for user `{}`", connection, user.address());
+
+        let mut connections = self.write();
+        connections.users.entry(user.clone()).or_default().insert(connection, sender);
+        connections.owners.insert(connection, user.clone());
+    }
+
+    /// Remove the event channel of a connection
+    pub fn remove(&self, connection: ConnectionId)
+    {
+        let mut connections = self.write();
+
+        if let Some(owner) = connections.owners.remove(&connection)
+        {
+            trace!("Removing connection {} for user `{}`", connection, owner.address());
+
+            if let Some(user) = connections.users.get_mut(&owner)
+            {
+                user.remove(&connection);
+
+                if user.is_empty()
+                {
+                    connections.users.remove(&owner);
+                }
+            }
+        }
+    }
+
+    /// Get the ids of the open connections of a user
+    pub fn connections(&self, user: &Recipient) -> Vec<ConnectionId>
+    {
+        let mut connections: Vec<ConnectionId> = match self.read().users.get(user)
+        {
+            Some(connections) => connections.keys().cloned().collect(),
+            None => vec![]
+        };
+
+        connections.sort_unstable();
+        connections
+    }
+
+    /// Get every user with a connection open
+    pub fn users(&self) -> Vec<Recipient>
+    {
+        let mut users: Vec<Recipient> = self.read().users.keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    /// Get the user a connection is registered for
+    pub fn owner(&self, connection: ConnectionId) -> Option<Recipient>
+    {
+        self.read().owners.get(&connection).cloned()
+    }
+
+    /// Send an event to every connection of a user, returns the number of connections it was sent to
+    pub fn send_to_user(&self, user: &Recipient, event: &Value) -> usize
+    {
+        match self.read().users.get(user)
+        {
+            Some(connections) => connections.iter().filter(|(connection, sender)| deliver(**connection, sender, event)).count(),
+            None =>
+            {
+                trace!("User `{}` has no connections, dropping event", user.address());
+                0
+            }
+        }
+    }
+
+    /// Send an event to one connection of a user, returns true if the user has the connection open
+    pub fn send_to_connection(&self, user: &Recipient, connection: ConnectionId, event: &Value) -> bool
+    {
+        match self.read().users.get(user).and_then(|connections| connections.get(&connection))
+        {
+            Some(sender) => deliver(connection, sender, event),
+            None =>
+            {
+                trace!("User `{}` has no connection {}, dropping event", user.address(), connection);
+                false
+            }
+        }
+    }
+
+    /// Send an event to every connection of each of the given users
+    pub fn send_to_users(&self, users: &[Recipient], event: &Value) -> Delivery
+    {
+        let mut delivery = Delivery::default();
+
+        for user in users
+        {
+            let address = user.address();
+
+            if delivery.delivered.contains(&address) || delivery.offline.contains(&address)
+            {
+                continue;
+            }
+
+            if self.send_to_user(user, event) > 0
+            {
+                delivery.delivered.push(address);
+            }
+            else
+            {
+                delivery.offline.push(address);
+            }
+        }
+
+        delivery
+    }
+
+    /// Send an event to every open connection of the given users except the one it came from
+    pub fn broadcast(&self, origin: ConnectionId, users: &[Recipient], event: &Value) -> Delivery
+    {
+        let mut delivery = Delivery::default();
+
+        for (user, connections) in self.read().users.iter().filter(|(user, _)| users.contains(user))
+        {
+            let sent = connections.iter().filter(|(connection, sender)| **connection != origin && deliver(**connection, sender, event)).count();
+
+            if sent > 0
+            {
+                delivery.delivered.push(user.address());
+            }
+        }
+
+        delivery.delivered.sort();
+        delivery
+    }
+}
+
+impl std::default::Default for EventRouter
+{
+    fn default() -> EventRouter
+    {
+        EventRouter::new()
+    }
+}
+
+/// Push an event down the channel of a connection, returns true if the channel was still open
+fn deliver(connection: ConnectionId, sender: &UpdateSender, event: &Value) -> bool
+{
+    if let Err(e) = sender.send(event.clone())
+    {
+        warn!("Unable to send event to connection {} ({})", connection, e);
+        return false;
+    }
+
+    true
+}
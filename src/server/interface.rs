@@ -1,12 +1,15 @@
 use super::Server;
 
-use crate::database::{DatabaseInterface, Database, UserAuthentication};
+use super::{AuthOutcome, Recipient};
+
+use crate::database::{DatabaseInterface, Database, DefaultPermissions, UserAuthentication, UpdateSender, ConnectionId, StagedChanges};
 use crate::commands::{Command, Commands};
 
 use serde_json::{Value, json};
 use log::{trace, error};
 
 use std::sync::Arc;
+use std::collections::HashMap;
 
 /// Extract a string from a json value, or throw an error
 fn extract_string(val: &Value, title: &str) -> Result<String, String>
@@ -47,6 +50,12 @@ fn extract_number(val: &Value, title: &str) -> Result<usize, String>
     }
 }
 
+/// Check if an optional boolean field is set to true
+fn extract_flag(map: &serde_json::Map<String, Value>, key: &str) -> bool
+{
+    matches!(map.get(key), Some(Value::Bool(true)))
+}
+
 /// Wrap a Result<Option<Value>, String> to include an optional unique ID
 fn add_unique_id(prev: Result<Option<Value>, String>, unique_id: Option<Value>) -> Result<Option<Value>, String>
 {
@@ -78,7 +87,12 @@ fn add_unique_id(prev: Result<Option<Value>, String>, unique_id: Option<Value>)
 pub struct ServerInterface
 {
     server: Arc<Server>,
-    pub user_profile: UserAuthentication
+    pub user_profile: UserAuthentication,
+    updates: Option<UpdateSender>,
+    events: Option<UpdateSender>,
+    connection: ConnectionId,
+    staging: Option<Arc<HashMap<String, DatabaseInterface>>>,
+    session: Option<String>
 }
 
 impl ServerInterface
@@ -89,7 +103,111 @@ impl ServerInterface
         Self
         {
             server: server.clone(),
-            user_profile: UserAuthentication::new()
+            user_profile: UserAuthentication::new(),
+            updates: None,
+            events: None,
+            connection: server.events.next_connection_id(),
+            staging: None,
+            session: None
+        }
+    }
+
+    /// Attach the channel subscription updates for this connection should be pushed to
+    pub fn attach_updates(&mut self, sender: UpdateSender)
+    {
+        if self.user_profile.is_authed
+        {
+            self.server.subscribers.register(self.connection, &self.user_profile, sender.clone());
+        }
+
+        self.updates = Some(sender);
+    }
+
+    /// Stop pushing subscription updates to this connection
+    pub fn detach_updates(&mut self)
+    {
+        self.updates = None;
+        self.server.subscribers.remove(self.connection);
+    }
+
+    /// Get the id of this connection, which events can be addressed to
+    pub fn connection_id(&self) -> ConnectionId
+    {
+        self.connection
+    }
+
+    /// Attach the channel events for this connection should be pushed to
+    pub fn attach_events(&mut self, sender: UpdateSender)
+    {
+        if self.user_profile.is_authed
+        {
+            self.server.events.register(self.connection, &Recipient::from_user(&self.user_profile), sender.clone());
+        }
+
+        self.events = Some(sender);
+    }
+
+    /// Stop pushing events to this connection
+    pub fn detach_events(&mut self)
+    {
+        self.events = None;
+        self.server.events.remove(self.connection);
+    }
+
+    /// Register the update and event channels for the newly authenticated user, and send the user any events which were
+    /// queued while they were offline
+    fn register_updates(&self) -> Result<(), String>
+    {
+        if let Some(sender) = &self.updates
+        {
+            self.server.subscribers.register(self.connection, &self.user_profile, sender.clone());
+        }
+
+        if let Some(sender) = &self.events
+        {
+            self.server.events.register(self.connection, &Recipient::from_user(&self.user_profile), sender.clone());
+
+            // Events are only removed from the queue once they are sent, so none are lost if the connection has closed
+            let kept = self.server.event_queue.deliver(&Recipient::from_user(&self.user_profile).address(), |event| sender.send(event).is_ok())?;
+
+            if kept > 0
+            {
+                let msg = format!("Unable to send queued events to connection {}", self.connection);
+                error!("{}", msg);
+                return Err(msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start a session for the newly authenticated user, returning the token which can resume it
+    fn start_session(&mut self, credential: &Value) -> Result<String, String>
+    {
+        let (id, token) = self.server.sessions.start(&self.user_profile, credential)?;
+        self.session = Some(id);
+
+        Ok(token)
+    }
+
+    /// Authenticate the connection with the provider registered for a domain, producing the response for the command
+    fn authenticate(&mut self, cmd: &str, domain: &str, args: &serde_json::Map<String, Value>) -> Result<Option<Value>, String>
+    {
+        match self.server.authenticate(domain, args)?
+        {
+            AuthOutcome::Accepted(name, credential) =>
+            {
+                self.user_profile.is_authed = true;
+                self.user_profile.domain = domain.to_string();
+                self.user_profile.name = name;
+
+                self.register_updates()?;
+
+                let session = self.start_session(&credential)?;
+
+                Ok(Some(json!({"cmd": cmd, "mode": "ok", "msg": "success", "session": session, "connection": self.connection})))
+            },
+            AuthOutcome::Refused(msg) => Ok(Some(json!({"cmd": cmd, "mode": "ok", "msg": msg})))
         }
     }
 
@@ -112,10 +230,204 @@ impl ServerInterface
         self.user_profile.is_authed = true;
     }
 
-    
+    /// Get a loaded database, or the staged copy of it while executing a transaction
+    fn get_database(&self, db_key: &str) -> Result<DatabaseInterface, String>
+    {
+        let database = match &self.staging
+        {
+            Some(staging) => staging.get(db_key).cloned(),
+            None => self.server.databases.get(db_key).map(|v| v.clone())
+        };
+
+        match database
+        {
+            Some(database) => Ok(database),
+            None =>
+            {
+                let msg = format!("No database with key `{}` initialized", db_key);
+                error!("{}", msg);
+                Err(msg)
+            }
+        }
+    }
+
+    /// Verify the user may administer a key, keys without an owner (such as keys loaded from items written before owners
+    /// were recorded) may also be administered by the users the `set_permissions` entry of `server_permissions` allows
+    fn check_key_admin(&self, database: &DatabaseInterface, key: &str) -> Result<(), String>
+    {
+        match database.check_admin(key, &self.user_profile)
+        {
+            Err(msg) if database.permissions.contains_key(key) && !database.owners.contains_key(key) =>
+                self.server.check_server_permission("set_permissions", &self.user_profile).map_err(|_| msg),
+            result => result
+        }
+    }
+
+    /// Execute a list of commands all-or-nothing, the commands are run against staged copies of the keys they touch
+    /// which are only written back to the databases once every command has succeeded
+    fn execute_transaction(&mut self, commands: Value) -> Result<Vec<Value>, String>
+    {
+        let commands = if let Value::Array(commands) = commands
+        {
+            commands
+        }
+        else
+        {
+            let msg = "Transaction commands are not an array".to_string();
+            error!("{}", msg);
+            return Err(msg);
+        };
+
+        let mut parsed = vec![];
+        let mut touched: HashMap<String, Vec<String>> = HashMap::new();
+        let mut created: Vec<(String, String)> = vec![];
 
-    /// Execute a command on the database
+        for (i, value) in commands.into_iter().enumerate()
+        {
+            let command = match Command::from_json(value)
+            {
+                Ok(command) => command,
+                Err(e) =>
+                {
+                    let msg = format!("Transaction command {} is invalid, {:?}", i, e);
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+            };
+
+            let is_write = match command.cmd
+            {
+                Commands::GetValue | Commands::GetIndex | Commands::GetLengthIndex | Commands::GetRecentIndex |
+                Commands::GetPath => false,
+                Commands::SetValue | Commands::SetIndex | Commands::AppendIndex | Commands::CompareAndSet |
+                Commands::Increment | Commands::Decrement | Commands::Toggle | Commands::SetPath | Commands::DeletePath |
+                Commands::PatchValue => true,
+                other =>
+                {
+                    let msg = format!("Command {:?} cannot be used in a transaction", other);
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+            };
+
+            let db_key = extract_string(command.data.get("db_key").unwrap(), "database key")?;
+            let key = extract_string(command.data.get("key").unwrap(), "item key")?;
+
+            // Permissions are checked before anything is staged, keys created earlier in the transaction are checked
+            // when the staged command runs
+            let database = self.get_database(&db_key)?;
+            let target = (db_key.clone(), key.clone());
+
+            if is_write
+            {
+                if database.verify_write(&key, &self.user_profile, command.cmd == Commands::SetValue)?
+                {
+                    created.push(target);
+                }
+            }
+            else if !created.contains(&target)
+            {
+                database.check_read(&key, &self.user_profile)?;
+            }
+
+            let keys = touched.entry(db_key).or_default();
+            if !keys.contains(&key)
+            {
+                keys.push(key);
+            }
+
+            parsed.push(command);
+        }
+
+        let mut staging = HashMap::new();
+        for (db_key, keys) in &touched
+        {
+            staging.insert(db_key.clone(), self.get_database(db_key)?.stage_keys(keys)?);
+        }
+
+        let mut staged = self.clone();
+        staged.staging = Some(Arc::new(staging));
+
+        let mut results = vec![];
+        for (i, command) in parsed.into_iter().enumerate()
+        {
+            match staged.run_command(command)
+            {
+                Ok(Some(result)) => results.push(result),
+                Ok(None) => {},
+                Err(e) =>
+                {
+                    let msg = format!("Transaction aborted at command {}, {}", i, e);
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+            }
+        }
+
+        if let Some(staging) = &staged.staging
+        {
+            let mut names: Vec<&String> = staging.keys().collect();
+            names.sort();
+
+            let mut committed: Vec<(DatabaseInterface, StagedChanges)> = vec![];
+
+            for db_key in names
+            {
+                let result = self.get_database(db_key).and_then(|database|
+                {
+                    let previous = database.commit_staged(&staging[db_key])?;
+                    Ok((database, previous))
+                });
+
+                match result
+                {
+                    Ok(commit) => committed.push(commit),
+                    Err(e) =>
+                    {
+                        // Undo the databases already committed, so the transaction is applied to none of them
+                        for (database, previous) in committed.iter().rev()
+                        {
+                            if let Err(e) = database.revert_staged(previous)
+                            {
+                                error!("Unable to revert transaction on database `{}` ({})", database.database.get_name(), e);
+                            }
+                        }
+
+                        let msg = format!("Transaction could not be applied to database `{}`, {}", db_key, e);
+                        error!("{}", msg);
+                        return Err(msg);
+                    }
+                }
+            }
+
+            for (database, changed) in &committed
+            {
+                database.notify_committed(changed);
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Execute a command on the database, a transaction blocks every other command until it has been applied
     pub fn execute_command(&mut self, command: Command) -> Result<Option<Value>, String>
+    {
+        let server = self.server.clone();
+
+        if command.cmd == Commands::Transaction
+        {
+            let _guard = server.transaction_guard();
+            self.run_command(command)
+        }
+        else
+        {
+            let _guard = server.command_guard();
+            self.run_command(command)
+        }
+    }
+
+    /// Execute a command without taking the transaction lock
+    fn run_command(&mut self, command: Command) -> Result<Option<Value>, String>
     {
         trace!("Executing command `{:?}` as {:?}", command.cmd, self.user_profile);
 
@@ -137,15 +449,23 @@ impl ServerInterface
             Commands::ReadFromDisk =>
             {
                 self.is_auth("ReadFromDisk")?;
+                self.server.check_server_permission("read_from_disk", &self.user_profile)?;
 
                 let db_key = extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
 
                 self.server.read_database_from_disk(&db_key)?;
+
+                if extract_flag(&cmd_map, "register")
+                {
+                    self.server.register_database(&db_key, &self.user_profile)?;
+                }
+
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": db_key})))
             },
             Commands::WriteToDisk =>
             {
                 self.is_auth("WriteToDisk")?;
+                self.server.check_server_permission("write_to_disk", &self.user_profile)?;
 
                 let db_key = extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
 
@@ -165,6 +485,7 @@ impl ServerInterface
             Commands::ListDatabases =>
             {
                 self.is_auth("ListDatabases")?;
+                self.server.check_server_permission("list_databases", &self.user_profile)?;
 
                 let keys = self.server.get_dbkeys()?;
 
@@ -177,16 +498,7 @@ impl ServerInterface
                 let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
                 let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
 
-                let data = match self.server.databases.get(db_key)
-                {
-                    Some(v) => {v.read_from_key(&key, &self.user_profile)?},
-                    None => 
-                    {
-                        let msg = format!("No database with key `{}` initialized", db_key);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-                };
+                let data = self.get_database(db_key)?.read_from_key(key, &self.user_profile)?;
 
                 Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "val": data})))
             },
@@ -199,16 +511,7 @@ impl ServerInterface
 
                 let data = cmd_map.remove("val").unwrap();
 
-                match self.server.databases.get(db_key)
-                {
-                    Some(v) => {v.write_to_key(&key, data, &self.user_profile)?},
-                    None => 
-                    {
-                        let msg = format!("No database with key `{}` initialized", db_key);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-                };
+                self.get_database(db_key)?.write_to_key(key, data, &self.user_profile)?;
 
                 Ok(Some(json!({"cmd": "set_value", "mode": "ok", "msg": "", "key": key, "db_key": db_key,})))
             },
@@ -220,16 +523,7 @@ impl ServerInterface
                 let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
                 let index = extract_number(cmd_map.get("index").unwrap(), "index")?;
 
-                let data = match self.server.databases.get(db_key)
-                {
-                    Some(v) => {v.read_from_key_index(&key, index, &self.user_profile)?},
-                    None => 
-                    {
-                        let msg = format!("No database with key `{}` initialized", db_key);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-                };
+                let data = self.get_database(db_key)?.read_from_key_index(key, index, &self.user_profile)?;
 
                 Ok(Some(json!({"cmd": "get_index", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "index": index, "val": data})))
             },
@@ -243,16 +537,7 @@ impl ServerInterface
 
                 let data = cmd_map.remove("val").unwrap();
 
-                match self.server.databases.get(db_key)
-                {
-                    Some(v) => {v.write_to_key_index(&key, index, data, &self.user_profile)?},
-                    None => 
-                    {
-                        let msg = format!("No database with key `{}` initialized", db_key);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-                };
+                self.get_database(db_key)?.write_to_key_index(key, index, data, &self.user_profile)?;
 
                 Ok(Some(json!({"cmd": "set_index", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "index": index})))
             },
@@ -265,16 +550,7 @@ impl ServerInterface
 
                 let data = cmd_map.remove("val").unwrap();
 
-                let index = match self.server.databases.get(db_key)
-                {
-                    Some(v) => {v.append_to_key(&key, data, &self.user_profile)?},
-                    None => 
-                    {
-                        let msg = format!("No database with key `{}` initialized", db_key);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-                };
+                let index = self.get_database(db_key)?.append_to_key(key, data, &self.user_profile)?;
 
                 Ok(Some(json!({"cmd": "append_list", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "next": index})))
             },
@@ -286,16 +562,7 @@ impl ServerInterface
                 let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
                 let index = extract_number(cmd_map.get("num").unwrap(), "read window")?;
 
-                let data = match self.server.databases.get(db_key)
-                {
-                    Some(v) => {v.read_last_n_from_key(&key, index, &self.user_profile)?},
-                    None => 
-                    {
-                        let msg = format!("No database with key `{}` initialized", db_key);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-                };
+                let data = self.get_database(db_key)?.read_last_n_from_key(key, index, &self.user_profile)?;
 
                 Ok(Some(json!({"cmd": "get_recent", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "val": data})))
             },
@@ -306,65 +573,331 @@ impl ServerInterface
                 let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
                 let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
 
-                let length = match self.server.databases.get(db_key)
-                {
-                    Some(v) => {v.get_length_from_key(&key, &self.user_profile)?},
-                    None => 
-                    {
-                        let msg = format!("No database with key `{}` initialized", db_key);
-                        error!("{}", msg);
-                        return Err(msg);
-                    }
-                };
+                let length = self.get_database(db_key)?.get_length_from_key(key, &self.user_profile)?;
 
                 Ok(Some(json!({"cmd": "get_list_length", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "length": length})))
             },
+            Commands::CompareAndSet =>
+            {
+                self.is_auth("CompareAndSet")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+
+                let expected = cmd_map.remove("expected").unwrap();
+                let data = cmd_map.remove("val").unwrap();
+
+                let (success, current) = self.get_database(db_key)?.compare_and_set_key(key, &expected, data, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "compare_and_set", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "success": success, "val": current})))
+            },
+            Commands::Increment =>
+            {
+                self.is_auth("Increment")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+                let by = cmd_map.remove("by").unwrap_or_else(|| json!(1));
+
+                let data = self.get_database(db_key)?.increment_key(key, &by, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "increment", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "val": data})))
+            },
+            Commands::Decrement =>
+            {
+                self.is_auth("Decrement")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+                let by = cmd_map.remove("by").unwrap_or_else(|| json!(1));
+
+                let data = self.get_database(db_key)?.decrement_key(key, &by, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "decrement", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "val": data})))
+            },
+            Commands::Toggle =>
+            {
+                self.is_auth("Toggle")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+
+                let data = self.get_database(db_key)?.toggle_key(key, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "toggle", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "val": data})))
+            },
+            Commands::GetPath =>
+            {
+                self.is_auth("GetPath")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+                let path = &extract_string(cmd_map.get("path").unwrap(), "path")?;
+
+                let data = self.get_database(db_key)?.read_from_key_path(key, path, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "get_path", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "path": path, "val": data})))
+            },
+            Commands::SetPath =>
+            {
+                self.is_auth("SetPath")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+                let path = &extract_string(cmd_map.get("path").unwrap(), "path")?;
+
+                let data = cmd_map.remove("val").unwrap();
+
+                self.get_database(db_key)?.write_to_key_path(key, path, data, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "set_path", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "path": path})))
+            },
+            Commands::DeletePath =>
+            {
+                self.is_auth("DeletePath")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+                let path = &extract_string(cmd_map.get("path").unwrap(), "path")?;
+
+                let removed = self.get_database(db_key)?.delete_from_key_path(key, path, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "delete_path", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "path": path, "val": removed})))
+            },
+            Commands::PatchValue =>
+            {
+                self.is_auth("PatchValue")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+
+                let patch = cmd_map.remove("val").unwrap();
+
+                self.get_database(db_key)?.patch_key(key, &patch, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "patch_value", "mode": "ok", "msg": "", "key": key, "db_key": db_key})))
+            },
+            Commands::DeleteKey =>
+            {
+                self.is_auth("DeleteKey")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+
+                self.get_database(db_key)?.delete_key(key, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "delete_key", "mode": "ok", "msg": "", "key": key, "db_key": db_key})))
+            },
+            Commands::UnloadDatabase =>
+            {
+                self.is_auth("UnloadDatabase")?;
+                self.server.check_server_permission("unload_database", &self.user_profile)?;
+
+                let db_key = extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+
+                self.get_database(&db_key)?.check_write_all(&self.user_profile)?;
+                self.server.unload_database(&db_key)?;
+
+                Ok(Some(json!({"cmd": "unload_database", "mode": "ok", "msg": "", "db_key": db_key})))
+            },
+            Commands::DeleteDatabase =>
+            {
+                self.is_auth("DeleteDatabase")?;
+                self.server.check_server_permission("delete_database", &self.user_profile)?;
+
+                let db_key = extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+
+                self.server.delete_database(&db_key, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "delete_database", "mode": "ok", "msg": "", "db_key": db_key})))
+            },
+            Commands::GetPermissions =>
+            {
+                self.is_auth("GetPermissions")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+
+                let database = self.get_database(db_key)?;
+                self.check_key_admin(&database, key)?;
+
+                let permissions = database.read_permissions(key)?;
+
+                Ok(Some(json!({"cmd": "get_permissions", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "val": permissions})))
+            },
+            Commands::SetPermissions =>
+            {
+                self.is_auth("SetPermissions")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+
+                let permissions = cmd_map.remove("val").unwrap();
+
+                let database = self.get_database(db_key)?;
+                self.check_key_admin(&database, key)?;
+
+                database.replace_permissions(key, &permissions)?;
+
+                Ok(Some(json!({"cmd": "set_permissions", "mode": "ok", "msg": "", "key": key, "db_key": db_key})))
+            },
+            Commands::GetMetadata =>
+            {
+                self.is_auth("GetMetadata")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+
+                let metadata = self.get_database(db_key)?.get_metadata(key, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "get_metadata", "mode": "ok", "msg": "", "key": key, "db_key": db_key, "val": metadata})))
+            },
             Commands::CreateDatabase =>
             {
                 self.is_auth("CreateDatabase")?;
+                self.server.check_server_permission("create_database", &self.user_profile)?;
 
                 let name = extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
 
-                self.server.databases.insert(name.clone(), DatabaseInterface::new(Database::new(&name), chashmap::CHashMap::new()));
+                if name == "config"
+                {
+                    let msg = "The config database cannot be replaced".to_string();
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+
+                // Replacing a loaded database throws away everything in it, so it must be asked for explicitly
+                if self.server.databases.contains_key(&name) && !extract_flag(&cmd_map, "force")
+                {
+                    let msg = format!("Database `{}` already exists, set `force` to replace it", name);
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+
+                let database = DatabaseInterface::new(Database::new(&name), chashmap::CHashMap::new());
+                database.set_default_permissions(DefaultPermissions::new(cmd_map.get("default_permissions"), cmd_map.get("prefix_permissions"))?);
+
+                self.server.insert_database(&name, database)?;
+
+                if extract_flag(&cmd_map, "register")
+                {
+                    self.server.register_database(&name, &self.user_profile)?;
+                }
                 Ok(Some(json!({"cmd": "create_database", "mode": "ok", "msg": "", "db_key": name})))
             },
-            Commands::AcronymAuth =>
+            Commands::AcronymAuth => self.authenticate("a_auth", "a_auth", &cmd_map),
+            Commands::GoogleAuth => self.authenticate("a_auth", "g_auth", &cmd_map),
+            Commands::Auth =>
+            {
+                let domain = extract_string(cmd_map.get("domain").unwrap(), "authentication domain")?;
+
+                self.authenticate("auth", &domain, &cmd_map)
+            },
+            Commands::Resume =>
             {
-                let id = extract_string(cmd_map.get("id").unwrap(), "user id")?;
-                let token = extract_string(cmd_map.get("token").unwrap(), "user token")?;
+                let token = extract_string(cmd_map.get("session").unwrap(), "session token")?;
 
-                let (result, msg) = self.server.check_a_auth(&id, &token)?;
+                let (id, user, credential) = self.server.sessions.resume(&token)?;
 
-                if result
+                // The credential may have been revoked since the session started, which ends the session
+                if !self.server.revalidate(&user, &credential)?
                 {
-                    self.user_profile.is_authed = true;
-                    self.user_profile.domain = "a_auth".to_string();
-                    self.user_profile.name = id;
+                    self.server.sessions.end(&id);
+
+                    let msg = format!("Unable to resume session, the credentials of `{}` are no longer accepted", user.name);
+                    error!("{}", msg);
+                    return Err(msg);
                 }
 
-                Ok(Some(json!({"cmd": "a_auth", "mode": "ok", "msg": msg})))
+                self.user_profile = user;
+                self.session = Some(id);
+
+                self.register_updates()?;
+
+                Ok(Some(json!({"cmd": "resume", "mode": "ok", "msg": "success", "connection": self.connection})))
+            },
+            Commands::Logout =>
+            {
+                if let Some(id) = self.session.take()
+                {
+                    self.server.sessions.end(&id);
+                }
+
+                self.server.subscribers.remove(self.connection);
+                self.server.events.remove(self.connection);
+                self.user_profile = UserAuthentication::new();
+
+                Ok(Some(json!({"cmd": "logout", "mode": "ok", "msg": ""})))
+            },
+            Commands::GetQueue =>
+            {
+                self.is_auth("Get Queue")?;
+
+                Ok(Some(json!({"cmd": "get_queue", "mode": "ok", "msg": "", "val": self.server.event_queue.pending(&Recipient::from_user(&self.user_profile).address())})))
+            },
+            Commands::PurgeQueue =>
+            {
+                self.is_auth("Purge Queue")?;
+
+                let removed = self.server.event_queue.purge(&Recipient::from_user(&self.user_profile).address(), cmd_map.get("event_id"))?;
+
+                Ok(Some(json!({"cmd": "purge_queue", "mode": "ok", "msg": "", "val": removed})))
             },
-            Commands::GoogleAuth =>
+            Commands::EventReply =>
             {
-                let id = extract_string(cmd_map.get("id_token").unwrap(), "user id")?;
+                self.is_auth("Event Reply")?;
 
-                let name = super::authentication::google_authenticate(&id)?;
+                let event_id = extract_string(cmd_map.get("event_id").unwrap(), "event id")?;
+                let replier = Recipient::from_user(&self.user_profile);
 
-                match name
+                // The reply reports a failure if it gives an error message
+                let reply = match cmd_map.get("error")
                 {
-                    Some(value) => 
-                    {
-                        self.user_profile.is_authed = true;
-                        self.user_profile.domain = "g_auth".to_string();
-                        self.user_profile.name = value;
+                    Some(error) => json!({"cmd": "event_reply", "mode": "error", "msg": extract_string(error, "reply error")?, "event_id": event_id,
+                                          "origin": replier.address(), "data": cmd_map.get("data").unwrap()}),
+                    None => json!({"cmd": "event_reply", "mode": "ok", "msg": "", "event_id": event_id,
+                                   "origin": replier.address(), "data": cmd_map.get("data").unwrap()})
+                };
 
-                        Ok(Some(json!({"cmd": "a_auth", "mode": "ok", "msg": "success"})))
-                    },
-                    None =>
-                    {
-                        Ok(Some(json!({"cmd": "a_auth", "mode": "ok", "msg": "error"})))
-                    }
-                }
+                let origin = match cmd_map.get("connection")
+                {
+                    Some(connection) => Some(extract_number(connection, "connection id")? as ConnectionId),
+                    None => None
+                };
+
+                self.server.reply_rpc(&replier, &event_id, origin, &reply)?;
+
+                Ok(Some(json!({"cmd": "event_reply", "mode": "ack", "msg": "", "event_id": event_id})))
+            },
+            Commands::Subscribe =>
+            {
+                self.is_auth("Subscribe")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+
+                self.get_database(db_key)?.subscribe(key, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "subscribe", "mode": "ok", "msg": "", "key": key, "db_key": db_key})))
+            },
+            Commands::Unsubscribe =>
+            {
+                self.is_auth("Unsubscribe")?;
+
+                let db_key = &extract_string(cmd_map.get("db_key").unwrap(), "database key")?;
+                let key = &extract_string(cmd_map.get("key").unwrap(), "item key")?;
+
+                self.get_database(db_key)?.unsubscribe(key, &self.user_profile)?;
+
+                Ok(Some(json!({"cmd": "unsubscribe", "mode": "ok", "msg": "", "key": key, "db_key": db_key})))
+            },
+            Commands::Transaction =>
+            {
+                self.is_auth("Transaction")?;
+
+                let results = self.execute_transaction(cmd_map.remove("cmds").unwrap())?;
+
+                Ok(Some(json!({"cmd": "transaction", "mode": "ok", "msg": "", "val": results})))
             },
             Commands::Event =>
             {
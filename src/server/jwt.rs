@@ -0,0 +1,338 @@
+use super::current_time;
+
+use serde_json::Value;
+use log::{error, warn, info};
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Minimum number of seconds between reloading the key set because a token named a key it did not contain
+const MIN_RELOAD_INTERVAL: u64 = 60;
+
+/// Number of seconds a request for a JWKS URL may wait on reading or writing before it is abandoned
+const FETCH_TIMEOUT: u64 = 10;
+
+/// Public half of an RSA key from a JWKS, as the big-endian modulus and exponent
+#[derive(Debug, Clone)]
+pub struct RsaKey
+{
+    n: Vec<u8>,
+    e: Vec<u8>
+}
+
+/// Keys loaded from a JWKS
+#[derive(Debug, Clone)]
+struct LoadedKeys
+{
+    loaded_at: u64,
+    keys: HashMap<String, RsaKey>
+}
+
+/// Signing keys for JWTs, loaded from a JWKS file or URL and cached for a number of seconds
+///
+/// Keys from a URL are fetched on a background thread, so checking a token never waits on the network, the previous keys
+/// are used until the new keys arrive.
+#[derive(Debug)]
+pub struct KeySet
+{
+    source: String,
+    lifetime: u64,
+    cache: Arc<RwLock<Option<LoadedKeys>>>,
+    refreshing: Arc<AtomicBool>,
+    attempted_at: AtomicU64
+}
+
+/// Decode a base64url value without padding, as used in JWTs and JWKS
+fn decode_base64url(data: &str, title: &str) -> Result<Vec<u8>, String>
+{
+    match base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+    {
+        Ok(data) => Ok(data),
+        Err(e) =>
+        {
+            let msg = format!("{} is not valid base64url ({})", title, e);
+            error!("{}", msg);
+            Err(msg)
+        }
+    }
+}
+
+/// Parse the RSA keys from a JWKS, keys of any other type are skipped
+pub fn parse_jwks(jwks: &Value) -> Result<HashMap<String, RsaKey>, String>
+{
+    let entries = match jwks.get("keys")
+    {
+        Some(Value::Array(entries)) => entries,
+        _ =>
+        {
+            let msg = "JWKS does not contain a `keys` array".to_string();
+            error!("{}", msg);
+            return Err(msg);
+        }
+    };
+
+    let mut keys = HashMap::new();
+
+    for entry in entries
+    {
+        match (entry["kty"].as_str(), entry["kid"].as_str(), entry["n"].as_str(), entry["e"].as_str())
+        {
+            (Some("RSA"), Some(kid), Some(n), Some(e)) =>
+            {
+                let n = decode_base64url(n, &format!("Modulus of key `{}`", kid))?;
+                let e = decode_base64url(e, &format!("Exponent of key `{}`", kid))?;
+
+                keys.insert(kid.to_string(), RsaKey{n, e});
+            },
+            _ => warn!("Skipping JWKS entry which is not an RSA key with an id")
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Fetch the contents of a URL
+fn fetch(url: &str) -> Result<String, String>
+{
+    let mut client = hyper::Client::with_connector(hyper::net::HttpsConnector::new(hyper_rustls::TlsClient::new()));
+    client.set_read_timeout(Some(std::time::Duration::from_secs(FETCH_TIMEOUT)));
+    client.set_write_timeout(Some(std::time::Duration::from_secs(FETCH_TIMEOUT)));
+
+    let mut response = match client.get(url).send()
+    {
+        Ok(response) => response,
+        Err(e) =>
+        {
+            let msg = format!("Unable to fetch `{}` ({})", url, e);
+            error!("{}", msg);
+            return Err(msg);
+        }
+    };
+
+    if !response.status.is_success()
+    {
+        let msg = format!("Fetching `{}` failed with status {}", url, response.status);
+        error!("{}", msg);
+        return Err(msg);
+    }
+
+    let mut body = String::new();
+
+    if let Err(e) = response.read_to_string(&mut body)
+    {
+        let msg = format!("Unable to read response from `{}` ({})", url, e);
+        error!("{}", msg);
+        return Err(msg);
+    }
+
+    Ok(body)
+}
+
+/// Read and parse a JWKS from a file path or an `http(s)://` URL
+fn load_keys(source: &str) -> Result<LoadedKeys, String>
+{
+    info!("Loading JWKS from `{}`", source);
+
+    let data = if is_url(source)
+    {
+        fetch(source)?
+    }
+    else
+    {
+        match std::fs::read_to_string(source)
+        {
+            Ok(data) => data,
+            Err(e) =>
+            {
+                let msg = format!("Unable to read JWKS file `{}` ({})", source, e);
+                error!("{}", msg);
+                return Err(msg);
+            }
+        }
+    };
+
+    let jwks: Value = match serde_json::from_str(&data)
+    {
+        Ok(jwks) => jwks,
+        Err(e) =>
+        {
+            let msg = format!("Unable to parse JWKS from `{}` ({})", source, e);
+            error!("{}", msg);
+            return Err(msg);
+        }
+    };
+
+    Ok(LoadedKeys
+    {
+        loaded_at: current_time(),
+        keys: parse_jwks(&jwks)?
+    })
+}
+
+/// Check if a JWKS source is a URL rather than a file path
+fn is_url(source: &str) -> bool
+{
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Replace the keys in a cache
+fn store_keys(cache: &RwLock<Option<LoadedKeys>>, loaded: LoadedKeys)
+{
+    match cache.write()
+    {
+        Ok(mut guard) => *guard = Some(loaded),
+        Err(poisoned) => *poisoned.into_inner() = Some(loaded)
+    }
+}
+
+impl KeySet
+{
+    /// Create a key set loaded from a file path or an `http(s)://` URL, keeping the keys for the given number of seconds
+    pub fn new(source: &str, lifetime: u64) -> Self
+    {
+        Self
+        {
+            source: source.to_string(),
+            lifetime,
+            cache: Arc::new(RwLock::new(None)),
+            refreshing: Arc::new(AtomicBool::new(false)),
+            attempted_at: AtomicU64::new(0)
+        }
+    }
+
+    /// Start reloading keys from a URL on a background thread, unless a reload is already running or the last attempt
+    /// was too recent while keys are still cached
+    pub fn refresh(&self)
+    {
+        if !is_url(&self.source)
+        {
+            return;
+        }
+
+        let now = current_time();
+        let has_keys = match self.cache.read()
+        {
+            Ok(guard) => guard.is_some(),
+            Err(poisoned) => poisoned.into_inner().is_some()
+        };
+
+        if has_keys && self.attempted_at.load(Ordering::SeqCst) + MIN_RELOAD_INTERVAL > now
+        {
+            return;
+        }
+
+        if self.refreshing.swap(true, Ordering::SeqCst)
+        {
+            return;
+        }
+
+        self.attempted_at.store(now, Ordering::SeqCst);
+
+        let source = self.source.clone();
+        let cache = self.cache.clone();
+        let refreshing = self.refreshing.clone();
+
+        std::thread::spawn(move ||
+        {
+            if let Ok(loaded) = load_keys(&source)
+            {
+                store_keys(&cache, loaded);
+            }
+
+            refreshing.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Get the key with the given id, reloading the key set if the cached copy has expired or does not contain it
+    ///
+    /// Keys from a file are reloaded immediately, keys from a URL are reloaded in the background and the cached keys are
+    /// used in the meantime, so a token may be refused until the first keys have been fetched.
+    pub fn get(&self, kid: &str) -> Result<Option<RsaKey>, String>
+    {
+        let now = current_time();
+
+        let cached = match self.cache.read()
+        {
+            Ok(guard) => guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        };
+
+        let needs_reload = match &cached
+        {
+            Some(loaded) if loaded.loaded_at + self.lifetime > now =>
+                !loaded.keys.contains_key(kid) && loaded.loaded_at + MIN_RELOAD_INTERVAL <= now,
+            _ => true
+        };
+
+        let loaded = match (needs_reload, cached)
+        {
+            (true, cached) if is_url(&self.source) =>
+            {
+                self.refresh();
+
+                match cached
+                {
+                    Some(loaded) => loaded,
+                    None =>
+                    {
+                        let msg = "Failed, the keys for checking the token have not been loaded yet".to_string();
+                        warn!("{}", msg);
+                        return Err(msg);
+                    }
+                }
+            },
+            (true, _) =>
+            {
+                let loaded = load_keys(&self.source)?;
+                store_keys(&self.cache, loaded.clone());
+
+                loaded
+            },
+            (false, cached) => cached.unwrap()
+        };
+
+        Ok(loaded.keys.get(kid).cloned())
+    }
+}
+
+/// Check the signature of an RS256 JWT against a key set, returning the claims if the signature is valid
+pub fn verify_jwt(token: &str, keys: &KeySet) -> Result<Value, String>
+{
+    let parts: Vec<&str> = token.split('.').collect();
+
+    if parts.len() != 3
+    {
+        return Err("Failed, token is not a JWT".to_string());
+    }
+
+    let header: Value = serde_json::from_slice(&decode_base64url(parts[0], "JWT header")?).map_err(|_| "Failed, JWT header is not json".to_string())?;
+
+    if header["alg"] != "RS256"
+    {
+        return Err(format!("Failed, JWT algorithm {} is not RS256", header["alg"]));
+    }
+
+    let key = match header["kid"].as_str()
+    {
+        Some(kid) => match keys.get(kid)?
+        {
+            Some(key) => key,
+            None => return Err(format!("Failed, JWT is signed with unknown key `{}`", kid))
+        },
+        None => return Err("Failed, JWT does not name its key".to_string())
+    };
+
+    let signature = decode_base64url(parts[2], "JWT signature")?;
+    let message = &token[..parts[0].len() + parts[1].len() + 1];
+
+    if ring::signature::primitive::verify_rsa(&ring::signature::RSA_PKCS1_2048_8192_SHA256,
+                                              (untrusted::Input::from(&key.n), untrusted::Input::from(&key.e)),
+                                              untrusted::Input::from(message.as_bytes()), untrusted::Input::from(&signature)).is_err()
+    {
+        return Err("Failed, JWT signature is not valid".to_string());
+    }
+
+    serde_json::from_slice(&decode_base64url(parts[1], "JWT claims")?).map_err(|_| "Failed, JWT claims are not json".to_string())
+}
@@ -1,11 +1,29 @@
 pub mod authentication;
 pub use authentication::*;
 
+pub mod events;
+pub use events::*;
+
 pub mod interface;
 pub use interface::*;
 
+pub mod jwt;
+pub use jwt::*;
+
+pub mod queue;
+pub use queue::*;
+
+pub mod rpc;
+pub use rpc::*;
+
 pub mod server;
 pub use server::*;
 
+pub mod sessions;
+pub use sessions::*;
+
+pub mod tokens;
+pub use tokens::*;
+
 #[cfg(test)]
 pub mod tests;
\ No newline at end of file
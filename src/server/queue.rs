@@ -0,0 +1,255 @@
+use super::current_time;
+use crate::database::write_replacing;
+
+use serde_json::{Value, json};
+use log::{error, warn, info, trace};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{RwLock, RwLockWriteGuard};
+
+/// Events held for users who were offline when the events were sent, written to a file so they survive a restart
+#[derive(Debug)]
+pub struct EventQueue
+{
+    path: Option<PathBuf>,
+    pending: RwLock<HashMap<String, Vec<Value>>>,
+    user_limit: usize,
+    limit: usize
+}
+
+/// Check if a queued entry, of the form `{"expires": TIME, "event": EVENT}`, has not yet expired
+fn is_live(entry: &Value, now: u64) -> bool
+{
+    entry["expires"].as_u64().map(|expires| expires > now) == Some(true)
+}
+
+impl EventQueue
+{
+    /// Create an empty queue which is only held in memory, holding at most `user_limit` events for each user and `limit`
+    /// events in all
+    pub fn new(user_limit: usize, limit: usize) -> Self
+    {
+        Self
+        {
+            path: None,
+            pending: RwLock::new(HashMap::new()),
+            user_limit,
+            limit
+        }
+    }
+
+    /// Load the queue from a file, which is created when an event is first queued if it does not exist, a queue file which
+    /// cannot be read is logged and moved aside, and the queue starts empty
+    pub fn load(path: PathBuf, user_limit: usize, limit: usize) -> Self
+    {
+        let pending = if path.exists()
+        {
+            let parsed = std::fs::read_to_string(&path).map_err(|e| e.to_string())
+                .and_then(|data| serde_json::from_str::<HashMap<String, Vec<Value>>>(&data).map_err(|e| e.to_string()));
+
+            match parsed
+            {
+                Ok(pending) => pending,
+                Err(e) =>
+                {
+                    error!("Unable to load event queue `{}`, starting with an empty queue ({})", path.display(), e);
+
+                    let aside = path.with_extension("json.corrupt");
+
+                    if let Err(e) = std::fs::rename(&path, &aside)
+                    {
+                        warn!("Unable to move the event queue `{}` aside to `{}` ({})", path.display(), aside.display(), e);
+                    }
+
+                    HashMap::new()
+                }
+            }
+        }
+        else
+        {
+            HashMap::new()
+        };
+
+        info!("Loaded {} queued events from `{}`", pending.values().map(Vec::len).sum::<usize>(), path.display());
+
+        Self
+        {
+            path: Some(path),
+            pending: RwLock::new(pending),
+            user_limit,
+            limit
+        }
+    }
+
+    /// Lock the pending events, dropping any which have expired
+    fn lock(&self) -> RwLockWriteGuard<'_, HashMap<String, Vec<Value>>>
+    {
+        let mut pending = match self.pending.write()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        };
+
+        let now = current_time();
+
+        for entries in pending.values_mut()
+        {
+            entries.retain(|entry| is_live(entry, now));
+        }
+
+        pending.retain(|_, entries| !entries.is_empty());
+
+        pending
+    }
+
+    /// Write the pending events to the queue file
+    fn save(&self, pending: &HashMap<String, Vec<Value>>) -> Result<(), String>
+    {
+        if let Some(path) = &self.path
+        {
+            if let Err(e) = write_replacing(path, &json!(pending).to_string())
+            {
+                let msg = format!("Unable to write event queue `{}` ({})", path.display(), e);
+                error!("{}", msg);
+                return Err(msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hold an event for a user until they next authenticate, or the given number of seconds pass, the event is refused if
+    /// the user or the queue as a whole already holds as many events as it may
+    pub fn push(&self, name: &str, event: &Value, ttl: u64) -> Result<(), String>
+    {
+        trace!("Queueing event for user `{}` for {} seconds", name, ttl);
+
+        let mut pending = self.lock();
+
+        if pending.get(name).map(Vec::len).unwrap_or(0) >= self.user_limit
+        {
+            let msg = format!("Unable to queue event for user `{}`, who already has {} queued events", name, self.user_limit);
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        if pending.values().map(Vec::len).sum::<usize>() >= self.limit
+        {
+            let msg = format!("Unable to queue event for user `{}`, the queue already holds {} events", name, self.limit);
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        pending.entry(name.to_string()).or_default().push(json!({"expires": current_time().saturating_add(ttl), "event": event}));
+
+        if let Err(e) = self.save(&pending)
+        {
+            // Keep the queue in memory matching the file
+            if let Some(entries) = pending.get_mut(name)
+            {
+                entries.pop();
+            }
+
+            pending.retain(|_, entries| !entries.is_empty());
+
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Get the events held for a user, oldest first, each as `{"expires": TIME, "event": EVENT}`
+    pub fn pending(&self, name: &str) -> Vec<Value>
+    {
+        self.lock().get(name).cloned().unwrap_or_default()
+    }
+
+    /// Remove and return the events held for a user, oldest first
+    pub fn take(&self, name: &str) -> Result<Vec<Value>, String>
+    {
+        let mut pending = self.lock();
+
+        let entries = match pending.remove(name)
+        {
+            Some(entries) => entries,
+            None => return Ok(vec![])
+        };
+
+        self.save(&pending)?;
+
+        Ok(entries.into_iter().map(|mut entry| entry["event"].take()).collect())
+    }
+
+    /// Send the events held for a user, oldest first, removing each event once it is sent, the events from the first
+    /// which could not be sent onwards are kept for the next time the user authenticates, returns the number kept
+    pub fn deliver(&self, name: &str, mut send: impl FnMut(Value) -> bool) -> Result<usize, String>
+    {
+        let mut pending = self.lock();
+
+        let entries = match pending.remove(name)
+        {
+            Some(entries) => entries,
+            None => return Ok(0)
+        };
+
+        let mut kept = vec![];
+
+        for entry in entries
+        {
+            if kept.is_empty() && send(entry["event"].clone())
+            {
+                continue;
+            }
+
+            kept.push(entry);
+        }
+
+        let count = kept.len();
+
+        if !kept.is_empty()
+        {
+            warn!("Unable to send {} queued events to user `{}`, keeping them queued", count, name);
+            pending.insert(name.to_string(), kept);
+        }
+
+        self.save(&pending)?;
+
+        Ok(count)
+    }
+
+    /// Remove the events held for a user, or only those with the given event id, returns the number removed
+    pub fn purge(&self, name: &str, event_id: Option<&Value>) -> Result<usize, String>
+    {
+        let mut pending = self.lock();
+
+        let removed = match (pending.get_mut(name), event_id)
+        {
+            (Some(entries), Some(event_id)) =>
+            {
+                let before = entries.len();
+                entries.retain(|entry| &entry["event"]["event_id"] != event_id);
+                before - entries.len()
+            },
+            (Some(_), None) => pending.remove(name).map(|entries| entries.len()).unwrap_or(0),
+            (None, _) => 0
+        };
+
+        pending.retain(|_, entries| !entries.is_empty());
+
+        if removed > 0
+        {
+            self.save(&pending)?;
+        }
+
+        Ok(removed)
+    }
+}
+
+impl std::default::Default for EventQueue
+{
+    fn default() -> EventQueue
+    {
+        EventQueue::new(usize::MAX, usize::MAX)
+    }
+}
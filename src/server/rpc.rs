@@ -0,0 +1,128 @@
+use crate::database::ConnectionId;
+
+use super::Recipient;
+
+use log::{error, trace, warn};
+
+use std::collections::HashMap;
+use std::sync::{RwLock, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// An rpc event waiting for its destination to reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingCall
+{
+    /// Connection the event came from, which the reply is sent to
+    pub origin: ConnectionId,
+
+    /// User the event came from
+    pub origin_user: Recipient,
+
+    /// User the event was sent to, who is the only user allowed to reply
+    pub destination: Recipient
+}
+
+/// Rpc events waiting for replies, by the connection they came from and their event id, each with a number identifying
+/// the call so a timeout for an earlier call never ends a later call reusing its event id
+#[derive(Debug)]
+pub struct RpcTracker
+{
+    next_call: AtomicU64,
+    pending: RwLock<HashMap<(ConnectionId, String), (u64, PendingCall)>>
+}
+
+impl RpcTracker
+{
+    /// Create a tracker without any pending calls
+    pub fn new() -> Self
+    {
+        Self
+        {
+            next_call: AtomicU64::new(1),
+            pending: RwLock::new(HashMap::new())
+        }
+    }
+
+    /// Lock the pending calls
+    fn lock(&self) -> RwLockWriteGuard<'_, HashMap<(ConnectionId, String), (u64, PendingCall)>>
+    {
+        match self.pending.write()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Start waiting for the reply to an event, event ids must be unique among the pending calls from the same
+    /// connection, returns the number of the call
+    pub fn start(&self, event_id: &str, call: PendingCall) -> Result<u64, String>
+    {
+        trace!("Waiting for a reply from `{}` to rpc event `{}`", call.destination.address(), event_id);
+
+        let mut pending = self.lock();
+        let key = (call.origin, event_id.to_string());
+
+        if pending.contains_key(&key)
+        {
+            let msg = format!("An rpc event with the event id `{}` is already waiting for a reply", event_id);
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        let number = self.next_call.fetch_add(1, Ordering::Relaxed);
+        pending.insert(key, (number, call));
+
+        Ok(number)
+    }
+
+    /// Finish the call with a reply from a user, which must be the user the event was sent to, the connection the event
+    /// came from is only needed when calls from several connections sent to the user share the event id
+    pub fn finish(&self, event_id: &str, origin: Option<ConnectionId>, replier: &Recipient) -> Result<PendingCall, String>
+    {
+        let mut pending = self.lock();
+
+        let keys: Vec<(ConnectionId, String)> = pending.iter()
+            .filter(|((_, id), (_, call))| id == event_id && &call.destination == replier)
+            .filter(|((connection, _), _)| origin.map(|origin| origin == *connection).unwrap_or(true))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        match keys.as_slice()
+        {
+            [key] => Ok(pending.remove(key).map(|(_, call)| call).unwrap()),
+            [] =>
+            {
+                let msg = format!("No rpc event with the event id `{}` sent to `{}` is waiting for a reply", event_id, replier.address());
+                warn!("{}", msg);
+                Err(msg)
+            },
+            _ =>
+            {
+                let msg = format!("Several rpc events with the event id `{}` are waiting for a reply, the `connection` they came from must be given", event_id);
+                error!("{}", msg);
+                Err(msg)
+            }
+        }
+    }
+
+    /// Stop waiting for the reply to a call, returns the call if it was still waiting
+    pub fn expire(&self, origin: ConnectionId, event_id: &str, number: u64) -> Option<PendingCall>
+    {
+        let mut pending = self.lock();
+        let key = (origin, event_id.to_string());
+
+        match pending.get(&key)
+        {
+            Some((pending_number, _)) if *pending_number == number => pending.remove(&key).map(|(_, call)| call),
+            _ => None
+        }
+    }
+}
+
+impl std::default::Default for RpcTracker
+{
+    fn default() -> RpcTracker
+    {
+        RpcTracker::new()
+    }
+}
@@ -1,13 +1,15 @@
 use chashmap::CHashMap;
 
-use crate::database::{DatabaseInterface, database_from_disk, database_to_disk, UserAuthentication};
+use crate::database::{DatabaseInterface, check_database_name, database_from_disk, database_to_disk, remove_database_from_disk, create_journal, check_entries, Permission, UserAuthentication, SubscriberRegistry, GroupDirectory, ConnectionId};
 use crate::args::Arguments;
 
-use log::error;
+use super::{EventRouter, Recipient, EventQueue, RpcTracker, PendingCall, Delivery, current_time, token_matches, redact_tokens, check_token_hashes, SessionStore, AuthProvider, AuthOutcome, AcronymAuthProvider, GoogleAuthProvider};
 
-use serde_json::Value;
+use log::{error, warn, info};
 
-use std::sync::Arc;
+use serde_json::{Value, json};
+
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 /// Extract an object from a json value, or throw an error
 fn extract_object(val: &Value, title: &str) -> Result<serde_json::Map<String, Value>, String>
@@ -24,6 +26,22 @@ fn extract_object(val: &Value, title: &str) -> Result<serde_json::Map<String, Va
     }
 }
 
+/// Stop clients from creating keys in the config database, unless it gives its own default permissions, as new keys
+/// there (such as `server_permissions` or `groups`) change what users are allowed to do
+fn restrict_config_defaults(config: &DatabaseInterface)
+{
+    let mut defaults = config.default_permissions();
+
+    if defaults.default.is_none()
+    {
+        defaults.default = Some(Permission::none());
+        config.set_default_permissions(defaults);
+    }
+}
+
+/// Name of the file in the database root directory events queued for offline users are kept in
+const EVENT_QUEUE_FILE: &str = "event_queue.json";
+
 /// Server
 #[derive(Debug)]
 pub struct Server
@@ -31,6 +49,27 @@ pub struct Server
     /// Database Interfaces
     pub databases: Arc<CHashMap<String, DatabaseInterface>>,
 
+    /// Update channels for users subscribed to keys
+    pub subscribers: SubscriberRegistry,
+
+    /// Group memberships used by permissions
+    pub groups: GroupDirectory,
+
+    /// Event channels for the connections of authenticated users
+    pub events: EventRouter,
+
+    /// Events held for offline users
+    pub event_queue: EventQueue,
+
+    /// Rpc events waiting for replies
+    pub rpc: RpcTracker,
+
+    /// Sessions which can be resumed by new connections
+    pub sessions: SessionStore,
+
+    /// Authentication schemes, indexed by the domain of the users they authenticate
+    auth_providers: CHashMap<String, Arc<dyn AuthProvider>>,
+
     /// Options
     opt: Arguments,
 
@@ -38,7 +77,10 @@ pub struct Server
     config_database: Arc<DatabaseInterface>,
 
     /// Config Admin
-    config_admin: UserAuthentication
+    config_admin: UserAuthentication,
+
+    /// Held shared by individual commands and exclusively by transactions
+    transactions: RwLock<()>
 }
 
 impl Server
@@ -46,24 +88,284 @@ impl Server
     /// Create a new (empty) Server
     pub fn new(opt: &Arguments) -> Result<Self, String>
     {
-        let config = database_from_disk(&opt.config_path.clone(), "config", opt)?;
+        let mut config = database_from_disk(&opt.config_path.clone(), "config", opt)?;
+        restrict_config_defaults(&config);
+
+        let groups = GroupDirectory::new();
+        groups.set_config(&config.database);
+        config.groups = groups.clone();
+        config.redactions.insert("a_users".to_string(), redact_tokens);
+        config.validations.insert("a_users".to_string(), check_token_hashes);
 
         let mut admin = UserAuthentication::new();
         admin.is_authed = true;
 
-        Ok(Self
+        let server = Self
         {
             databases: Arc::new(CHashMap::new()),
+            subscribers: SubscriberRegistry::new(),
+            groups,
+            events: EventRouter::new(),
+            event_queue: EventQueue::load(opt.path.join(EVENT_QUEUE_FILE), opt.event_queue_user_limit, opt.event_queue_limit),
+            rpc: RpcTracker::new(),
+            sessions: SessionStore::new(opt.session_lifetime)?,
+            auth_providers: CHashMap::new(),
             opt: opt.clone(),
             config_database: Arc::new(config),
-            config_admin: admin
-        })
+            config_admin: admin,
+            transactions: RwLock::new(())
+        };
+
+        server.register_auth_provider(Arc::new(AcronymAuthProvider));
+
+        if let Some(provider) = GoogleAuthProvider::from_arguments(opt).filter(|_| !opt.no_g_auth)
+        {
+            server.register_auth_provider(Arc::new(provider));
+        }
+
+        if !opt.no_autoload
+        {
+            let errors = server.autoload_databases();
+
+            if !errors.is_empty() && !opt.allow_autoload_errors
+            {
+                let msg = format!("Unable to autoload {} database(s), use --allow-autoload-errors to start anyway: {}",
+                                  errors.len(), errors.join(", "));
+                error!("{}", msg);
+                return Err(msg);
+            }
+        }
+
+        Ok(server)
+    }
+
+    /// Register an authentication scheme for the domain it authenticates users into, replacing any previous provider for
+    /// the domain
+    pub fn register_auth_provider(&self, provider: Arc<dyn AuthProvider>)
+    {
+        info!("Registering authentication provider for domain `{}`", provider.domain());
+        self.auth_providers.insert(provider.domain().to_string(), provider);
+    }
+
+    /// Authenticate a user with the provider registered for a domain
+    pub fn authenticate(&self, domain: &str, args: &serde_json::Map<String, Value>) -> Result<AuthOutcome, String>
+    {
+        let provider = match self.auth_providers.get(domain)
+        {
+            Some(provider) => provider.clone(),
+            None =>
+            {
+                let msg = format!("No authentication provider registered for domain `{}`", domain);
+                error!("{}", msg);
+                return Err(msg);
+            }
+        };
+
+        provider.authenticate(self, args)
+    }
+
+    /// Check the credential a user was authenticated with still allows them in, with the provider for their domain
+    pub fn revalidate(&self, user: &UserAuthentication, credential: &Value) -> Result<bool, String>
+    {
+        let provider = match self.auth_providers.get(&user.domain)
+        {
+            Some(provider) => provider.clone(),
+            None =>
+            {
+                warn!("No authentication provider registered for domain `{}`, refusing user `{}`", user.domain, user.name);
+                return Ok(false);
+            }
+        };
+
+        provider.revalidate(self, &user.name, credential)
+    }
+
+    /// Block transactions from running until the guard is dropped
+    pub fn command_guard(&self) -> RwLockReadGuard<'_, ()>
+    {
+        match self.transactions.read()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Block every other command and transaction from running until the guard is dropped
+    pub fn transaction_guard(&self) -> RwLockWriteGuard<'_, ()>
+    {
+        match self.transactions.write()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Get the config database, preferring the copy loaded into the server's databases so changes made through commands
+    /// are picked up
+    fn config(&self) -> DatabaseInterface
+    {
+        match self.databases.get("config")
+        {
+            Some(config) => config.clone(),
+            None => (*self.config_database).clone()
+        }
+    }
+
+    /// Get the root directory a database is stored in, the config database is kept in its own directory
+    fn database_root(&self, name: &str) -> std::path::PathBuf
+    {
+        if name == "config"
+        {
+            self.opt.config_path.clone()
+        }
+        else
+        {
+            self.opt.path.clone()
+        }
+    }
+
+    /// Load every database listed in the `dbs` key of the config database, returning the errors for any which failed
+    pub fn autoload_databases(&self) -> Vec<String>
+    {
+        let names = match self.config().read_from_key("dbs", &self.config_admin)
+        {
+            Ok(Value::Array(names)) => names,
+            Ok(_) =>
+            {
+                let msg = "Database list in the config database is not an array".to_string();
+                error!("{}", msg);
+                return vec![msg];
+            },
+            Err(_) =>
+            {
+                log::warn!("No database list given in the config database, not loading any databases");
+                return vec![];
+            }
+        };
+
+        let mut errors = vec![];
+
+        for name in names
+        {
+            let result = match &name
+            {
+                Value::String(name) if name == "config" =>
+                {
+                    info!("Adding the config database to the loaded databases");
+                    self.insert_database(name, (*self.config_database).clone())
+                },
+                Value::String(name) =>
+                {
+                    info!("Autoloading database `{}`", name);
+                    self.read_database_from_disk(name)
+                },
+                _ => Err(format!("Database name {} in the config database is not a string", name))
+            };
+
+            if let Err(e) = result
+            {
+                let msg = format!("Unable to autoload database {}: {}", name, e);
+                error!("{}", msg);
+                errors.push(msg);
+            }
+        }
+
+        errors
+    }
+
+    /// Verify a user may run a server-level command, according to the `server_permissions` key of the config database,
+    /// a command without an entry there may be run by the users the `"*"` entry allows, and otherwise by nobody
+    pub fn check_server_permission(&self, command: &str, user: &UserAuthentication) -> Result<(), String>
+    {
+        let config = self.config();
+
+        let rules = if config.database.contains_key("server_permissions")
+        {
+            extract_object(&config.database.read("server_permissions")?, "Server permissions")?
+        }
+        else
+        {
+            serde_json::Map::new()
+        };
+
+        let allowed = match rules.get(command).or_else(|| rules.get("*"))
+        {
+            Some(list) => check_entries(list, &format!("Server permissions for `{}`", command), user, &self.groups)?,
+            None => false
+        };
+
+        if !allowed
+        {
+            let msg = format!("User {:?} is not permitted to run `{}`", user, command);
+            warn!("{}", msg);
+            return Err(msg);
+        }
+
+        Ok(())
+    }
+
+    /// Add a database name to the `dbs` key of the config database so it is loaded at startup, and write the config
+    /// database to disk, the user must be permitted to write the `dbs` key
+    pub fn register_database(&self, name: &str, user: &UserAuthentication) -> Result<(), String>
+    {
+        let config = self.config();
+
+        let mut names = match config.database.read("dbs")
+        {
+            Ok(Value::Array(names)) => names,
+            Ok(_) =>
+            {
+                let msg = "Database list in the config database is not an array".to_string();
+                error!("{}", msg);
+                return Err(msg);
+            },
+            Err(_) => vec![]
+        };
+
+        if names.contains(&Value::String(name.to_string()))
+        {
+            return Ok(());
+        }
+
+        info!("Registering database `{}` in the config database", name);
+
+        names.push(Value::String(name.to_string()));
+        config.write_to_key("dbs", Value::Array(names), user)?;
+
+        database_to_disk(&self.opt.config_path, config, &self.opt)
+    }
+
+    /// Remove a database name from the `dbs` key of the config database, and write the config database to disk if it
+    /// was listed, the user must be permitted to write the `dbs` key
+    pub fn unregister_database(&self, name: &str, user: &UserAuthentication) -> Result<(), String>
+    {
+        let config = self.config();
+
+        let mut names = match config.database.read("dbs")
+        {
+            Ok(Value::Array(names)) => names,
+            _ => return Ok(())
+        };
+
+        let length = names.len();
+        names.retain(|v| v != &Value::String(name.to_string()));
+
+        if names.len() == length
+        {
+            return Ok(());
+        }
+
+        info!("Unregistering database `{}` from the config database", name);
+
+        config.write_to_key("dbs", Value::Array(names), user)?;
+
+        database_to_disk(&self.opt.config_path, config, &self.opt)
     }
 
     /// Get the ip address of the Server from the config database
     pub fn config_get_ip(&self) -> Result<String, String>
     {
-        if let Ok(val) = self.config_database.read_from_key("ip", &self.config_admin)
+        if let Ok(val) = self.config().read_from_key("ip", &self.config_admin)
         {
             if let Value::String(ip) = val
             {
@@ -87,7 +389,7 @@ impl Server
     /// Get the port of the Server from the config database
     pub fn config_get_port(&self) -> Result<usize, String>
     {
-        if let Ok(val) = self.config_database.read_from_key("port", &self.config_admin)
+        if let Ok(val) = self.config().read_from_key("port", &self.config_admin)
         {
             if let Value::Number(port) = val
             {
@@ -123,17 +425,92 @@ impl Server
         Ok(self.databases.get(name).ok_or(format!("No database with name, `{}` loaded", name))?.clone())
     }
 
+    /// Add a database to the server, connecting it to the server's subscribers and journal
+    pub fn insert_database(&self, name: &str, mut database: DatabaseInterface) -> Result<(), String>
+    {
+        check_database_name(name)?;
+
+        database.subscribers = self.subscribers.clone();
+        database.groups = self.groups.clone();
+
+        if name == "config"
+        {
+            self.groups.set_config(&database.database);
+            database.redactions.insert("a_users".to_string(), redact_tokens);
+            database.validations.insert("a_users".to_string(), check_token_hashes);
+            restrict_config_defaults(&database);
+        }
+
+        if self.opt.journal && database.journal.is_none()
+        {
+            let journal = create_journal(&self.database_root(name), name)?;
+
+            // Anything already in the journal belongs to the copy on disk, which this database replaces, the default
+            // permissions are recorded along with it so a database which is never written keeps them
+            let mut entry = database.default_permissions().create_json()?;
+            entry.insert("op".to_string(), serde_json::json!("clear"));
+            journal.record(&serde_json::Value::Object(entry))?;
+            database.journal = Some(Arc::new(journal));
+        }
+
+        self.databases.insert(name.to_string(), database);
+
+        Ok(())
+    }
+
     /// Read a database from disk
     pub fn read_database_from_disk(&self, name: &str) -> Result<(), String>
     {
-        self.databases.insert(name.to_string(), database_from_disk(&self.opt.path.clone(), name, &self.opt)?);
+        self.insert_database(name, database_from_disk(&self.database_root(name), name, &self.opt)?)
+    }
+
+    /// Drop a database from the server without writing it to disk, the config database cannot be unloaded as the
+    /// server reads its settings and groups from it
+    pub fn unload_database(&self, name: &str) -> Result<(), String>
+    {
+        check_database_name(name)?;
+
+        if name == "config"
+        {
+            let msg = "The config database cannot be unloaded".to_string();
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        if self.databases.remove(name).is_none()
+        {
+            let msg = format!("No database with key `{}` initialized", name);
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        info!("Unloaded database `{}`", name);
 
         Ok(())
     }
 
+    /// Drop a database from the server and remove it from disk and from the config database
+    pub fn delete_database(&self, name: &str, user: &UserAuthentication) -> Result<(), String>
+    {
+        check_database_name(name)?;
+
+        if name == "config"
+        {
+            let msg = "The config database cannot be deleted".to_string();
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        self.unload_database(name)?;
+        remove_database_from_disk(&self.database_root(name), name)?;
+        self.unregister_database(name, user)
+    }
+
     /// Write a database to disk
     pub fn write_database_to_disk(&self, name: &str) -> Result<(), String>
     {
+        check_database_name(name)?;
+
         if !self.databases.contains_key(name)
         {
             let msg = format!("No database with key `{}` initialized", name);
@@ -141,11 +518,49 @@ impl Server
             return Err(msg);
         }
 
-        database_to_disk(&self.opt.path.clone(), self.get_database_by_name(name)?, &self.opt)?;
+        database_to_disk(&self.database_root(name), self.get_database_by_name(name)?, &self.opt)?;
 
         Ok(())
     }
 
+    /// Write every database with unsaved changes to disk, returning the names of the databases written
+    pub fn write_dirty_databases(&self) -> Result<Vec<String>, String>
+    {
+        let _guard = self.command_guard();
+
+        let mut written = vec![];
+        let mut failed = vec![];
+
+        for name in self.get_dbkeys()?
+        {
+            let is_dirty = match self.databases.get(&name)
+            {
+                Some(database) => database.database.is_dirty(),
+                None => false
+            };
+
+            if !is_dirty
+            {
+                continue;
+            }
+
+            match self.write_database_to_disk(&name)
+            {
+                Ok(()) => written.push(name),
+                Err(e) => failed.push(format!("`{}` ({})", name, e))
+            }
+        }
+
+        if !failed.is_empty()
+        {
+            let msg = format!("Unable to write databases {}", failed.join(", "));
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        Ok(written)
+    }
+
     /// Get the array of keys in the given database
     pub fn get_keys(&self, name: &str) -> Result<Vec<String>, String>
     {
@@ -173,46 +588,387 @@ impl Server
         Ok(keys)
     }
 
-    /// Check acronym authentication
-    pub fn check_a_auth(&self, id: &str, token: &str) -> Result<(bool, String), String>
+    /// Get the users an event destination addresses, which is either a user address, a `{"group": "NAME"}` object for
+    /// the members of a group, or a list of either
+    fn destination_users(&self, destination: &Value) -> Result<Vec<Recipient>, String>
+    {
+        match destination
+        {
+            Value::String(address) => Ok(vec![Recipient::parse(address)]),
+            Value::Object(map) => match map.get("group")
+            {
+                Some(Value::String(group)) => Ok(self.groups.member_entries(group).iter()
+                                                    .filter_map(|(domain, name)| Recipient::from_entry(domain, name)).collect()),
+                _ =>
+                {
+                    let msg = format!("Event destination {} does not name a group", destination);
+                    error!("{}", msg);
+                    Err(msg)
+                }
+            },
+            Value::Array(entries) if entries.iter().all(|entry| !entry.is_array()) =>
+            {
+                let mut names = vec![];
+
+                for entry in entries
+                {
+                    names.extend(self.destination_users(entry)?);
+                }
+
+                Ok(names)
+            },
+            _ =>
+            {
+                let msg = format!("Event destination {} is not a user name, group or list of them", destination);
+                error!("{}", msg);
+                Err(msg)
+            }
+        }
+    }
+
+    /// Split users into those a user is permitted to send events to and those they are not, by the `event_permissions`
+    /// item of the config database, which gives the permissions for events to each user, or to every user not listed
+    /// under `"*"`, of which the `write` entries may send events, returns the permitted users and the addresses of the
+    /// others
+    fn permitted_recipients(&self, sender: &UserAuthentication, users: Vec<Recipient>) -> Result<(Vec<Recipient>, Vec<String>), String>
+    {
+        let config = self.config();
+
+        if !config.database.contains_key("event_permissions")
+        {
+            return Ok((users, vec![]));
+        }
+
+        let rules = extract_object(&config.database.read("event_permissions")?, "Event permissions")?;
+        let mut permitted = vec![];
+        let mut denied = vec![];
+
+        for user in users
+        {
+            let address = user.address();
+
+            let allowed = match rules.get(&address).or_else(|| rules.get("*"))
+            {
+                Some(rule) => Permission::new(rule, &format!("events to `{}`", address))?.check_user_write(sender, &self.groups)?,
+                None => true
+            };
+
+            if allowed
+            {
+                permitted.push(user);
+            }
+            else
+            {
+                warn!("User {:?} is not permitted to send events to `{}`", sender, address);
+                denied.push(address);
+            }
+        }
+
+        Ok((permitted, denied))
+    }
+
+    /// Relay an event from a user to every connection of the users its destination addresses which the user is
+    /// permitted to send events to, or to every such connection except the one it came from if the destination is `"*"`
+    pub fn send_event(&self, destination: &Value, origin: ConnectionId, sender: &UserAuthentication, event: &Value) -> Result<Delivery, String>
     {
-        let user_data = self.config_database.read_from_key("a_users", &self.config_admin)?;
+        if destination == "*"
+        {
+            let (permitted, denied) = self.permitted_recipients(sender, self.events.users())?;
+
+            let mut delivery = self.events.broadcast(origin, &permitted, event);
+            delivery.denied = denied;
+
+            return Ok(delivery);
+        }
+
+        let (permitted, denied) = self.permitted_recipients(sender, self.destination_users(destination)?)?;
+
+        let mut delivery = self.events.send_to_users(&permitted, event);
+        delivery.denied = denied;
+
+        Ok(delivery)
+    }
+
+    /// Relay the event command sent by a connection, queueing it for offline users or waiting for a reply to it as the
+    /// command asks, returns the recipients and, for an rpc event, the number of the call and the seconds to wait for
+    /// its reply
+    ///
+    /// The `origin` of the event is replaced with the name of the user who sent it, and its domain is added as
+    /// `origin_domain`, so events can only be sent by authenticated users and never on behalf of another user.
+    pub fn relay_event(&self, origin: ConnectionId, sender: &UserAuthentication, args: &serde_json::Map<String, Value>) -> Result<(Delivery, Option<(u64, u64)>), String>
+    {
+        if !sender.is_authed
+        {
+            let msg = "Cannot send events, user is not yet authenticated".to_string();
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        let destination = match args.get("destination")
+        {
+            Some(destination) => destination,
+            None =>
+            {
+                let msg = "Event command does not include destination".to_string();
+                error!("{}", msg);
+                return Err(msg);
+            }
+        };
+
+        // Tell the destination who sent the event and from which connection, so it can reply to that connection alone
+        let mut event = Value::Object(args.clone());
+        event["origin"] = json!(sender.name);
+        event["origin_domain"] = json!(sender.domain);
+        event["origin_connection"] = json!(origin);
+
+        let rpc = match args.get("rpc")
+        {
+            Some(Value::Bool(true)) => match (destination.as_str(), args.get("event_id").and_then(Value::as_str))
+            {
+                (Some(address), Some(event_id)) if address != "*" =>
+                {
+                    let timeout = args.get("timeout").and_then(Value::as_u64).unwrap_or(self.opt.rpc_timeout).min(self.opt.rpc_max_timeout);
+                    let call = PendingCall{origin, origin_user: Recipient::from_user(sender), destination: Recipient::parse(address)};
+                    Some((event_id, self.rpc.start(event_id, call)?, timeout))
+                },
+                _ =>
+                {
+                    let msg = "Rpc events require a string `event_id` and a single user as the destination".to_string();
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+            },
+            _ => None
+        };
+
+        let delivery = match args.get("connection")
+        {
+            Some(connection) => match (destination.as_str(), connection.as_u64())
+            {
+                (Some(address), Some(connection)) =>
+                {
+                    let user = Recipient::parse(address);
+                    let target = format!("{} (connection {})", user.address(), connection);
+
+                    if self.permitted_recipients(sender, vec![user.clone()])?.0.is_empty()
+                    {
+                        Ok(Delivery{denied: vec![user.address()], ..Default::default()})
+                    }
+                    else if self.events.send_to_connection(&user, connection, &event)
+                    {
+                        Ok(Delivery{delivered: vec![target], ..Default::default()})
+                    }
+                    else
+                    {
+                        Ok(Delivery{offline: vec![target], ..Default::default()})
+                    }
+                },
+                _ => Err("Events can only be sent to a connection id of a single user".to_string())
+            },
+            None => self.send_event(destination, origin, sender, &event).map(|mut delivery|
+            {
+                // Hold the event for offline users if asked to, rpc events are never held as their reply would time out
+                if let (Some(Value::Bool(true)), None) = (args.get("queue"), &rpc)
+                {
+                    self.queue_event(&mut delivery, &event, args.get("ttl").and_then(Value::as_u64));
+                }
+
+                delivery
+            })
+        };
+
+        // An event for a single user which could not be delivered is an error, as it always has been
+        let delivery = match delivery
+        {
+            Ok(delivery) if !delivery.denied.is_empty() && destination.is_string() && destination != "*" =>
+            {
+                let msg = format!("User `{}` is not permitted to send events to `{}`", sender.name, delivery.denied.join(", "));
+                Err(msg)
+            },
+            Ok(delivery) if delivery.delivered.is_empty() && delivery.queued.is_empty() && destination.is_string() && destination != "*" =>
+            {
+                let msg = format!("Unable to connect to user `{}`", delivery.offline.join(", "));
+                warn!("Attempted to forward event to `{}`, however, this user is not connected", delivery.offline.join(", "));
+                Err(msg)
+            },
+            other => other
+        };
+
+        match (delivery, rpc)
+        {
+            (Ok(delivery), rpc) => Ok((delivery, rpc.map(|(_, number, timeout)| (number, timeout)))),
+            (Err(msg), Some((event_id, number, _))) =>
+            {
+                self.rpc.expire(origin, event_id, number);
+                Err(msg)
+            },
+            (Err(msg), None) => Err(msg)
+        }
+    }
+
+    /// Send the reply to an rpc event from the user it was sent to back to the connection which sent the event, which
+    /// is given when calls from several connections share the event id
+    pub fn reply_rpc(&self, replier: &Recipient, event_id: &str, origin: Option<ConnectionId>, reply: &Value) -> Result<(), String>
+    {
+        let call = self.rpc.finish(event_id, origin, replier)?;
+
+        if !self.events.send_to_connection(&call.origin_user, call.origin, reply)
+        {
+            let msg = format!("Unable to send reply to rpc event `{}`, the connection which sent it has closed", event_id);
+            warn!("{}", msg);
+            return Err(msg);
+        }
+
+        Ok(())
+    }
+
+    /// Stop waiting for the reply to an rpc event once it has timed out, sending the connection which sent it an error
+    pub fn expire_rpc(&self, origin: ConnectionId, event_id: &str, number: u64)
+    {
+        if let Some(call) = self.rpc.expire(origin, event_id, number)
+        {
+            let msg = format!("No reply to rpc event `{}` from `{}` before the timeout", event_id, call.destination.address());
+            warn!("{}", msg);
+
+            self.events.send_to_connection(&call.origin_user, call.origin,
+                &json!({"cmd": "event_reply", "mode": "error", "msg": msg, "event_id": event_id, "origin": call.destination.address()}));
+        }
+    }
+
+    /// Queue an event for each offline user it was addressed to, to be sent when they next authenticate, events are held
+    /// for the given number of seconds or the `--event-queue-ttl`, up to the `--event-queue-max-ttl`, users the event
+    /// could not be queued for are left out of the queued users
+    pub fn queue_event(&self, delivery: &mut Delivery, event: &Value, ttl: Option<u64>)
+    {
+        let ttl = ttl.unwrap_or(self.opt.event_queue_ttl).min(self.opt.event_queue_max_ttl);
+
+        for address in &delivery.offline
+        {
+            if self.event_queue.push(address, event, ttl).is_ok()
+            {
+                delivery.queued.push(address.clone());
+            }
+        }
+    }
+
+    /// Get the token entries of an a_user, or `None` if the user is not listed in the config database
+    fn a_user_tokens(&self, id: &str) -> Result<Option<Vec<Value>>, String>
+    {
+        // Read the key directly, since reads through the interface have the token hashes redacted
+        let user_data = self.config().database.read("a_users")?;
         let user_map = extract_object(&user_data, "General user data")?;
 
         if !user_map.contains_key(id)
         {
-            let msg = format!("Failed, a_user not found");
-            error!("{}", msg);
-            return Ok((false, msg));
+            return Ok(None);
         }
 
         let id_map = extract_object(user_map.get(id).ok_or(format!("No user with id, `{}` loaded", id))?, "Specific user data")?;
 
         if !id_map.contains_key("tokens")
         {
-            let msg = format!("Specific user data does not contain `tokens` key");
+            let msg = "Specific user data does not contain `tokens` key".to_string();
             error!("{}", msg);
             return Err(msg);
         }
 
-        let allowable_tokens = if let Value::Array(tokens) = id_map.get("tokens").ok_or(format!("ID map does not contain a tokens field"))?
+        if let Value::Array(tokens) = id_map.get("tokens").ok_or("ID map does not contain a tokens field".to_string())?
         {
-            tokens
+            Ok(Some(tokens.clone()))
         }
         else
         {
-            let msg = format!("Tokens for specific user data is not an array");
+            let msg = "Tokens for specific user data is not an array".to_string();
             error!("{}", msg);
-            return Err(msg);
+            Err(msg)
+        }
+    }
+
+    /// Check acronym authentication, returning the token entry which matched
+    pub fn check_a_auth(&self, id: &str, token: &str) -> Result<(Option<Value>, String), String>
+    {
+        let allowable_tokens = match self.a_user_tokens(id)?
+        {
+            Some(tokens) => tokens,
+            None =>
+            {
+                let msg = "Failed, a_user not found".to_string();
+                error!("{}", msg);
+                return Ok((None, msg));
+            }
         };
 
-        if !allowable_tokens.contains(&Value::String(token.to_string()))
+        let now = current_time();
+
+        match allowable_tokens.into_iter().find(|entry| token_matches(entry, token, now))
         {
-            let msg = format!("Failed, token incorrect");
-            error!("{}", msg);
-            return Ok((false, msg));
+            Some(entry) => Ok((Some(entry), "success".to_string())),
+            None =>
+            {
+                let msg = "Failed, token incorrect".to_string();
+                error!("{}", msg);
+                Ok((None, msg))
+            }
+        }
+    }
+
+    /// Check a token entry an a_user authenticated with is still listed for them and has not expired
+    pub fn check_a_token(&self, id: &str, entry: &Value) -> Result<bool, String>
+    {
+        let listed = match self.a_user_tokens(id)?
+        {
+            Some(tokens) => tokens.contains(entry),
+            None => false
+        };
+
+        let expired = match entry.get("expires").map(Value::as_u64)
+        {
+            Some(Some(expires)) => expires <= current_time(),
+            Some(None) => true,
+            None => false
+        };
+
+        Ok(listed && !expired)
+    }
+
+    /// Check a Google account against the allow-list in the config database, which accepts the emails listed in the
+    /// `g_users` key and any account of a hosted domain listed in the `g_domains` key
+    pub fn check_g_users(&self, email: &str, hosted_domain: Option<&str>) -> Result<(bool, String), String>
+    {
+        let config = self.config();
+
+        if config.database.contains_key("g_users")
+        {
+            let user_map = extract_object(&config.database.read("g_users")?, "General g_user data")?;
+
+            if user_map.keys().any(|allowed| allowed.eq_ignore_ascii_case(email))
+            {
+                return Ok((true, "success".to_string()));
+            }
+        }
+
+        if let (Some(hosted_domain), true) = (hosted_domain, config.database.contains_key("g_domains"))
+        {
+            match config.database.read("g_domains")?
+            {
+                Value::Array(domains) =>
+                {
+                    if domains.iter().any(|domain| domain.as_str().map(|domain| domain.eq_ignore_ascii_case(hosted_domain)) == Some(true))
+                    {
+                        return Ok((true, "success".to_string()));
+                    }
+                },
+                _ =>
+                {
+                    let msg = "The `g_domains` key in the config database is not an array".to_string();
+                    error!("{}", msg);
+                    return Err(msg);
+                }
+            }
         }
 
-        Ok((true, "success".to_string()))
+        let msg = "Failed, g_user not allowed".to_string();
+        warn!("{} ({})", msg, email);
+        Ok((false, msg))
     }
-}
\ No newline at end of file
+}
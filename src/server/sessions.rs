@@ -0,0 +1,144 @@
+use crate::database::UserAuthentication;
+
+use super::current_time;
+
+use chashmap::CHashMap;
+use ring::rand::SecureRandom;
+use serde_json::{Value, json};
+use log::{error, warn, trace};
+
+/// Number of random bytes in the key session tokens are signed with
+const KEY_LENGTH: usize = 32;
+
+/// Number of random bytes in a session id
+const SESSION_ID_LENGTH: usize = 16;
+
+/// Sessions started by authenticating, each identified by a signed token which restores the user on a new connection
+#[derive(Debug)]
+pub struct SessionStore
+{
+    key: ring::hmac::SigningKey,
+    lifetime: u64,
+    active: CHashMap<String, (u64, Value)>
+}
+
+impl SessionStore
+{
+    /// Create an empty store with a fresh signing key, sessions last for the given number of seconds
+    pub fn new(lifetime: u64) -> Result<Self, String>
+    {
+        let mut key = [0u8; KEY_LENGTH];
+
+        if ring::rand::SystemRandom::new().fill(&mut key).is_err()
+        {
+            let msg = "Unable to generate a session signing key".to_string();
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        Ok(Self
+        {
+            key: ring::hmac::SigningKey::new(&ring::digest::SHA256, &key),
+            lifetime,
+            active: CHashMap::new()
+        })
+    }
+
+    /// Start a session for an authenticated user, keeping the credential they were accepted with, returns the session id
+    /// and the token for it
+    pub fn start(&self, user: &UserAuthentication, credential: &Value) -> Result<(String, String), String>
+    {
+        let now = current_time();
+        self.active.retain(|_, (expires, _)| *expires > now);
+
+        let mut id = [0u8; SESSION_ID_LENGTH];
+
+        if ring::rand::SystemRandom::new().fill(&mut id).is_err()
+        {
+            let msg = "Unable to generate a session id".to_string();
+            error!("{}", msg);
+            return Err(msg);
+        }
+
+        let id = base64::encode_config(id, base64::URL_SAFE_NO_PAD);
+        let expires = now + self.lifetime;
+
+        let payload = json!({"id": id, "domain": user.domain, "name": user.name, "expires": expires}).to_string();
+        let signature = ring::hmac::sign(&self.key, payload.as_bytes());
+
+        self.active.insert(id.clone(), (expires, credential.clone()));
+
+        trace!("Started session for `{}`", user.name);
+
+        Ok((id, format!("{}.{}", base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+                                 base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD))))
+    }
+
+    /// Check a session token, returns the session id, the user the session was started for and the credential they were
+    /// accepted with
+    pub fn resume(&self, token: &str) -> Result<(String, UserAuthentication, Value), String>
+    {
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let (payload, signature) = match (parts.len(), parts.first(), parts.last())
+        {
+            (2, Some(payload), Some(signature)) =>
+                match (base64::decode_config(payload, base64::URL_SAFE_NO_PAD), base64::decode_config(signature, base64::URL_SAFE_NO_PAD))
+                {
+                    (Ok(payload), Ok(signature)) => (payload, signature),
+                    _ => return Err(invalid_session("session token is not valid base64"))
+                },
+            _ => return Err(invalid_session("session token is not in two parts"))
+        };
+
+        if ring::hmac::verify_with_own_key(&self.key, &payload, &signature).is_err()
+        {
+            return Err(invalid_session("signature does not match"));
+        }
+
+        let payload: Value = match serde_json::from_slice(&payload)
+        {
+            Ok(payload) => payload,
+            Err(_) => return Err(invalid_session("session data is not json"))
+        };
+
+        let (id, domain, name, expires) = match (payload["id"].as_str(), payload["domain"].as_str(), payload["name"].as_str(), payload["expires"].as_u64())
+        {
+            (Some(id), Some(domain), Some(name), Some(expires)) => (id, domain, name, expires),
+            _ => return Err(invalid_session("session data is incomplete"))
+        };
+
+        if expires <= current_time()
+        {
+            return Err(invalid_session("session has expired"));
+        }
+
+        let credential = match self.active.get(id)
+        {
+            Some(session) => session.1.clone(),
+            None => return Err(invalid_session("session has ended"))
+        };
+
+        let mut user = UserAuthentication::new();
+        user.is_authed = true;
+        user.domain = domain.to_string();
+        user.name = name.to_string();
+
+        Ok((id.to_string(), user, credential))
+    }
+
+    /// End a session, its token can no longer be used to resume it
+    pub fn end(&self, id: &str)
+    {
+        trace!("Ending session `{}`", id);
+        self.active.remove(id);
+    }
+}
+
+/// Log and produce the error for a session token which cannot be resumed
+fn invalid_session(reason: &str) -> String
+{
+    let msg = format!("Unable to resume session, {}", reason);
+    warn!("{}", msg);
+    msg
+}
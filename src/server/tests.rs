@@ -6,17 +6,32 @@ use crate::args;
 
 use structopt::StructOpt;
 
-use serde_json::json;
+use serde_json::{json, Value};
+
+/// Let the given users run every server command and edit the config database, as an operator changing the config
+/// files would
+fn make_operators(server: &server::Server, users: Value)
+{
+    let config = server.get_database_by_name("config").unwrap();
+    let permission = crate::database::Permission::new(&json!({"read": [["a_user", "any"]], "write": users}), "operators").unwrap();
+
+    config.database.write("server_permissions", json!({"*": users})).unwrap();
+    config.permissions.insert("server_permissions".to_string(), permission.clone());
+    config.set_default_permissions(crate::database::DefaultPermissions{default: Some(permission), prefixes: vec![]});
+}
 
 #[test]
 pub fn test_read_write_disk()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "command"})).unwrap()),
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "command"}))));
@@ -35,11 +50,14 @@ pub fn test_read_write_disk()
 pub fn test_list_keys()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "command"})).unwrap()),
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "command"}))));
@@ -55,11 +73,15 @@ pub fn test_list_keys()
 pub fn test_list_databases()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
+    opt.no_autoload = true;
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "list_databases"})).unwrap()),
                 Ok(Some(json!({"cmd": "list_databases", "mode": "ok", "msg": "", "val": []}))));
@@ -81,11 +103,14 @@ pub fn test_list_databases()
 pub fn test_get_value()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "command"})).unwrap()),
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "command"}))));
@@ -104,11 +129,14 @@ pub fn test_get_value()
 pub fn test_set_value()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "command"})).unwrap()),
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "command"}))));
@@ -142,11 +170,14 @@ pub fn test_set_value()
 pub fn test_get_index()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "test"})).unwrap()), 
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "test"}))));
@@ -177,11 +208,14 @@ pub fn test_get_index()
 pub fn test_set_index()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "test"})).unwrap()), 
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "test"}))));
@@ -211,11 +245,14 @@ pub fn test_set_index()
 pub fn test_append()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "test"})).unwrap()), 
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "test"}))));
@@ -233,11 +270,14 @@ pub fn test_append()
 pub fn test_get_length()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "test"})).unwrap()), 
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "test"}))));
@@ -255,11 +295,14 @@ pub fn test_get_length()
 pub fn test_get_recent()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "test"})).unwrap()), 
                 Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "test"}))));
@@ -277,11 +320,14 @@ pub fn test_get_recent()
 pub fn test_create_database()
 {
     let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
     opt.path = std::path::PathBuf::from("test-databases");
 
     let server = server::Server::new(&opt).unwrap();
     let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
     conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
 
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()),
                 Ok(Some(json!({"cmd": "create_database", "mode": "ok", "msg": "", "db_key": "database0"}))));
@@ -289,4 +335,1506 @@ pub fn test_create_database()
                 Ok(Some(json!({"cmd": "create_database", "mode": "ok", "msg": "", "db_key": "database1"}))));
     assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database2", "unique_id": 512})).unwrap()),
                 Ok(Some(json!({"cmd": "create_database", "mode": "ok", "msg": "", "db_key": "database2", "unique_id": 512}))));
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn test_subscribe()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = server::Server::new(&opt).unwrap();
+    let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    conn.attach_updates(tx);
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()),
+                Ok(Some(json!({"cmd": "create_database", "mode": "ok", "msg": "", "db_key": "database0"}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 0})).unwrap()),
+                Ok(Some(json!({"cmd": "set_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "key"}))));
+    assert!(rx.try_recv().is_err());
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "subscribe", "db_key": "database0", "key": "key", "unique_id": 7})).unwrap()),
+                Ok(Some(json!({"cmd": "subscribe", "mode": "ok", "msg": "", "db_key": "database0", "key": "key", "unique_id": 7}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 1})).unwrap()),
+                Ok(Some(json!({"cmd": "set_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "key"}))));
+    assert_eq!(rx.try_recv(), Ok(json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": "database0", "key": "key", "val": 1})));
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "unsubscribe", "db_key": "database0", "key": "key"})).unwrap()),
+                Ok(Some(json!({"cmd": "unsubscribe", "mode": "ok", "msg": "", "db_key": "database0", "key": "key"}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 2})).unwrap()),
+                Ok(Some(json!({"cmd": "set_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "key"}))));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+pub fn test_subscription_connections()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+
+    make_operators(&server, json!([["a_user", "owner"]]));
+    let mut owner = server::ServerInterface::new(&server);
+    owner.fake_auth();
+    owner.user_profile.name = "owner".to_string();
+    owner.user_profile.domain = "a_auth".to_string();
+
+    owner.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    owner.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 0})).unwrap()).unwrap();
+    owner.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "database0", "key": "key",
+                                                              "val": {"read": [["a_user", "term.jordan"], ["g_user", "authed"]], "write": []}})).unwrap()).unwrap();
+
+    // Two connections of the same user, and a user with the same name in another domain
+    let mut receivers = vec![];
+    let mut conns = vec![];
+
+    for domain in &["a_auth", "a_auth", "g_auth"]
+    {
+        let mut conn = server::ServerInterface::new(&server);
+        conn.fake_auth();
+        conn.user_profile.name = "term.jordan".to_string();
+        conn.user_profile.domain = domain.to_string();
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        conn.attach_updates(tx);
+
+        conns.push(conn);
+        receivers.push(rx);
+    }
+
+    conns[0].execute_command(commands::Command::from_json(json!({"cmd": "subscribe", "db_key": "database0", "key": "key"})).unwrap()).unwrap();
+
+    let update = |val| json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": "database0", "key": "key", "val": val});
+
+    // The user of the other domain is not subscribed, even though it has the same name and can read the key
+    owner.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 1})).unwrap()).unwrap();
+    assert_eq!(receivers[0].try_recv(), Ok(update(1)));
+    assert_eq!(receivers[1].try_recv(), Ok(update(1)));
+    assert!(receivers[2].try_recv().is_err());
+
+    conns[2].execute_command(commands::Command::from_json(json!({"cmd": "subscribe", "db_key": "database0", "key": "key"})).unwrap()).unwrap();
+
+    // Logging out one connection leaves the other connections subscribed
+    conns[1].execute_command(commands::Command::from_json(json!({"cmd": "logout"})).unwrap()).unwrap();
+
+    owner.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 2})).unwrap()).unwrap();
+    assert_eq!(receivers[0].try_recv(), Ok(update(2)));
+    assert!(receivers[1].try_recv().is_err());
+    assert_eq!(receivers[2].try_recv(), Ok(update(2)));
+
+    // Updates stop as soon as a connection can no longer read the key
+    owner.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "database0", "key": "key",
+                                                              "val": {"read": [["a_user", "term.jordan"]], "write": []}})).unwrap()).unwrap();
+    owner.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 3})).unwrap()).unwrap();
+    assert_eq!(receivers[0].try_recv(), Ok(update(3)));
+    assert!(receivers[2].try_recv().is_err());
+
+    owner.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "database0", "key": "key",
+                                                              "val": {"read": [], "write": []}})).unwrap()).unwrap();
+    owner.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 4})).unwrap()).unwrap();
+    assert!(receivers[0].try_recv().is_err());
+}
+
+#[test]
+pub fn test_transaction()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = server::Server::new(&opt).unwrap();
+    let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    conn.attach_updates(tx);
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "list", "val": [0]})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "subscribe", "db_key": "database0", "key": "list"})).unwrap()).unwrap();
+
+    // Every command sees the changes made earlier in the transaction, subscribers only see the final value
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "transaction", "unique_id": 3, "cmds": [
+                    {"cmd": "append_list", "db_key": "database0", "key": "list", "val": 1},
+                    {"cmd": "set_index", "db_key": "database0", "key": "list", "index": 0, "val": 2},
+                    {"cmd": "set_value", "db_key": "database0", "key": "new", "val": "a"},
+                    {"cmd": "get_value", "db_key": "database0", "key": "new", "unique_id": 4}]})).unwrap()),
+                Ok(Some(json!({"cmd": "transaction", "mode": "ok", "msg": "", "unique_id": 3, "val": [
+                    {"cmd": "append_list", "mode": "ok", "msg": "", "db_key": "database0", "key": "list", "next": 1},
+                    {"cmd": "set_index", "mode": "ok", "msg": "", "db_key": "database0", "key": "list", "index": 0},
+                    {"cmd": "set_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "new"},
+                    {"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "new", "val": "a", "unique_id": 4}]}))));
+    assert_eq!(rx.try_recv(), Ok(json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": "database0", "key": "list", "val": [2, 1]})));
+    assert!(rx.try_recv().is_err());
+
+    // A failure part way through leaves every key untouched
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "transaction", "cmds": [
+                    {"cmd": "set_value", "db_key": "database0", "key": "new", "val": "b"},
+                    {"cmd": "append_list", "db_key": "database0", "key": "list", "val": 3},
+                    {"cmd": "set_index", "db_key": "database0", "key": "new", "index": 0, "val": 4}]})).unwrap()).is_err());
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "list"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "list", "val": [2, 1]}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "new"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "new", "val": "a"}))));
+    assert!(rx.try_recv().is_err());
+
+    // Permissions are checked before anything runs, and only key commands are allowed
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "transaction", "cmds": [
+                    {"cmd": "set_value", "db_key": "database0", "key": "new", "val": "c"},
+                    {"cmd": "set_index", "db_key": "database0", "key": "missing", "index": 0, "val": 0}]})).unwrap()).is_err());
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "transaction", "cmds": [
+                    {"cmd": "set_value", "db_key": "database0", "key": "new", "val": "c"},
+                    {"cmd": "write_to_disk", "db_key": "database0"}]})).unwrap()).is_err());
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "new"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "new", "val": "a"}))));
+}
+
+#[test]
+pub fn test_transaction_rollback()
+{
+    let root = std::env::temp_dir().join("aci-test-transaction-rollback");
+    let _ = std::fs::remove_dir_all(&root);
+
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = root.clone();
+    opt.journal = true;
+
+    let server = server::Server::new(&opt).unwrap();
+    let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    conn.attach_updates(tx);
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database1"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 0})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "subscribe", "db_key": "database0", "key": "key"})).unwrap()).unwrap();
+
+    // Block the journal of the second database, so the transaction can only be committed to the first
+    let journal = root.join("database1").join("database1.journal");
+    std::fs::remove_file(&journal).unwrap();
+    std::fs::create_dir(&journal).unwrap();
+
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "transaction", "cmds": [
+                    {"cmd": "set_value", "db_key": "database0", "key": "key", "val": 1},
+                    {"cmd": "set_value", "db_key": "database0", "key": "new", "val": 1},
+                    {"cmd": "set_value", "db_key": "database1", "key": "key", "val": 1}]})).unwrap()).is_err());
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "key"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "key", "val": 0}))));
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "new"})).unwrap()).is_err());
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database1", "key": "key"})).unwrap()).is_err());
+    assert!(rx.try_recv().is_err());
+
+    // The reverted commit is also reverted when the journal is replayed
+    let database = crate::database::database_from_disk(&root, "database0", &opt).unwrap();
+    assert_eq!(database.database.read("key"), Ok(json!(0)));
+    assert!(database.database.read("new").is_err());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+pub fn test_atomic_operations()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = server::Server::new(&opt).unwrap();
+    let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "state", "val": "open"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "count", "val": 0})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "flag", "val": true})).unwrap()).unwrap();
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "compare_and_set", "db_key": "database0", "key": "state", "expected": "closed", "val": "open"})).unwrap()),
+                Ok(Some(json!({"cmd": "compare_and_set", "mode": "ok", "msg": "", "db_key": "database0", "key": "state", "success": false, "val": "open"}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "compare_and_set", "db_key": "database0", "key": "state", "expected": "open", "val": "closed"})).unwrap()),
+                Ok(Some(json!({"cmd": "compare_and_set", "mode": "ok", "msg": "", "db_key": "database0", "key": "state", "success": true, "val": "closed"}))));
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "increment", "db_key": "database0", "key": "count"})).unwrap()),
+                Ok(Some(json!({"cmd": "increment", "mode": "ok", "msg": "", "db_key": "database0", "key": "count", "val": 1}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "decrement", "db_key": "database0", "key": "count", "by": 5})).unwrap()),
+                Ok(Some(json!({"cmd": "decrement", "mode": "ok", "msg": "", "db_key": "database0", "key": "count", "val": -4}))));
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "increment", "db_key": "database0", "key": "flag"})).unwrap()).is_err());
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "toggle", "db_key": "database0", "key": "flag"})).unwrap()),
+                Ok(Some(json!({"cmd": "toggle", "mode": "ok", "msg": "", "db_key": "database0", "key": "flag", "val": false}))));
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "toggle", "db_key": "database0", "key": "missing"})).unwrap()).is_err());
+}
+
+#[test]
+pub fn test_paths()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = server::Server::new(&opt).unwrap();
+    let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    conn.attach_updates(tx);
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "users", "val": {"term.jordan": {"tokens": ["a"]}}})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "subscribe", "db_key": "database0", "key": "users"})).unwrap()).unwrap();
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_path", "db_key": "database0", "key": "users", "path": "/term.jordan/tokens/-", "val": "b"})).unwrap()),
+                Ok(Some(json!({"cmd": "set_path", "mode": "ok", "msg": "", "db_key": "database0", "key": "users", "path": "/term.jordan/tokens/-"}))));
+    assert_eq!(rx.try_recv(), Ok(json!({"cmd": "subscription", "mode": "update", "op": "set_path", "db_key": "database0", "key": "users", "path": "/term.jordan/tokens/-", "val": "b"})));
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_path", "db_key": "database0", "key": "users", "path": "/term.jordan/tokens/1"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_path", "mode": "ok", "msg": "", "db_key": "database0", "key": "users", "path": "/term.jordan/tokens/1", "val": "b"}))));
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "delete_path", "db_key": "database0", "key": "users", "path": "/term.jordan/tokens/0"})).unwrap()),
+                Ok(Some(json!({"cmd": "delete_path", "mode": "ok", "msg": "", "db_key": "database0", "key": "users", "path": "/term.jordan/tokens/0", "val": "a"}))));
+    assert_eq!(rx.try_recv(), Ok(json!({"cmd": "subscription", "mode": "update", "op": "delete_path", "db_key": "database0", "key": "users", "path": "/term.jordan/tokens/0"})));
+
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_path", "db_key": "database0", "key": "users", "path": "/term.carter"})).unwrap()).is_err());
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_path", "db_key": "database0", "key": "missing", "path": "/a", "val": 0})).unwrap()).is_err());
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "users"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "users", "val": {"term.jordan": {"tokens": ["b"]}}}))));
+}
+
+#[test]
+pub fn test_patch_value()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = server::Server::new(&opt).unwrap();
+    let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": {"a": 0, "b": [1]}})).unwrap()).unwrap();
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "patch_value", "db_key": "database0", "key": "key", "val": {"a": null, "c": 2}})).unwrap()),
+                Ok(Some(json!({"cmd": "patch_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "key"}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "patch_value", "db_key": "database0", "key": "key", "val": [{"op": "add", "path": "/b/-", "value": 3}]})).unwrap()),
+                Ok(Some(json!({"cmd": "patch_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "key"}))));
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "patch_value", "db_key": "database0", "key": "key", "val": [
+                    {"op": "add", "path": "/d", "value": 4},
+                    {"op": "test", "path": "/c", "value": 0}]})).unwrap()).is_err());
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "key"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "key", "val": {"b": [1, 3], "c": 2}}))));
+}
+
+#[test]
+pub fn test_permissions()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = server::Server::new(&opt).unwrap();
+
+    make_operators(&server, json!([["a_user", "creator"]]));
+    let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
+    conn.fake_auth();
+    conn.user_profile.name = "creator".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 0})).unwrap()).unwrap();
+
+    // Keys created with the default permissions can only be administered by their owner
+    let permissions = json!({"read": [["a_user", "any"]], "write": [["a_user", "other"]], "admin": [["a_user", "user"]]});
+
+    conn.user_profile.name = "user".to_string();
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "database0", "key": "key", "val": permissions})).unwrap()).is_err());
+
+    conn.user_profile.name = "creator".to_string();
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "database0", "key": "key", "val": permissions})).unwrap()),
+                Ok(Some(json!({"cmd": "set_permissions", "mode": "ok", "msg": "", "db_key": "database0", "key": "key"}))));
+
+    conn.user_profile.name = "user".to_string();
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_permissions", "db_key": "database0", "key": "key"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_permissions", "mode": "ok", "msg": "", "db_key": "database0", "key": "key", "val": permissions}))));
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 1})).unwrap()).is_err());
+
+    conn.user_profile.name = "other".to_string();
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_permissions", "db_key": "database0", "key": "key"})).unwrap()).is_err());
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 1})).unwrap()).unwrap();
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_metadata", "db_key": "database0", "key": "key"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_metadata", "mode": "ok", "msg": "", "db_key": "database0", "key": "key", "val": {"owner": ["a_user", "creator"], "type": "string"}}))));
+
+    // Keys loaded from disk without an owner can be administered by the users server_permissions allows to, owned keys
+    // are still left to their owner
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_metadata", "db_key": "config", "key": "port"})).unwrap()).unwrap().unwrap()["val"]["owner"],
+                Value::Null);
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_permissions", "db_key": "config", "key": "port"})).unwrap()).is_err());
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "config", "key": "port", "val": permissions})).unwrap()).is_err());
+
+    conn.user_profile.name = "creator".to_string();
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "config", "key": "port", "val": permissions})).unwrap()),
+                Ok(Some(json!({"cmd": "set_permissions", "mode": "ok", "msg": "", "db_key": "config", "key": "port"}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_permissions", "db_key": "config", "key": "port"})).unwrap()).unwrap().unwrap()["val"],
+                permissions);
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "config", "key": "missing", "val": permissions})).unwrap()).is_err());
+
+    conn.user_profile.name = "other".to_string();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "owned", "val": 0})).unwrap()).unwrap();
+    conn.user_profile.name = "creator".to_string();
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "database0", "key": "owned", "val": permissions})).unwrap()).is_err());
+}
+
+#[test]
+pub fn test_group_permissions()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+
+    make_operators(&server, json!([["a_user", "term.admin"]]));
+    let mut admin = server::ServerInterface::new(&server);
+    admin.fake_auth();
+    admin.user_profile.name = "term.admin".to_string();
+    admin.user_profile.domain = "a_auth".to_string();
+
+    let mut conn = server::ServerInterface::new(&server);
+    conn.fake_auth();
+    conn.user_profile.name = "term.carter".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "groups", "val": {"operators": [["a_user", "term.jordan"]]}})).unwrap()).unwrap();
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 0})).unwrap()).unwrap();
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "database0", "key": "key",
+                    "val": {"read": [["a_user", "any"]], "write": [["group", "operators"]]}})).unwrap()).unwrap();
+
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 1})).unwrap()).is_err());
+
+    // Adding a member to the group in the config database takes effect immediately
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_path", "db_key": "config", "key": "groups", "path": "/operators/-", "val": ["a_user", "term.carter"]})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 1})).unwrap()).unwrap();
+}
+
+#[test]
+pub fn test_create_database_default_permissions()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    let mut controller = server::ServerInterface::new(&server);
+    controller.fake_auth();
+    controller.user_profile.name = "term.controller".to_string();
+    controller.user_profile.domain = "a_auth".to_string();
+
+    let mut conn = server::ServerInterface::new(&server);
+    conn.fake_auth();
+    conn.user_profile.name = "term.carter".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "status",
+                    "default_permissions": {"read": [["a_user", "any"]]}})).unwrap()).is_err());
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "status",
+                    "default_permissions": {"read": [["a_user", "any"]], "write": [["a_user", "term.controller"]]},
+                    "prefix_permissions": {"requests.": {"read": [["a_user", "any"]], "write": [["a_user", "authed"]]}}})).unwrap()),
+                Ok(Some(json!({"cmd": "create_database", "mode": "ok", "msg": "", "db_key": "status"}))));
+
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "status", "key": "state", "val": 0})).unwrap()).is_err());
+    controller.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "status", "key": "state", "val": 0})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "status", "key": "requests.carter", "val": 1})).unwrap()).unwrap();
+}
+
+#[test]
+pub fn test_server_permissions()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    let mut admin = server::ServerInterface::new(&server);
+    admin.fake_auth();
+    admin.user_profile.name = "term.admin".to_string();
+    admin.user_profile.domain = "a_auth".to_string();
+
+    let mut conn = server::ServerInterface::new(&server);
+    conn.fake_auth();
+    conn.user_profile.name = "term.carter".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    let mut other = server::ServerInterface::new(&server);
+    other.fake_auth();
+    other.user_profile.name = "term.other".to_string();
+    other.user_profile.domain = "a_auth".to_string();
+
+    // Commands without their own entry may only be run by the users of the `*` entry
+    make_operators(&server, json!([["a_user", "term.admin"], ["a_user", "term.carter"]]));
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    assert!(other.execute_command(commands::Command::from_json(json!({"cmd": "list_databases"})).unwrap()).is_err());
+    assert!(other.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database2"})).unwrap()).is_err());
+    assert!(other.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "g_domains", "val": []})).unwrap()).is_err());
+
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "server_permissions",
+                    "val": {"*": [["a_user", "term.admin"], ["a_user", "term.carter"]], "create_database": [["a_user", "term.admin"]],
+                            "unload_database": [["group", "operators"]]}})).unwrap()).unwrap();
+
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database1"})).unwrap()).is_err());
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database1"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "list_databases"})).unwrap()).unwrap();
+
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "unload_database", "db_key": "database0"})).unwrap()).is_err());
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "groups", "val": {"operators": [["a_user", "term.carter"]]}})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "unload_database", "db_key": "database0"})).unwrap()).unwrap();
+}
+
+#[test]
+pub fn test_create_existing_database()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    let mut conn = server::ServerInterface::new(&server);
+    conn.fake_auth();
+    conn.user_profile.name = "term.carter".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 0})).unwrap()).unwrap();
+
+    // An existing database is only replaced when forced
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).is_err());
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "key"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "key": "key", "db_key": "database0", "val": 0}))));
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0", "force": true})).unwrap()).unwrap();
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "key"})).unwrap()).is_err());
+}
+
+#[test]
+pub fn test_write_dirty_databases()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-dirty-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    let mut conn = server::ServerInterface::new(&server);
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database1"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 0})).unwrap()).unwrap();
+
+    assert_eq!(server.write_dirty_databases(), Ok(vec!["database0".to_string(), "database1".to_string()]));
+    assert_eq!(server.write_dirty_databases(), Ok(vec![]));
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database1", "key": "key", "val": 1})).unwrap()).unwrap();
+    assert_eq!(server.write_dirty_databases(), Ok(vec!["database1".to_string()]));
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "write_to_disk", "db_key": "database0"})).unwrap()).unwrap();
+    assert_eq!(server.write_dirty_databases(), Ok(vec![]));
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}
+
+
+#[test]
+pub fn test_autoload_databases()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.path = std::path::PathBuf::from("databases-dev");
+
+    // The server refuses to start unless it is allowed to skip databases which fail to load
+    let err = server::Server::new(&opt).err().unwrap();
+    assert!(err.contains("Unable to autoload 2 database(s)"));
+
+    opt.allow_autoload_errors = true;
+    let server = server::Server::new(&opt).unwrap();
+    assert_eq!(server.get_dbkeys(), Ok(vec!["config".to_string(), "sensors".to_string()]));
+
+    // The `status` database is in an old format, and there is no `command` database
+    let errors = server.autoload_databases();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].contains("status"));
+    assert!(errors[1].contains("command"));
+
+    let mut conn = server::ServerInterface::new(&std::sync::Arc::new(server));
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "config", "key": "dbs"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "config", "key": "dbs", "val": ["config", "status", "sensors", "command"]}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_list_length", "db_key": "sensors", "key": "load1"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_list_length", "mode": "ok", "msg": "", "db_key": "sensors", "key": "load1", "length": 0}))));
+}
+
+#[test]
+pub fn test_register_database()
+{
+    let root = std::env::temp_dir().join("aci-test-register-database");
+    std::fs::create_dir_all(root.join("config")).unwrap();
+
+    for entry in std::fs::read_dir("databases-dev/config").unwrap()
+    {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), root.join("config").join(entry.file_name())).unwrap();
+    }
+
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = root.clone();
+    opt.config_path = root.clone();
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    let mut conn = server::ServerInterface::new(&server);
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0", "register": true})).unwrap()),
+                Ok(Some(json!({"cmd": "create_database", "mode": "ok", "msg": "", "db_key": "database0"}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database1"})).unwrap()),
+                Ok(Some(json!({"cmd": "create_database", "mode": "ok", "msg": "", "db_key": "database1"}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "write_to_disk", "db_key": "database1"})).unwrap()),
+                Ok(Some(json!({"cmd": "write_to_disk", "mode": "ok", "msg": "", "db_key": "database1"}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "database1", "register": true})).unwrap()),
+                Ok(Some(json!({"cmd": "read_from_disk", "mode": "ok", "msg": "", "db_key": "database1"}))));
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "config", "key": "dbs"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "config", "key": "dbs", "val": ["config", "status", "sensors", "command", "database0", "database1"]}))));
+
+    let config = crate::database::database_from_disk(&root, "config", &opt).unwrap();
+    assert_eq!(config.database.read("dbs"), Ok(json!(["config", "status", "sensors", "command", "database0", "database1"])));
+
+    // Registering a database needs write permissions on the `dbs` key
+    let mut other = server::ServerInterface::new(&server);
+    other.fake_auth();
+    other.user_profile.name = "term.other".to_string();
+    other.user_profile.domain = "a_auth".to_string();
+
+    assert!(other.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database2", "register": true})).unwrap()).is_err());
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "config", "key": "dbs"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "config", "key": "dbs", "val": ["config", "status", "sensors", "command", "database0", "database1"]}))));
+
+    // The config database can neither be unloaded nor replaced
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "unload_database", "db_key": "config"})).unwrap()).is_err());
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "config", "force": true})).unwrap()).is_err());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+pub fn test_delete_database()
+{
+    let root = std::env::temp_dir().join("aci-test-delete-database");
+    std::fs::create_dir_all(root.join("config")).unwrap();
+
+    for entry in std::fs::read_dir("databases-dev/config").unwrap()
+    {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), root.join("config").join(entry.file_name())).unwrap();
+    }
+
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = root.clone();
+    opt.config_path = root.clone();
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    let mut conn = server::ServerInterface::new(&server);
+    conn.fake_auth();
+    conn.user_profile.name = "term.jordan".to_string();
+    conn.user_profile.domain = "a_auth".to_string();
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0", "register": true})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 0})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "write_to_disk", "db_key": "database0"})).unwrap()).unwrap();
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "delete_key", "db_key": "database0", "key": "key"})).unwrap()),
+                Ok(Some(json!({"cmd": "delete_key", "mode": "ok", "msg": "", "db_key": "database0", "key": "key"}))));
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "key"})).unwrap()).is_err());
+
+    // Unloading does not write the database, so the deleted key is still on disk
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "unload_database", "db_key": "database0"})).unwrap()),
+                Ok(Some(json!({"cmd": "unload_database", "mode": "ok", "msg": "", "db_key": "database0"}))));
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "key"})).unwrap()).is_err());
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "unload_database", "db_key": "database0"})).unwrap()).is_err());
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "database0"})).unwrap()).unwrap();
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "key"})).unwrap()),
+                Ok(Some(json!({"cmd": "get_value", "mode": "ok", "msg": "", "db_key": "database0", "key": "key", "val": 0}))));
+
+    // Names which could reach outside of the database root directory are refused
+    for name in &["", ".hidden", "..", "../config", "database0/..", "a\\b"]
+    {
+        assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": name})).unwrap()).is_err());
+        assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": name})).unwrap()).is_err());
+        assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "delete_database", "db_key": name})).unwrap()).is_err());
+    }
+    assert!(root.join("config").exists());
+
+    // Deleting is a server command, so writing to every key of the database is not enough
+    let mut other = server::ServerInterface::new(&server);
+    other.fake_auth();
+    other.user_profile.name = "term.other".to_string();
+    other.user_profile.domain = "a_auth".to_string();
+    assert!(other.execute_command(commands::Command::from_json(json!({"cmd": "delete_database", "db_key": "database0"})).unwrap()).is_err());
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "delete_database", "db_key": "database0"})).unwrap()),
+                Ok(Some(json!({"cmd": "delete_database", "mode": "ok", "msg": "", "db_key": "database0"}))));
+    assert!(!root.join("database0").exists());
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "read_from_disk", "db_key": "database0"})).unwrap()).is_err());
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "delete_database", "db_key": "config"})).unwrap()).is_err());
+
+    let config = crate::database::database_from_disk(&root, "config", &opt).unwrap();
+    assert_eq!(config.database.read("dbs"), Ok(json!(["config", "status", "sensors", "command"])));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+pub fn test_hashed_tokens()
+{
+    let token = server::generate_token().unwrap();
+    let hash = server::hash_token(&token).unwrap();
+
+    assert!(hash.starts_with("pbkdf2-sha256$"));
+    assert!(server::verify_token(&hash, &token));
+    assert!(!server::verify_token(&hash, "wrong"));
+    assert!(!server::verify_token("not a hash", &token));
+
+    // Expired tokens are refused
+    assert!(server::token_matches(&json!({"hash": hash, "expires": 200}), &token, 100));
+    assert!(!server::token_matches(&json!({"hash": hash, "expires": 200}), &token, 200));
+    assert!(server::token_matches(&json!({"hash": hash}), &token, 200));
+    assert!(!server::token_matches(&json!(token), &token, 200));
+
+    assert_eq!(server::redact_tokens(&json!({"user": {"tokens": [{"hash": hash, "label": "laptop", "expires": 200}, "plain"]}})),
+               json!({"user": {"tokens": [{"label": "laptop", "expires": 200}, "redacted"]}}));
+}
+
+#[test]
+pub fn test_mint_token()
+{
+    let root = std::env::temp_dir().join("aci-test-mint-token");
+    std::fs::create_dir_all(root.join("config")).unwrap();
+
+    for entry in std::fs::read_dir("databases-dev/config").unwrap()
+    {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), root.join("config").join(entry.file_name())).unwrap();
+    }
+
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = root.clone();
+    opt.config_path = root.clone();
+
+    let token = server::mint_token(&opt, "bots.minted", None, Some("test"), None).unwrap();
+    let expired = server::mint_token(&opt, "bots.minted", None, None, Some(0)).unwrap();
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    let mut conn = server::ServerInterface::new(&server);
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "bots.minted", "token": expired})).unwrap()),
+                Ok(Some(json!({"cmd": "a_auth", "mode": "ok", "msg": "Failed, token incorrect"}))));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "bots.minted", "token": token})).unwrap()).unwrap().unwrap()["msg"],
+                json!("success"));
+
+    // Token hashes are never handed out, even to users allowed to read the key
+    let users = conn.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "config", "key": "a_users"})).unwrap()).unwrap().unwrap();
+    assert_eq!(users["val"]["bots.minted"]["tokens"][0], json!({"label": "test"}));
+    assert_eq!(users["val"]["term.jordan"]["tokens"], json!([{}]));
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_path", "db_key": "config", "key": "a_users", "path": "/bots.minted/tokens/0"})).unwrap()).unwrap().unwrap()["val"],
+               json!({"label": "test"}));
+
+    // Writing back the redacted value would replace the tokens, so values without the token hashes are refused
+    let mut admin = server::ServerInterface::new(&server);
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.jordan", "token": "AbDc314"})).unwrap()).unwrap();
+    assert!(admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "a_users", "val": users["val"]})).unwrap()).is_err());
+    assert!(admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "a_users",
+                    "val": {"term.jordan": {"tokens": ["plain"]}}})).unwrap()).is_err());
+
+    // Every command which changes the key is checked, including the commands of a transaction
+    let refused = [json!({"cmd": "set_path", "db_key": "config", "key": "a_users", "path": "/term.jordan/tokens/-", "val": "plain"}),
+                   json!({"cmd": "set_path", "db_key": "config", "key": "a_users", "path": "/term.new", "val": {"tokens": ["plain"]}}),
+                   json!({"cmd": "patch_value", "db_key": "config", "key": "a_users", "val": {"term.new": {"tokens": ["plain"]}}}),
+                   json!({"cmd": "patch_value", "db_key": "config", "key": "a_users", "val": [{"op": "add", "path": "/term.jordan/tokens/-", "value": "plain"}]}),
+                   json!({"cmd": "transaction", "cmds": [{"cmd": "set_path", "db_key": "config", "key": "a_users", "path": "/term.new", "val": {"tokens": ["plain"]}}]})];
+
+    for command in refused.iter()
+    {
+        assert!(admin.execute_command(commands::Command::from_json(command.clone()).unwrap()).is_err(), "{} was accepted", command);
+    }
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.new", "token": "plain"})).unwrap()),
+                Ok(Some(json!({"cmd": "a_auth", "mode": "ok", "msg": "Failed, a_user not found"}))));
+
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_path", "db_key": "config", "key": "a_users", "path": "/term.new",
+                    "val": {"tokens": [{"hash": server::hash_token("minted").unwrap()}]}})).unwrap()).unwrap();
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.new", "token": "minted"})).unwrap()).unwrap().unwrap()["msg"],
+                json!("success"));
+
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "a_users",
+                    "val": {"term.jordan": {"tokens": [{"hash": server::hash_token("other").unwrap()}]}}})).unwrap()).unwrap();
+
+    // Plaintext tokens are migrated by giving them to `mint-token`
+    drop(admin);
+    drop(conn);
+    drop(server);
+    std::fs::copy("databases-dev/config/a_users.item", root.join("config").join("a_users.item")).unwrap();
+    let config = crate::database::database_from_disk(&opt.config_path, "config", &opt).unwrap();
+    config.database.write("a_users", json!({"term.legacy": {"tokens": ["legacy"]}})).unwrap();
+    crate::database::database_to_disk(&opt.config_path, config, &opt).unwrap();
+
+    assert_eq!(server::mint_token(&opt, "term.legacy", Some("legacy"), None, None), Ok("legacy".to_string()));
+    let config = crate::database::database_from_disk(&opt.config_path, "config", &opt).unwrap();
+    assert_eq!(config.database.read("a_users").unwrap()["term.legacy"]["tokens"].as_array().unwrap().len(), 1);
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    let mut conn = server::ServerInterface::new(&server);
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.legacy", "token": "legacy"})).unwrap()).unwrap().unwrap()["msg"],
+                json!("success"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+pub fn test_sessions()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    let mut conn = server::ServerInterface::new(&server);
+
+    let response = conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.jordan", "token": "AbDc314"})).unwrap()).unwrap().unwrap();
+    let session = response["session"].clone();
+    assert!(session.is_string());
+
+    // A new connection can pick up the session without the long lived token
+    let mut resumed = server::ServerInterface::new(&server);
+    assert_eq!(resumed.execute_command(commands::Command::from_json(json!({"cmd": "resume", "session": session})).unwrap()),
+                Ok(Some(json!({"cmd": "resume", "mode": "ok", "msg": "success", "connection": resumed.connection_id()}))));
+    assert_eq!(resumed.user_profile.name, "term.jordan");
+    assert_eq!(resumed.user_profile.domain, "a_auth");
+    assert!(resumed.user_profile.is_authed);
+
+    // Tampered tokens are refused
+    let tampered = format!("{}x", session.as_str().unwrap());
+    assert!(server::ServerInterface::new(&server).execute_command(commands::Command::from_json(json!({"cmd": "resume", "session": tampered})).unwrap()).is_err());
+
+    assert_eq!(resumed.execute_command(commands::Command::from_json(json!({"cmd": "logout"})).unwrap()),
+                Ok(Some(json!({"cmd": "logout", "mode": "ok", "msg": ""}))));
+    assert!(!resumed.user_profile.is_authed);
+    assert!(resumed.execute_command(commands::Command::from_json(json!({"cmd": "list_databases"})).unwrap()).is_err());
+
+    // Logging out ends the session for every connection
+    assert!(server::ServerInterface::new(&server).execute_command(commands::Command::from_json(json!({"cmd": "resume", "session": session})).unwrap()).is_err());
+
+    // Revoking the token a session was started with ends the session, even once the token is listed again
+    let session = conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.jordan", "token": "AbDc314"})).unwrap()).unwrap().unwrap()["session"].clone();
+    let resume = |session: &Value| server::ServerInterface::new(&server).execute_command(commands::Command::from_json(json!({"cmd": "resume", "session": session})).unwrap()).is_ok();
+    assert!(resume(&session));
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "delete_path", "db_key": "config", "key": "a_users", "path": "/term.jordan/tokens/0"})).unwrap()).unwrap();
+    assert!(!resume(&session));
+
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "set_path", "db_key": "config", "key": "a_users", "path": "/term.jordan/tokens/-", "val": "AbDc314"})).unwrap()).is_err());
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_path", "db_key": "config", "key": "a_users", "path": "/term.jordan/tokens/-",
+                    "val": {"hash": server::hash_token("AbDc314").unwrap()}})).unwrap()).unwrap();
+    assert!(!resume(&session));
+
+    // Sessions expire after their lifetime
+    let store = server::SessionStore::new(0).unwrap();
+    let (_, token) = store.start(&conn.user_profile, &Value::Null).unwrap();
+    assert!(store.resume(&token).is_err());
+}
+
+/// Provider accepting a single fixed key, as the `key_auth` domain
+#[derive(Debug)]
+struct StaticKeyProvider;
+
+impl server::AuthProvider for StaticKeyProvider
+{
+    fn domain(&self) -> &str
+    {
+        "key_auth"
+    }
+
+    fn authenticate(&self, _: &server::Server, args: &serde_json::Map<String, serde_json::Value>) -> Result<server::AuthOutcome, String>
+    {
+        if server::auth_argument(args, "key")? == "secret"
+        {
+            Ok(server::AuthOutcome::Accepted("bot".to_string(), serde_json::Value::Null))
+        }
+        else
+        {
+            Ok(server::AuthOutcome::Refused("Failed, key incorrect".to_string()))
+        }
+    }
+}
+
+#[test]
+pub fn test_auth_providers()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+
+    make_operators(&server, json!([["key_user", "bot"]]));
+    server.register_auth_provider(std::sync::Arc::new(StaticKeyProvider));
+
+    let mut conn = server::ServerInterface::new(&server);
+    assert!(conn.execute_command(commands::Command::from_json(json!({"cmd": "auth", "domain": "unknown_auth"})).unwrap()).is_err());
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "auth", "domain": "key_auth", "key": "wrong"})).unwrap()),
+                Ok(Some(json!({"cmd": "auth", "mode": "ok", "msg": "Failed, key incorrect"}))));
+    assert!(!conn.user_profile.is_authed);
+
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "auth", "domain": "key_auth", "key": "secret"})).unwrap()).unwrap().unwrap()["msg"],
+                json!("success"));
+    assert_eq!(conn.user_profile.name, "bot");
+    assert_eq!(conn.user_profile.domain, "key_auth");
+
+    // Users of the new domain are named in permissions by the matching `_user` domain
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "create_database", "db_key": "database0"})).unwrap()).unwrap();
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "database0", "key": "key", "val": 0})).unwrap()).unwrap();
+    assert_eq!(conn.execute_command(commands::Command::from_json(json!({"cmd": "get_metadata", "db_key": "database0", "key": "key"})).unwrap()).unwrap().unwrap()["val"]["owner"],
+                json!(["key_user", "bot"]));
+
+    let mut other = server::ServerInterface::new(&server);
+    other.execute_command(commands::Command::from_json(json!({"cmd": "auth", "domain": "a_auth", "id": "term.jordan", "token": "AbDc314"})).unwrap()).unwrap();
+    assert_eq!(other.user_profile.domain, "a_auth");
+
+    conn.execute_command(commands::Command::from_json(json!({"cmd": "set_permissions", "db_key": "database0", "key": "key",
+                    "val": {"read": [["key_user", "authed"]], "write": [["key_user", "bot"]]}})).unwrap()).unwrap();
+    assert!(other.execute_command(commands::Command::from_json(json!({"cmd": "get_value", "db_key": "database0", "key": "key"})).unwrap()).is_err());
+}
+
+/// Sign a Google id token with the fixture key in `tests/keys`
+fn sign_id_token(kid: &str, claims: serde_json::Value) -> String
+{
+    let key = std::fs::read("tests/keys/test-key.pk8").unwrap();
+    let key = ring::signature::RSAKeyPair::from_pkcs8(untrusted::Input::from(&key)).unwrap();
+    let mut state = ring::signature::RSASigningState::new(std::sync::Arc::new(key)).unwrap();
+
+    let header = json!({"alg": "RS256", "kid": kid, "typ": "JWT"}).to_string();
+    let message = format!("{}.{}", base64::encode_config(&header, base64::URL_SAFE_NO_PAD),
+                                   base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD));
+
+    let mut signature = vec![0u8; state.key_pair().public_modulus_len()];
+    state.sign(&ring::signature::RSA_PKCS1_SHA256, &ring::rand::SystemRandom::new(), message.as_bytes(), &mut signature).unwrap();
+
+    format!("{}.{}", message, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD))
+}
+
+/// Create a server with a Google provider for the client `client` verifying tokens against the fixture keys
+fn google_test_server(hosted_domain: Option<&str>) -> std::sync::Arc<server::Server>
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+    opt.google_client_id = None;
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+    assert!(server.authenticate("g_auth", &serde_json::Map::new()).is_err());
+
+    server.register_auth_provider(std::sync::Arc::new(server::GoogleAuthProvider::new("client", vec!["accounts.google.com".to_string()],
+                                                      hosted_domain.map(String::from), server::KeySet::new("tests/keys/jwks.json", 3600))));
+
+    server
+}
+
+#[test]
+pub fn test_google_id_tokens()
+{
+    let server = google_test_server(Some("example.com"));
+    make_operators(&server, json!([["a_user", "term.admin"]]));
+
+    let mut admin = server::ServerInterface::new(&server);
+    admin.fake_auth();
+    admin.user_profile.name = "term.admin".to_string();
+    admin.user_profile.domain = "a_auth".to_string();
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "g_domains", "val": ["example.com"]})).unwrap()).unwrap();
+
+    let now = server::current_time();
+    let claims = json!({"aud": "client", "iss": "accounts.google.com", "exp": now + 60, "hd": "example.com",
+                        "email": "user@example.com", "email_verified": true});
+
+    let mut conn = server::ServerInterface::new(&server);
+    let result = conn.execute_command(commands::Command::from_json(json!({"cmd": "g_auth", "id_token": sign_id_token("test-key", claims.clone())})).unwrap()).unwrap().unwrap();
+    assert_eq!(result["msg"], json!("success"));
+    assert_eq!(conn.user_profile.name, "user@example.com");
+    assert_eq!(conn.user_profile.domain, "g_auth");
+
+    let refused = |claims: serde_json::Value, kid: &str|
+    {
+        let mut conn = server::ServerInterface::new(&server);
+        let result = conn.execute_command(commands::Command::from_json(json!({"cmd": "g_auth", "id_token": sign_id_token(kid, claims)})).unwrap()).unwrap().unwrap();
+        result["msg"] != json!("success") && !conn.user_profile.is_authed
+    };
+
+    let mut wrong_audience = claims.clone();
+    wrong_audience["aud"] = json!("other");
+    assert!(refused(wrong_audience, "test-key"));
+
+    let mut wrong_issuer = claims.clone();
+    wrong_issuer["iss"] = json!("example.com");
+    assert!(refused(wrong_issuer, "test-key"));
+
+    let mut expired = claims.clone();
+    expired["exp"] = json!(now - 60);
+    assert!(refused(expired, "test-key"));
+
+    let mut wrong_domain = claims.clone();
+    wrong_domain["hd"] = json!("example.org");
+    assert!(refused(wrong_domain, "test-key"));
+
+    // Only verified email addresses are accepted
+    let mut unverified = claims.clone();
+    unverified["email_verified"] = json!(false);
+    assert!(refused(unverified.clone(), "test-key"));
+    unverified.as_object_mut().unwrap().remove("email_verified");
+    assert!(refused(unverified, "test-key"));
+
+    assert!(refused(claims.clone(), "unknown-key"));
+
+    // A token whose claims were changed after signing
+    let token = sign_id_token("test-key", claims);
+    let parts: Vec<&str> = token.split('.').collect();
+    let tampered = json!({"aud": "client", "iss": "accounts.google.com", "exp": now + 60, "hd": "example.com", "email": "admin@example.com", "email_verified": true});
+    let tampered = format!("{}.{}.{}", parts[0], base64::encode_config(tampered.to_string(), base64::URL_SAFE_NO_PAD), parts[2]);
+    let result = conn.execute_command(commands::Command::from_json(json!({"cmd": "g_auth", "id_token": tampered})).unwrap()).unwrap().unwrap();
+    assert_eq!(result["msg"], json!("Failed, JWT signature is not valid"));
+}
+
+#[test]
+pub fn test_remote_key_set()
+{
+    // Keys from a URL are fetched in the background, so a token is refused straight away rather than waiting on them
+    let keys = server::KeySet::new("http://127.0.0.1:9/jwks.json", 3600);
+    let started = std::time::Instant::now();
+
+    assert!(keys.get("test-key").is_err());
+    assert!(keys.get("test-key").is_err());
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+}
+
+#[test]
+pub fn test_g_users_allow_list()
+{
+    let server = google_test_server(None);
+    make_operators(&server, json!([["a_user", "term.jordan"]]));
+
+    let mut admin = server::ServerInterface::new(&server);
+    admin.fake_auth();
+    admin.user_profile.name = "term.jordan".to_string();
+    admin.user_profile.domain = "a_auth".to_string();
+
+    let now = server::current_time();
+    let token = |email: &str, hd: Option<&str>|
+    {
+        let mut claims = json!({"aud": "client", "iss": "accounts.google.com", "exp": now + 60, "email": email, "email_verified": true});
+
+        if let Some(hd) = hd
+        {
+            claims["hd"] = json!(hd);
+        }
+
+        sign_id_token("test-key", claims)
+    };
+
+    let authenticate = |id_token: String|
+    {
+        let mut conn = server::ServerInterface::new(&server);
+        let result = conn.execute_command(commands::Command::from_json(json!({"cmd": "g_auth", "id_token": id_token})).unwrap()).unwrap().unwrap();
+        (result["msg"].clone(), conn.user_profile.is_authed)
+    };
+
+    // Accounts listed in `g_users` are allowed, any other account is refused even though its token is valid
+    assert_eq!(authenticate(token("carter.plasek@scienceandpizza.com", None)), (json!("success"), true));
+    assert_eq!(authenticate(token("someone@gmail.com", None)), (json!("Failed, g_user not allowed"), false));
+    assert_eq!(authenticate(token("someone@example.com", Some("example.com"))), (json!("Failed, g_user not allowed"), false));
+
+    // Changes to the allow-list take effect immediately
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_path", "db_key": "config", "key": "g_users", "path": "/someone@gmail.com", "val": {}})).unwrap()).unwrap();
+    assert_eq!(authenticate(token("someone@gmail.com", None)), (json!("success"), true));
+
+    // Hosted domains allow every account of the domain, going by the `hd` claim rather than the email address
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "g_domains", "val": ["example.com"]})).unwrap()).unwrap();
+    assert_eq!(authenticate(token("someone@example.com", Some("example.com"))), (json!("success"), true));
+    assert_eq!(authenticate(token("someone@example.com", None)), (json!("Failed, g_user not allowed"), false));
+
+    // Sessions of accounts removed from the allow-list can no longer be resumed
+    let mut conn = server::ServerInterface::new(&server);
+    let session = conn.execute_command(commands::Command::from_json(json!({"cmd": "g_auth", "id_token": token("someone@gmail.com", None)})).unwrap()).unwrap().unwrap()["session"].clone();
+    let resume = |session: &Value| server::ServerInterface::new(&server).execute_command(commands::Command::from_json(json!({"cmd": "resume", "session": session})).unwrap()).is_ok();
+    assert!(resume(&session));
+
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "delete_path", "db_key": "config", "key": "g_users", "path": "/someone@gmail.com"})).unwrap()).unwrap();
+    assert!(!resume(&session));
+}
+
+#[test]
+pub fn test_event_connections()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+
+    let connect = ||
+    {
+        let mut conn = server::ServerInterface::new(&server);
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        conn.attach_events(tx);
+        (conn, rx)
+    };
+
+    let (mut first, mut first_rx) = connect();
+    let (mut second, mut second_rx) = connect();
+    let (_, mut anonymous_rx) = connect();
+    assert_ne!(first.connection_id(), second.connection_id());
+
+    // Unauthenticated connections are not registered under any name
+    assert_eq!(server.events.send_to_user(&server::Recipient::parse(""), &json!({"cmd": "event"})), 0);
+    assert!(anonymous_rx.try_recv().is_err());
+
+    let result = first.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.carter", "token": "AbDc314"})).unwrap()).unwrap().unwrap();
+    assert_eq!(result["connection"], json!(first.connection_id()));
+    second.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.carter", "token": "AbDc314"})).unwrap()).unwrap();
+
+    let mut both = vec![first.connection_id(), second.connection_id()];
+    both.sort_unstable();
+    assert_eq!(server.events.connections(&server::Recipient::parse("term.carter")), both);
+
+    // Events for a user reach every connection of the user, or only the one they are addressed to
+    assert_eq!(server.events.send_to_user(&server::Recipient::parse("term.carter"), &json!({"n": 1})), 2);
+    assert_eq!(first_rx.try_recv(), Ok(json!({"n": 1})));
+    assert_eq!(second_rx.try_recv(), Ok(json!({"n": 1})));
+
+    assert!(server.events.send_to_connection(&server::Recipient::parse("term.carter"), second.connection_id(), &json!({"n": 2})));
+    assert!(first_rx.try_recv().is_err());
+    assert_eq!(second_rx.try_recv(), Ok(json!({"n": 2})));
+    assert!(!server.events.send_to_connection(&server::Recipient::parse("term.jordan"), second.connection_id(), &json!({"n": 3})));
+
+    // Closing one connection leaves the other connected
+    first.detach_events();
+    assert_eq!(server.events.connections(&server::Recipient::parse("term.carter")), vec![second.connection_id()]);
+    assert_eq!(server.events.send_to_user(&server::Recipient::parse("term.carter"), &json!({"n": 4})), 1);
+    assert_eq!(second_rx.try_recv(), Ok(json!({"n": 4})));
+
+    // Reauthenticating moves the connection to the new user, and logging out removes it
+    second.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.jordan", "token": "AbDc314"})).unwrap()).unwrap();
+    assert!(server.events.connections(&server::Recipient::parse("term.carter")).is_empty());
+    assert_eq!(server.events.owner(second.connection_id()), Some(server::Recipient::parse("term.jordan")));
+
+    second.execute_command(commands::Command::from_json(json!({"cmd": "logout"})).unwrap()).unwrap();
+    assert!(server.events.connections(&server::Recipient::parse("term.jordan")).is_empty());
+
+    // Users of other domains are kept apart from a_users of the same name, and are addressed with their domain
+    #[derive(Debug)]
+    struct ImpostorProvider;
+
+    impl server::AuthProvider for ImpostorProvider
+    {
+        fn domain(&self) -> &str
+        {
+            "key_auth"
+        }
+
+        fn authenticate(&self, _: &server::Server, _: &serde_json::Map<String, serde_json::Value>) -> Result<server::AuthOutcome, String>
+        {
+            Ok(server::AuthOutcome::Accepted("term.carter".to_string(), serde_json::Value::Null))
+        }
+    }
+
+    server.register_auth_provider(std::sync::Arc::new(ImpostorProvider));
+    second.execute_command(commands::Command::from_json(json!({"cmd": "auth", "domain": "key_auth"})).unwrap()).unwrap();
+    assert_eq!(second.user_profile.domain, "key_auth");
+
+    let impostor = server::Recipient::parse("key_user:term.carter");
+    assert_eq!(impostor.domain, "key_auth");
+    assert_eq!(impostor.address(), "key_user:term.carter");
+    assert_eq!(server::Recipient::parse("term.carter").address(), "term.carter");
+    assert_eq!(server.events.connections(&impostor), vec![second.connection_id()]);
+    assert!(server.events.connections(&server::Recipient::parse("term.carter")).is_empty());
+
+    assert_eq!(server.events.send_to_user(&server::Recipient::parse("term.carter"), &json!({"n": 5})), 0);
+    assert!(!server.events.send_to_connection(&server::Recipient::parse("term.carter"), second.connection_id(), &json!({"n": 6})));
+    assert!(second_rx.try_recv().is_err());
+    assert!(server.events.send_to_connection(&impostor, second.connection_id(), &json!({"n": 7})));
+    assert_eq!(second_rx.try_recv(), Ok(json!({"n": 7})));
+}
+
+#[test]
+pub fn test_event_destinations()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+
+    make_operators(&server, json!([["a_user", "term.admin"]]));
+
+    let mut admin = server::ServerInterface::new(&server);
+    admin.fake_auth();
+    admin.user_profile.name = "term.admin".to_string();
+    admin.user_profile.domain = "a_auth".to_string();
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "groups",
+                    "val": {"operators": [["a_user", "term.jordan"], ["a_user", "term.carter"], ["a_user", "term.offline"]]}})).unwrap()).unwrap();
+
+    let connect = |id: &str, token: &str|
+    {
+        let mut conn = server::ServerInterface::new(&server);
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        conn.attach_events(tx);
+        conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": id, "token": token})).unwrap()).unwrap();
+        (conn, rx)
+    };
+
+    let (jordan, mut jordan_rx) = connect("term.jordan", "AbDc314");
+    let (_carter, mut carter_rx) = connect("term.carter", "AbDc314");
+    let (_laura, mut laura_rx) = connect("bots.laura", "AaCt56Kg9");
+
+    // A list of users reports who was sent the event and who is offline
+    assert_eq!(server.send_event(&json!(["term.carter", "bots.laura", "term.offline"]), jordan.connection_id(), &jordan.user_profile, &json!({"n": 1})),
+               Ok(server::Delivery{delivered: vec!["term.carter".to_string(), "bots.laura".to_string()], offline: vec!["term.offline".to_string()], queued: vec![], denied: vec![]}));
+    assert_eq!(carter_rx.try_recv(), Ok(json!({"n": 1})));
+    assert_eq!(laura_rx.try_recv(), Ok(json!({"n": 1})));
+    assert!(jordan_rx.try_recv().is_err());
+
+    // A group reaches each of its members once
+    assert_eq!(server.send_event(&json!({"group": "operators"}), jordan.connection_id(), &jordan.user_profile, &json!({"n": 2})),
+               Ok(server::Delivery{delivered: vec!["term.jordan".to_string(), "term.carter".to_string()], offline: vec!["term.offline".to_string()], queued: vec![], denied: vec![]}));
+    assert_eq!(jordan_rx.try_recv(), Ok(json!({"n": 2})));
+    assert_eq!(carter_rx.try_recv(), Ok(json!({"n": 2})));
+    assert!(carter_rx.try_recv().is_err());
+    assert!(laura_rx.try_recv().is_err());
+
+    // A broadcast reaches every connection except the one it came from
+    assert_eq!(server.send_event(&json!("*"), jordan.connection_id(), &jordan.user_profile, &json!({"n": 3})),
+               Ok(server::Delivery{delivered: vec!["bots.laura".to_string(), "term.carter".to_string()], offline: vec![], queued: vec![], denied: vec![]}));
+    assert!(jordan_rx.try_recv().is_err());
+    assert_eq!(carter_rx.try_recv(), Ok(json!({"n": 3})));
+    assert_eq!(laura_rx.try_recv(), Ok(json!({"n": 3})));
+
+    assert!(server.send_event(&json!(3), jordan.connection_id(), &jordan.user_profile, &json!({"n": 4})).is_err());
+    assert!(server.send_event(&json!({"user": "term.carter"}), jordan.connection_id(), &jordan.user_profile, &json!({"n": 4})).is_err());
+}
+
+#[test]
+pub fn test_event_queue()
+{
+    let root = std::env::temp_dir().join("aci-test-event-queue");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = root.clone();
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+
+    let mut jordan = server::ServerInterface::new(&server);
+    jordan.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.jordan", "token": "AbDc314"})).unwrap()).unwrap();
+
+    let mut delivery = server.send_event(&json!(["term.carter", "bots.laura"]), 0, &jordan.user_profile, &json!({"event_id": "first"})).unwrap();
+    server.queue_event(&mut delivery, &json!({"event_id": "first"}), None);
+    assert_eq!(delivery.queued, vec!["term.carter".to_string(), "bots.laura".to_string()]);
+
+    let mut delivery = server.send_event(&json!("term.carter"), 0, &jordan.user_profile, &json!({"event_id": "second"})).unwrap();
+    server.queue_event(&mut delivery, &json!({"event_id": "second"}), Some(60));
+
+    // Expired events are dropped
+    server.event_queue.push("term.carter", &json!({"event_id": "expired"}), 0).unwrap();
+
+    // Queued events survive a restart
+    drop(server);
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+
+    let mut laura = server::ServerInterface::new(&server);
+    laura.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "bots.laura", "token": "AaCt56Kg9"})).unwrap()).unwrap();
+    let pending = laura.execute_command(commands::Command::from_json(json!({"cmd": "get_queue"})).unwrap()).unwrap().unwrap();
+    assert_eq!(pending["val"].as_array().unwrap().len(), 1);
+    assert_eq!(pending["val"][0]["event"], json!({"event_id": "first"}));
+    assert_eq!(laura.execute_command(commands::Command::from_json(json!({"cmd": "purge_queue"})).unwrap()).unwrap().unwrap()["val"], json!(1));
+    assert_eq!(server.event_queue.pending("bots.laura"), Vec::<serde_json::Value>::new());
+
+    // The queue drains in order when the user authenticates
+    let mut carter = server::ServerInterface::new(&server);
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    carter.attach_events(tx);
+    carter.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.carter", "token": "AbDc314"})).unwrap()).unwrap();
+
+    assert_eq!(rx.try_recv(), Ok(json!({"event_id": "first"})));
+    assert_eq!(rx.try_recv(), Ok(json!({"event_id": "second"})));
+    assert!(rx.try_recv().is_err());
+    assert_eq!(server.event_queue.pending("term.carter"), Vec::<serde_json::Value>::new());
+
+    // Purging can be limited to one event id
+    server.event_queue.push("term.jordan", &json!({"event_id": "a"}), 60).unwrap();
+    server.event_queue.push("term.jordan", &json!({"event_id": "b"}), 60).unwrap();
+    assert_eq!(server.event_queue.purge("term.jordan", Some(&json!("a"))), Ok(1));
+    assert_eq!(server.event_queue.take("term.jordan"), Ok(vec![json!({"event_id": "b"})]));
+
+    // Events which could not be sent stay queued, in order
+    server.event_queue.push("term.jordan", &json!({"event_id": "c"}), 60).unwrap();
+    server.event_queue.push("term.jordan", &json!({"event_id": "d"}), 60).unwrap();
+    server.event_queue.push("term.jordan", &json!({"event_id": "e"}), 60).unwrap();
+    let mut sent = vec![];
+    assert_eq!(server.event_queue.deliver("term.jordan", |event| if sent.is_empty() { sent.push(event); true } else { false }), Ok(2));
+    assert_eq!(sent, vec![json!({"event_id": "c"})]);
+
+    let mut closed = server::ServerInterface::new(&server);
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    closed.attach_events(tx);
+    drop(rx);
+    assert!(closed.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": "term.jordan", "token": "AbDc314"})).unwrap()).is_err());
+    assert_eq!(server.event_queue.take("term.jordan"), Ok(vec![json!({"event_id": "d"}), json!({"event_id": "e"})]));
+
+    // A ttl too large to add to the time is capped rather than overflowing
+    let before = server::current_time();
+    let mut delivery = server::Delivery{offline: vec!["term.jordan".to_string()], ..Default::default()};
+    server.queue_event(&mut delivery, &json!({"event_id": "long"}), Some(u64::MAX));
+    assert_eq!(delivery.queued, vec!["term.jordan".to_string()]);
+    let expires = server.event_queue.pending("term.jordan")[0]["expires"].as_u64().unwrap();
+    assert!(expires >= before + opt.event_queue_max_ttl && expires <= server::current_time() + opt.event_queue_max_ttl);
+    server.event_queue.push("term.jordan", &json!({"event_id": "forever"}), u64::MAX).unwrap();
+    assert_eq!(server.event_queue.pending("term.jordan")[1]["expires"], json!(u64::MAX));
+    server.event_queue.purge("term.jordan", None).unwrap();
+
+    // A corrupt queue file is moved aside and the server starts with an empty queue
+    drop(server);
+    std::fs::write(root.join("event_queue.json"), "{\"term.carter\": [").unwrap();
+    let server = server::Server::new(&opt).unwrap();
+    assert_eq!(server.event_queue.pending("term.carter"), Vec::<serde_json::Value>::new());
+    assert!(root.join("event_queue.json.corrupt").exists());
+    server.event_queue.push("term.carter", &json!({"event_id": "after"}), 60).unwrap();
+    assert!(!root.join("event_queue.json.tmp").exists());
+    assert!(serde_json::from_str::<serde_json::Value>(&std::fs::read_to_string(root.join("event_queue.json")).unwrap()).is_ok());
+}
+
+#[test]
+pub fn test_event_queue_limits()
+{
+    let queue = server::EventQueue::new(2, 3);
+
+    queue.push("term.carter", &json!({"event_id": "a"}), 60).unwrap();
+    queue.push("term.carter", &json!({"event_id": "b"}), 60).unwrap();
+    assert!(queue.push("term.carter", &json!({"event_id": "c"}), 60).is_err());
+    assert_eq!(queue.pending("term.carter").len(), 2);
+
+    queue.push("term.jordan", &json!({"event_id": "a"}), 60).unwrap();
+    assert!(queue.push("bots.laura", &json!({"event_id": "a"}), 60).is_err());
+
+    // Users whose events could not be queued are left out of the queued users
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-event-queue-limits");
+    let _ = std::fs::remove_dir_all(&opt.path);
+    std::fs::create_dir_all(&opt.path).unwrap();
+    opt.event_queue_user_limit = 1;
+
+    let server = server::Server::new(&opt).unwrap();
+    let mut delivery = server::Delivery{offline: vec!["term.carter".to_string(), "bots.laura".to_string()], ..Default::default()};
+    server.queue_event(&mut delivery, &json!({"event_id": "first"}), None);
+    assert_eq!(delivery.queued, vec!["term.carter".to_string(), "bots.laura".to_string()]);
+
+    let mut delivery = server::Delivery{offline: vec!["term.carter".to_string()], ..Default::default()};
+    server.queue_event(&mut delivery, &json!({"event_id": "second"}), None);
+    assert!(delivery.queued.is_empty());
+}
+
+#[test]
+pub fn test_rpc_events()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+
+    let connect = |id: &str, token: &str|
+    {
+        let mut conn = server::ServerInterface::new(&server);
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        conn.attach_events(tx);
+        conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": id, "token": token})).unwrap()).unwrap();
+        (conn, rx)
+    };
+
+    let (panel, mut panel_rx) = connect("term.carter", "AbDc314");
+    let (mut bot, mut bot_rx) = connect("bots.laura", "AaCt56Kg9");
+    let (mut other, mut other_rx) = connect("term.jordan", "AbDc314");
+
+    let event = |event_id: &str| json!({"cmd": "event", "event_id": event_id, "destination": "bots.laura", "origin": "term.carter",
+                                        "data": "actuate relay", "rpc": true, "timeout": 5});
+    let args = |value: serde_json::Value| value.as_object().unwrap().clone();
+
+    let (delivery, call) = server.relay_event(panel.connection_id(), &panel.user_profile, &args(event("relay"))).unwrap();
+    assert_eq!(delivery.delivered, vec!["bots.laura".to_string()]);
+    assert_eq!(call.unwrap().1, 5);
+    assert_eq!(bot_rx.try_recv().unwrap()["origin_connection"], json!(panel.connection_id()));
+
+    // Event ids of pending calls are unique
+    assert!(server.relay_event(panel.connection_id(), &panel.user_profile, &args(event("relay"))).is_err());
+
+    // Only the destination can reply, and the reply is routed to the connection which sent the event
+    assert!(other.execute_command(commands::Command::from_json(json!({"cmd": "event_reply", "event_id": "relay", "data": "done"})).unwrap()).is_err());
+    assert_eq!(bot.execute_command(commands::Command::from_json(json!({"cmd": "event_reply", "event_id": "relay", "data": "done"})).unwrap()),
+               Ok(Some(json!({"cmd": "event_reply", "mode": "ack", "msg": "", "event_id": "relay"}))));
+    assert_eq!(panel_rx.try_recv(), Ok(json!({"cmd": "event_reply", "mode": "ok", "msg": "", "event_id": "relay", "origin": "bots.laura", "data": "done"})));
+    assert!(bot.execute_command(commands::Command::from_json(json!({"cmd": "event_reply", "event_id": "relay", "data": "done"})).unwrap()).is_err());
+
+    // Replies can report a failure
+    server.relay_event(panel.connection_id(), &panel.user_profile, &args(event("failing"))).unwrap();
+    bot.execute_command(commands::Command::from_json(json!({"cmd": "event_reply", "event_id": "failing", "data": null, "error": "relay stuck"})).unwrap()).unwrap();
+    assert_eq!(panel_rx.try_recv().unwrap()["mode"], json!("error"));
+
+    // A call which times out sends an error to the connection which sent the event, and can no longer be replied to
+    let (_, call) = server.relay_event(panel.connection_id(), &panel.user_profile, &args(event("slow"))).unwrap();
+    let (number, _) = call.unwrap();
+    server.expire_rpc(panel.connection_id(), "slow", number + 1);
+    server.expire_rpc(other.connection_id(), "slow", number);
+    assert!(panel_rx.try_recv().is_err());
+    server.expire_rpc(panel.connection_id(), "slow", number);
+    assert_eq!(panel_rx.try_recv().unwrap()["msg"], json!("No reply to rpc event `slow` from `bots.laura` before the timeout"));
+    assert!(bot.execute_command(commands::Command::from_json(json!({"cmd": "event_reply", "event_id": "slow", "data": "done"})).unwrap()).is_err());
+
+    // Calls from different connections may share an event id, the reply then names the connection the event came from
+    server.relay_event(panel.connection_id(), &panel.user_profile, &args(event("shared"))).unwrap();
+    server.relay_event(other.connection_id(), &other.user_profile, &args(event("shared"))).unwrap();
+    assert!(bot.execute_command(commands::Command::from_json(json!({"cmd": "event_reply", "event_id": "shared", "data": "done"})).unwrap()).is_err());
+    assert!(bot.execute_command(commands::Command::from_json(json!({"cmd": "event_reply", "event_id": "shared", "data": "done",
+                                                                    "connection": bot.connection_id()})).unwrap()).is_err());
+    bot.execute_command(commands::Command::from_json(json!({"cmd": "event_reply", "event_id": "shared", "data": "other",
+                                                            "connection": other.connection_id()})).unwrap()).unwrap();
+    assert_eq!(other_rx.try_recv().unwrap()["data"], json!("other"));
+    assert!(panel_rx.try_recv().is_err());
+    bot.execute_command(commands::Command::from_json(json!({"cmd": "event_reply", "event_id": "shared", "data": "panel"})).unwrap()).unwrap();
+    assert_eq!(panel_rx.try_recv().unwrap()["data"], json!("panel"));
+
+    // Timeouts are capped, so waiting for the reply never overflows the clock
+    let mut endless = event("endless");
+    endless["timeout"] = json!(u64::MAX);
+    let (_, call) = server.relay_event(panel.connection_id(), &panel.user_profile, &args(endless)).unwrap();
+    assert_eq!(call.unwrap().1, opt.rpc_max_timeout);
+
+    // Rpc events need a single connected destination and an authenticated origin
+    let mut offline = event("offline");
+    offline["destination"] = json!("term.offline");
+    assert!(server.relay_event(panel.connection_id(), &panel.user_profile, &args(offline)).is_err());
+    assert!(server.relay_event(panel.connection_id(), &panel.user_profile, &args(event("offline"))).is_ok());
+
+    let mut broadcast = event("broadcast");
+    broadcast["destination"] = json!("*");
+    assert!(server.relay_event(panel.connection_id(), &panel.user_profile, &args(broadcast)).is_err());
+
+    let anonymous = server::ServerInterface::new(&server);
+    assert!(server.relay_event(anonymous.connection_id(), &anonymous.user_profile, &args(event("anonymous"))).is_err());
+}
+
+#[test]
+pub fn test_event_permissions()
+{
+    let mut opt = args::Arguments::from_args();
+    opt.allow_autoload_errors = true;
+    opt.path = std::path::PathBuf::from("test-databases");
+
+    let server = std::sync::Arc::new(server::Server::new(&opt).unwrap());
+
+    make_operators(&server, json!([["a_user", "term.admin"]]));
+
+    let connect = |id: &str, token: &str|
+    {
+        let mut conn = server::ServerInterface::new(&server);
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        conn.attach_events(tx);
+        conn.execute_command(commands::Command::from_json(json!({"cmd": "a_auth", "id": id, "token": token})).unwrap()).unwrap();
+        (conn, rx)
+    };
+
+    let (jordan, _) = connect("term.jordan", "AbDc314");
+    let (carter, mut carter_rx) = connect("term.carter", "AbDc314");
+    let (laura, mut laura_rx) = connect("bots.laura", "AaCt56Kg9");
+
+    let event = |destination: serde_json::Value| json!({"cmd": "event", "event_id": "ID", "destination": destination, "origin": "term.jordan", "data": 0})
+        .as_object().unwrap().clone();
+
+    // Events require authentication, and the origin is always the sender
+    let anonymous = server::ServerInterface::new(&server);
+    assert!(server.relay_event(anonymous.connection_id(), &anonymous.user_profile, &event(json!("term.carter"))).is_err());
+    assert!(carter_rx.try_recv().is_err());
+
+    server.relay_event(laura.connection_id(), &laura.user_profile, &event(json!("term.carter"))).unwrap();
+    let received = carter_rx.try_recv().unwrap();
+    assert_eq!(received["origin"], json!("bots.laura"));
+    assert_eq!(received["origin_domain"], json!("a_auth"));
+
+    // Only the users allowed to write may send events to a user, with `*` covering every user not listed
+    let mut admin = server::ServerInterface::new(&server);
+    admin.fake_auth();
+    admin.user_profile.name = "term.admin".to_string();
+    admin.user_profile.domain = "a_auth".to_string();
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "groups", "val": {"operators": [["a_user", "term.carter"]]}})).unwrap()).unwrap();
+    admin.execute_command(commands::Command::from_json(json!({"cmd": "set_value", "db_key": "config", "key": "event_permissions",
+                    "val": {"bots.laura": {"read": [], "write": [["group", "operators"]]}, "*": {"read": [], "write": [["a_user", "authed"]]}}})).unwrap()).unwrap();
+
+    assert_eq!(server.relay_event(jordan.connection_id(), &jordan.user_profile, &event(json!("bots.laura"))).map(|(delivery, _)| delivery),
+               Err("User `term.jordan` is not permitted to send events to `bots.laura`".to_string()));
+    assert!(laura_rx.try_recv().is_err());
+
+    server.relay_event(carter.connection_id(), &carter.user_profile, &event(json!("bots.laura"))).unwrap();
+    assert_eq!(laura_rx.try_recv().unwrap()["origin"], json!("term.carter"));
+
+    let mut connection = event(json!("bots.laura"));
+    connection.insert("connection".to_string(), json!(laura.connection_id()));
+    assert!(server.relay_event(jordan.connection_id(), &jordan.user_profile, &connection).is_err());
+
+    // Recipients of a list or broadcast the sender may not send to are reported as denied
+    let (delivery, _) = server.relay_event(jordan.connection_id(), &jordan.user_profile, &event(json!(["term.carter", "bots.laura"]))).unwrap();
+    assert_eq!(delivery, server::Delivery{delivered: vec!["term.carter".to_string()], offline: vec![], queued: vec![], denied: vec!["bots.laura".to_string()]});
+
+    let (delivery, _) = server.relay_event(jordan.connection_id(), &jordan.user_profile, &event(json!("*"))).unwrap();
+    assert_eq!(delivery.delivered, vec!["term.carter".to_string()]);
+    assert_eq!(delivery.denied, vec!["bots.laura".to_string()]);
+    assert!(laura_rx.try_recv().is_err());
+}
@@ -0,0 +1,278 @@
+use crate::args::Arguments;
+use crate::database::{database_from_disk, database_to_disk};
+
+use ring::rand::SecureRandom;
+use serde_json::{Value, json};
+use log::{error, warn, info};
+
+/// Number of PBKDF2 iterations used when hashing new tokens
+const TOKEN_ITERATIONS: u32 = 100_000;
+
+/// Length of the salt and derived hash for a token, in bytes
+const HASH_LENGTH: usize = 32;
+
+/// Number of random bytes in a minted token
+const TOKEN_LENGTH: usize = 24;
+
+/// Prefix identifying the hashing scheme of a stored token
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+
+/// Fill a buffer with random bytes from the system random number generator
+fn fill_random(buffer: &mut [u8]) -> Result<(), String>
+{
+    if ring::rand::SystemRandom::new().fill(buffer).is_err()
+    {
+        let msg = "Unable to generate random bytes".to_string();
+        error!("{}", msg);
+        return Err(msg);
+    }
+
+    Ok(())
+}
+
+/// Get the current time in seconds since the unix epoch
+pub fn current_time() -> u64
+{
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+    {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0
+    }
+}
+
+/// Generate a new random token
+pub fn generate_token() -> Result<String, String>
+{
+    let mut token = [0u8; TOKEN_LENGTH];
+    fill_random(&mut token)?;
+
+    Ok(base64::encode_config(token, base64::URL_SAFE_NO_PAD))
+}
+
+/// Hash a token with a random salt, in the form `pbkdf2-sha256$ITERATIONS$SALT$HASH`
+pub fn hash_token(token: &str) -> Result<String, String>
+{
+    let mut salt = [0u8; HASH_LENGTH];
+    fill_random(&mut salt)?;
+
+    let mut hash = [0u8; HASH_LENGTH];
+    ring::pbkdf2::derive(&ring::digest::SHA256, TOKEN_ITERATIONS, &salt, token.as_bytes(), &mut hash);
+
+    Ok(format!("{}${}${}${}", HASH_SCHEME, TOKEN_ITERATIONS, base64::encode(salt), base64::encode(hash)))
+}
+
+/// Check a token against a hash produced by `hash_token`
+pub fn verify_token(hash: &str, token: &str) -> bool
+{
+    let parts: Vec<&str> = hash.split('$').collect();
+
+    if parts.len() != 4 || parts[0] != HASH_SCHEME
+    {
+        warn!("Stored token hash is not in the `{}` format", HASH_SCHEME);
+        return false;
+    }
+
+    match (parts[1].parse::<u32>(), base64::decode(parts[2]), base64::decode(parts[3]))
+    {
+        (Ok(iterations), Ok(salt), Ok(expected)) if iterations > 0 =>
+            ring::pbkdf2::verify(&ring::digest::SHA256, iterations, &salt, token.as_bytes(), &expected).is_ok(),
+        _ =>
+        {
+            warn!("Unable to parse stored token hash");
+            false
+        }
+    }
+}
+
+/// Check a token against an entry in the `tokens` list of an a_user, an object holding the token `hash` and an
+/// optional `expires` time in seconds since the unix epoch, legacy plaintext tokens are never accepted and must be
+/// migrated with `mint-token --token`
+pub fn token_matches(entry: &Value, token: &str, now: u64) -> bool
+{
+    match entry
+    {
+        Value::String(_) =>
+        {
+            warn!("A token is stored in plaintext and is not accepted, it must be migrated with `mint-token --token`");
+            false
+        },
+        Value::Object(map) =>
+        {
+            if let Some(expires) = map.get("expires")
+            {
+                match expires.as_u64()
+                {
+                    Some(expires) if expires > now => {},
+                    Some(_) => return false,
+                    None =>
+                    {
+                        warn!("Token expiry {} is not a number of seconds", expires);
+                        return false;
+                    }
+                }
+            }
+
+            match map.get("hash").and_then(Value::as_str)
+            {
+                Some(hash) => verify_token(hash, token),
+                None => false
+            }
+        },
+        _ => false
+    }
+}
+
+/// Remove the token material from the value of the `a_users` key, leaving the labels and expiry times
+pub fn redact_tokens(users: &Value) -> Value
+{
+    let mut users = users.clone();
+
+    if let Value::Object(users) = &mut users
+    {
+        for user in users.values_mut()
+        {
+            if let Some(Value::Array(tokens)) = user.get_mut("tokens")
+            {
+                for entry in tokens.iter_mut()
+                {
+                    match entry
+                    {
+                        Value::Object(map) =>
+                        {
+                            map.remove("hash");
+                        },
+                        _ => *entry = json!("redacted")
+                    }
+                }
+            }
+        }
+    }
+
+    users
+}
+
+/// Check every token of every a_user in a new value of the `a_users` key is stored as a hash, so a redacted value read
+/// from the key cannot be written back in place of the tokens
+pub fn check_token_hashes(users: &Value) -> Result<(), String>
+{
+    let users = match users
+    {
+        Value::Object(users) => users,
+        _ =>
+        {
+            let msg = "The `a_users` key must be an object".to_string();
+            error!("{}", msg);
+            return Err(msg);
+        }
+    };
+
+    for (name, user) in users
+    {
+        let tokens = match user.get("tokens")
+        {
+            Some(Value::Array(tokens)) => tokens,
+            Some(_) =>
+            {
+                let msg = format!("Tokens for a_user `{}` are not an array", name);
+                error!("{}", msg);
+                return Err(msg);
+            },
+            None => continue
+        };
+
+        if let Some(entry) = tokens.iter().find(|entry| entry.get("hash").and_then(Value::as_str).is_none())
+        {
+            let msg = format!("Token {} for a_user `{}` does not have a hash, tokens are added with `mint-token`", entry, name);
+            error!("{}", msg);
+            return Err(msg);
+        }
+    }
+
+    Ok(())
+}
+
+/// Mint a new token for an a_user, storing its hash in the `a_users` key of the config database on disk, returns the
+/// token which is not stored anywhere, an existing token can be given in place of a new one to migrate a token stored in
+/// plaintext, which is removed from the tokens of the user
+///
+/// The config database is read from and written back to disk, a running server keeps its own copy and would overwrite
+/// the new token when it next writes the config database, so the server must be stopped while tokens are minted.
+pub fn mint_token(opt: &Arguments, user: &str, token: Option<&str>, label: Option<&str>, expires_in: Option<u64>) -> Result<String, String>
+{
+    let config = database_from_disk(&opt.config_path, "config", opt)?;
+
+    if !config.database.contains_key("a_users")
+    {
+        let msg = "Config database does not contain an `a_users` key".to_string();
+        error!("{}", msg);
+        return Err(msg);
+    }
+
+    let token = match token
+    {
+        Some(token) => token.to_string(),
+        None => generate_token()?
+    };
+
+    let mut entry = json!({"hash": hash_token(&token)?});
+
+    if let Some(label) = label
+    {
+        entry["label"] = json!(label);
+    }
+
+    if let Some(expires_in) = expires_in
+    {
+        entry["expires"] = json!(current_time() + expires_in);
+    }
+
+    let mut users = config.database.read("a_users")?;
+
+    match users.get_mut(user)
+    {
+        Some(Value::Object(data)) => match data.get_mut("tokens")
+        {
+            Some(Value::Array(tokens)) =>
+            {
+                tokens.retain(|existing| existing.as_str() != Some(&token));
+                tokens.push(entry);
+            },
+            Some(_) =>
+            {
+                let msg = format!("Tokens for a_user `{}` are not an array", user);
+                error!("{}", msg);
+                return Err(msg);
+            },
+            None =>
+            {
+                data.insert("tokens".to_string(), json!([entry]));
+            }
+        },
+        Some(_) =>
+        {
+            let msg = format!("Data for a_user `{}` is not an object", user);
+            error!("{}", msg);
+            return Err(msg);
+        },
+        None => match &mut users
+        {
+            Value::Object(users) =>
+            {
+                users.insert(user.to_string(), json!({"tokens": [entry]}));
+            },
+            _ =>
+            {
+                let msg = "The `a_users` key in the config database is not an object".to_string();
+                error!("{}", msg);
+                return Err(msg);
+            }
+        }
+    }
+
+    config.database.write("a_users", users)?;
+    database_to_disk(&opt.config_path, config, opt)?;
+
+    info!("Minted a new token for a_user `{}`", user);
+
+    Ok(token)
+}
@@ -1,7 +1,9 @@
 //! Integration tests for the DatabaseInterface object, this object is used for access to individual databases
 extern crate aci_server;
 
-use aci_server::database::{Database, DatabaseInterface, UserAuthentication};
+use aci_server::database::{Database, DatabaseInterface, DefaultPermissions, Permission, Subscriber, UserAuthentication, database_from_disk, database_to_disk, read_journal};
+
+use structopt::StructOpt;
 
 #[test]
 pub fn integration_test_database_read_write()
@@ -117,4 +119,406 @@ pub fn integration_test_database_last_n_list()
     assert_eq!(db.read_last_n_from_key("list", 6, &user), Ok(serde_json::json!([1, "2", "3", 4, 5, "6"])));
     assert_eq!(db.read_last_n_from_key("list", 7, &user), Ok(serde_json::json!([0, 1, "2", "3", 4, 5, "6"])));
     assert_eq!(db.read_last_n_from_key("list", 8, &user), Ok(serde_json::json!([0, 1, "2", "3", 4, 5, "6"])));
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn integration_test_database_subscriptions()
+{
+    let db = DatabaseInterface::new(Database::new("Database0"), chashmap::CHashMap::new());
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    db.subscribers.register(1, &user, tx);
+
+    db.write_to_key("list", serde_json::json!([0, 1]), &user).unwrap();
+    assert!(rx.try_recv().is_err());
+
+    db.subscribe("list", &user).unwrap();
+    db.subscribe("list", &user).unwrap();
+    assert_eq!(db.get_subscribers("list"), vec![Subscriber::from_user(&user)]);
+
+    db.write_to_key("list", serde_json::json!([0, 1, 2]), &user).unwrap();
+    assert_eq!(rx.try_recv().unwrap(),
+        serde_json::json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": "Database0", "key": "list", "val": [0, 1, 2]}));
+
+    db.write_to_key_index("list", 1, serde_json::json!("one"), &user).unwrap();
+    assert_eq!(rx.try_recv().unwrap(),
+        serde_json::json!({"cmd": "subscription", "mode": "update", "op": "set_index", "db_key": "Database0", "key": "list", "index": 1, "val": "one"}));
+
+    db.append_to_key("list", serde_json::json!(3), &user).unwrap();
+    assert_eq!(rx.try_recv().unwrap(),
+        serde_json::json!({"cmd": "subscription", "mode": "update", "op": "append", "db_key": "Database0", "key": "list", "index": 3, "val": 3}));
+
+    db.unsubscribe("list", &user).unwrap();
+    assert!(db.get_subscribers("list").is_empty());
+
+    db.append_to_key("list", serde_json::json!(4), &user).unwrap();
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+pub fn integration_test_database_subscriptions_disk()
+{
+    let mut opt = aci_server::args::Arguments::from_iter(Vec::<String>::new());
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-subscriptions");
+
+    let db = DatabaseInterface::new(Database::new("subs"), chashmap::CHashMap::new());
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    db.write_to_key("watched", serde_json::json!(0), &user).unwrap();
+    db.write_to_key("ignored", serde_json::json!(1), &user).unwrap();
+    db.subscribe("watched", &user).unwrap();
+
+    // A user of another domain with the same name is a separate subscriber
+    let google = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"g_auth".to_string()};
+    db.write_to_key("other", serde_json::json!(2), &google).unwrap();
+    db.subscribe("other", &google).unwrap();
+
+    database_to_disk(&opt.path, db, &opt).unwrap();
+    let item = std::fs::read_to_string(opt.path.join("subs").join("watched.item")).unwrap();
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&item).unwrap()["subs"], serde_json::json!([["a_user", "user"]]));
+
+    let db = database_from_disk(&opt.path, "subs", &opt).unwrap();
+
+    assert_eq!(db.get_subscribers("watched"), vec![Subscriber::from_user(&user)]);
+    assert_eq!(db.get_subscribers("other"), vec![Subscriber::from_user(&google)]);
+    assert!(db.get_subscribers("ignored").is_empty());
+
+    // Subscribers recorded as a bare name before subscribers had domains are a_users
+    assert_eq!(Subscriber::from_json(&serde_json::json!("user")), Ok(Subscriber::from_user(&user)));
+    assert!(Subscriber::from_json(&serde_json::json!(["unknown", "user"])).is_err());
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}
+
+#[test]
+pub fn integration_test_database_disk_failed_write()
+{
+    let mut opt = aci_server::args::Arguments::from_iter(Vec::<String>::new());
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-failed-write");
+
+    let db = DatabaseInterface::new(Database::new("atomic"), chashmap::CHashMap::new());
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    db.write_to_key("a", serde_json::json!(0), &user).unwrap();
+    db.write_to_key("b", serde_json::json!([0, 1]), &user).unwrap();
+    database_to_disk(&opt.path, db.clone(), &opt).unwrap();
+
+    // Writing a key directly to the database skips creating its permissions, so the write fails part way through
+    db.write_to_key("a", serde_json::json!(1), &user).unwrap();
+    db.database.write("c", serde_json::json!(2)).unwrap();
+    assert!(database_to_disk(&opt.path, db, &opt).is_err());
+
+    let db = database_from_disk(&opt.path, "atomic", &opt).unwrap();
+    assert_eq!(db.database.get_all_keys(), Ok(vec!["a".to_string(), "b".to_string()]));
+    assert_eq!(db.read_from_key("a", &user), Ok(serde_json::json!(0)));
+    assert_eq!(db.read_from_key("b", &user), Ok(serde_json::json!([0, 1])));
+
+    assert!(!opt.path.join(".atomic.tmp").exists());
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}
+
+#[test]
+pub fn integration_test_database_disk_interrupted_swap()
+{
+    let mut opt = aci_server::args::Arguments::from_iter(Vec::<String>::new());
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-interrupted-swap");
+
+    let db = DatabaseInterface::new(Database::new("swap"), chashmap::CHashMap::new());
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    db.write_to_key("a", serde_json::json!(0), &user).unwrap();
+    database_to_disk(&opt.path, db.clone(), &opt).unwrap();
+
+    // Simulate a crash after the previous copy was moved aside, but before the new copy was in place
+    std::fs::rename(opt.path.join("swap"), opt.path.join(".swap.old")).unwrap();
+    std::fs::create_dir_all(opt.path.join("swap")).unwrap();
+    std::fs::write(opt.path.join("swap").join("a.item"), "{\"key\": \"a\"").unwrap();
+
+    let loaded = database_from_disk(&opt.path, "swap", &opt).unwrap();
+    assert_eq!(loaded.read_from_key("a", &user), Ok(serde_json::json!(0)));
+    assert!(!opt.path.join(".swap.old").exists());
+
+    db.write_to_key("a", serde_json::json!(1), &user).unwrap();
+    database_to_disk(&opt.path, db, &opt).unwrap();
+
+    let loaded = database_from_disk(&opt.path, "swap", &opt).unwrap();
+    assert_eq!(loaded.read_from_key("a", &user), Ok(serde_json::json!(1)));
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}
+
+#[test]
+pub fn integration_test_database_journal()
+{
+    let mut opt = aci_server::args::Arguments::from_iter(Vec::<String>::new());
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-journal");
+    opt.journal = true;
+
+    let db = DatabaseInterface::new(Database::new("journal"), chashmap::CHashMap::new());
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    db.write_to_key("value", serde_json::json!(0), &user).unwrap();
+    db.write_to_key("list", serde_json::json!([0, 1]), &user).unwrap();
+    database_to_disk(&opt.path, db, &opt).unwrap();
+
+    // Changes made after loading are only recorded in the journal
+    let db = database_from_disk(&opt.path, "journal", &opt).unwrap();
+    assert!(db.journal.is_some());
+
+    db.write_to_key("value", serde_json::json!(1), &user).unwrap();
+    db.write_to_key("new", serde_json::json!("new"), &user).unwrap();
+    db.write_to_key_index("list", 0, serde_json::json!("zero"), &user).unwrap();
+    db.append_to_key("list", serde_json::json!(2), &user).unwrap();
+    assert!(db.append_to_key("value", serde_json::json!(2), &user).is_err());
+
+    // Operations which fail are not recorded
+    assert_eq!(read_journal(&db.journal.as_ref().unwrap().get_path()).unwrap().len(), 5);
+    drop(db);
+
+    let db = database_from_disk(&opt.path, "journal", &opt).unwrap();
+    assert_eq!(db.read_from_key("value", &user), Ok(serde_json::json!(1)));
+    assert_eq!(db.read_from_key("new", &user), Ok(serde_json::json!("new")));
+    assert_eq!(db.read_from_key("list", &user), Ok(serde_json::json!(["zero", 1, 2])));
+
+    // Writing to disk truncates the journal, so the entries are not applied twice
+    database_to_disk(&opt.path, db.clone(), &opt).unwrap();
+    assert!(!opt.path.join("journal").join("journal.journal").exists());
+
+    db.append_to_key("list", serde_json::json!(3), &user).unwrap();
+
+    let db = database_from_disk(&opt.path, "journal", &opt).unwrap();
+    assert_eq!(db.read_from_key("list", &user), Ok(serde_json::json!(["zero", 1, 2, 3])));
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}
+
+#[test]
+pub fn integration_test_database_atomic_operations()
+{
+    let mut opt = aci_server::args::Arguments::from_iter(Vec::<String>::new());
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-atomic-operations");
+    opt.journal = true;
+
+    let db = DatabaseInterface::new(Database::new("atomic"), chashmap::CHashMap::new());
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    db.write_to_key("relay_state", serde_json::json!("open"), &user).unwrap();
+    db.write_to_key("countdown", serde_json::json!(10), &user).unwrap();
+    db.write_to_key("armed", serde_json::json!(false), &user).unwrap();
+    database_to_disk(&opt.path, db, &opt).unwrap();
+
+    let db = database_from_disk(&opt.path, "atomic", &opt).unwrap();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    db.subscribers.register(1, &user, tx);
+    db.subscribe("countdown", &user).unwrap();
+
+    assert!(db.compare_and_set_key("missing", &serde_json::json!(null), serde_json::json!(0), &user).is_err());
+    assert_eq!(db.compare_and_set_key("relay_state", &serde_json::json!("closed"), serde_json::json!("open"), &user),
+                Ok((false, serde_json::json!("open"))));
+    assert!(read_journal(&db.journal.as_ref().unwrap().get_path()).unwrap().is_empty());
+    assert_eq!(db.compare_and_set_key("relay_state", &serde_json::json!("open"), serde_json::json!("closed"), &user),
+                Ok((true, serde_json::json!("closed"))));
+
+    assert_eq!(db.decrement_key("countdown", &serde_json::json!(1), &user), Ok(serde_json::json!(9)));
+    assert_eq!(rx.try_recv().unwrap(),
+        serde_json::json!({"cmd": "subscription", "mode": "update", "op": "set", "db_key": "atomic", "key": "countdown", "val": 9}));
+    assert_eq!(db.increment_key("countdown", &serde_json::json!(3), &user), Ok(serde_json::json!(12)));
+    assert_eq!(db.toggle_key("armed", &user), Ok(true));
+
+    db.write_to_key("users", serde_json::json!({"term.jordan": {"tokens": ["a"]}}), &user).unwrap();
+    db.write_to_key_path("users", "/term.jordan/tokens/-", serde_json::json!("b"), &user).unwrap();
+    assert_eq!(db.delete_from_key_path("users", "/term.jordan/tokens/0", &user), Ok(serde_json::json!("a")));
+    drop(db);
+
+    // The operations are replayed from the journal
+    let db = database_from_disk(&opt.path, "atomic", &opt).unwrap();
+    assert_eq!(db.read_from_key("relay_state", &user), Ok(serde_json::json!("closed")));
+    assert_eq!(db.read_from_key("countdown", &user), Ok(serde_json::json!(12)));
+    assert_eq!(db.read_from_key("armed", &user), Ok(serde_json::json!(true)));
+    assert_eq!(db.read_from_key_path("users", "/term.jordan/tokens", &user), Ok(serde_json::json!(["b"])));
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}
+
+#[test]
+pub fn integration_test_database_delete_key()
+{
+    let mut opt = aci_server::args::Arguments::from_iter(Vec::<String>::new());
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-delete-key");
+    opt.journal = true;
+
+    let db = DatabaseInterface::new(Database::new("delete"), chashmap::CHashMap::new());
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    db.write_to_key("stale", serde_json::json!(0), &user).unwrap();
+    db.write_to_key("kept", serde_json::json!(1), &user).unwrap();
+    db.subscribe("stale", &user).unwrap();
+    database_to_disk(&opt.path, db, &opt).unwrap();
+
+    let db = database_from_disk(&opt.path, "delete", &opt).unwrap();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    db.subscribers.register(1, &user, tx);
+
+    db.delete_key("stale", &user).unwrap();
+    assert_eq!(rx.try_recv().unwrap(),
+        serde_json::json!({"cmd": "subscription", "mode": "update", "op": "delete", "db_key": "delete", "key": "stale"}));
+    assert!(db.read_from_key("stale", &user).is_err());
+    assert!(db.delete_key("stale", &user).is_err());
+    assert!(db.permissions.get("stale").is_none());
+    assert!(db.get_subscribers("stale").is_empty());
+    drop(db);
+
+    // The deletion is replayed from the journal, and the item file is only removed by the next write
+    assert!(opt.path.join("delete").join("stale.item").exists());
+
+    let db = database_from_disk(&opt.path, "delete", &opt).unwrap();
+    assert_eq!(db.database.get_all_keys(), Ok(vec!["kept".to_string()]));
+
+    database_to_disk(&opt.path, db.clone(), &opt).unwrap();
+    assert!(!opt.path.join("delete").join("stale.item").exists());
+    assert!(opt.path.join("delete").join("kept.item").exists());
+
+    // Every key must be writable for the whole database to be removed
+    let other = UserAuthentication{is_authed: true, name: "other".to_string(), domain:"a_auth".to_string()};
+    assert!(db.check_write_all(&other).is_ok());
+    db.permissions.insert("kept".to_string(), Permission{read_users: vec![], write_users: vec![("a_user".to_string(), "user".to_string())], admin_users: vec![], read_groups: vec![], write_groups: vec![], admin_groups: vec![]});
+    assert!(db.check_write_all(&other).is_err());
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}
+
+#[test]
+pub fn integration_test_database_permissions()
+{
+    let mut opt = aci_server::args::Arguments::from_iter(Vec::<String>::new());
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-permissions");
+    opt.journal = true;
+
+    let db = DatabaseInterface::new(Database::new("permissions"), chashmap::CHashMap::new());
+    let admin = UserAuthentication{is_authed: true, name: "admin".to_string(), domain:"a_auth".to_string()};
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    db.write_to_key("key", serde_json::json!(0), &admin).unwrap();
+    assert_eq!(db.get_permissions("key", &admin),
+                Ok(serde_json::json!({"read": [["a_user", "any"], ["g_user", "any"]], "write": [["a_user", "any"], ["g_user", "any"]], "admin": []})));
+
+    // Only the owner may change the permissions of a key created with the default permissions
+    assert!(db.get_permissions("key", &user).is_err());
+
+    let restricted = serde_json::json!({"read": [["a_user", "any"]], "write": [["a_user", "admin"]], "admin": [["a_user", "admin"]]});
+    db.set_permissions("key", &restricted, &admin).unwrap();
+    assert!(db.get_permissions("key", &user).is_err());
+    assert!(db.set_permissions("key", &restricted, &user).is_err());
+    assert!(db.set_permissions("key", &serde_json::json!({"read": []}), &admin).is_err());
+    assert!(db.set_permissions("missing", &restricted, &admin).is_err());
+    assert!(db.write_to_key("key", serde_json::json!(1), &user).is_err());
+    database_to_disk(&opt.path, db, &opt).unwrap();
+
+    let db = database_from_disk(&opt.path, "permissions", &opt).unwrap();
+    assert_eq!(db.get_permissions("key", &admin), Ok(restricted));
+    assert!(db.write_to_key("key", serde_json::json!(1), &user).is_err());
+
+    // Changes made after loading are replayed from the journal
+    db.set_permissions("key", &serde_json::json!({"read": [], "write": [["a_user", "user"]]}), &admin).unwrap();
+    drop(db);
+
+    let db = database_from_disk(&opt.path, "permissions", &opt).unwrap();
+    db.write_to_key("key", serde_json::json!(1), &user).unwrap();
+    assert!(db.get_permissions("key", &user).is_err());
+    assert_eq!(db.get_permissions("key", &admin), Ok(serde_json::json!({"read": [], "write": [["a_user", "user"]], "admin": []})));
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}
+
+#[test]
+pub fn integration_test_database_owners()
+{
+    let mut opt = aci_server::args::Arguments::from_iter(Vec::<String>::new());
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-owners");
+    opt.journal = true;
+
+    let db = DatabaseInterface::new(Database::new("owners"), chashmap::CHashMap::new());
+    let owner = UserAuthentication{is_authed: true, name: "owner".to_string(), domain:"g_auth".to_string()};
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    db.write_to_key("owned", serde_json::json!({"a": 0}), &owner).unwrap();
+    db.write_to_key("owned", serde_json::json!({"a": 1}), &user).unwrap();
+    db.write_to_key("other", serde_json::json!([0]), &user).unwrap();
+    assert_eq!(db.get_metadata("owned", &user), Ok(serde_json::json!({"owner": ["g_user", "owner"], "type": "obj"})));
+    assert_eq!(db.get_metadata("other", &user), Ok(serde_json::json!({"owner": ["a_user", "user"], "type": "table"})));
+
+    // Owners keep every right regardless of the permissions
+    db.set_permissions("owned", &serde_json::json!({"read": [], "write": [], "admin": []}), &owner).unwrap();
+    assert!(db.read_from_key("owned", &user).is_err());
+    assert!(db.get_permissions("owned", &user).is_err());
+    db.write_to_key("owned", serde_json::json!({"a": 2}), &owner).unwrap();
+    assert_eq!(db.read_from_key("owned", &owner), Ok(serde_json::json!({"a": 2})));
+    db.get_permissions("owned", &owner).unwrap();
+
+    database_to_disk(&opt.path, db, &opt).unwrap();
+
+    let db = database_from_disk(&opt.path, "owners", &opt).unwrap();
+    assert_eq!(db.get_metadata("owned", &owner), Ok(serde_json::json!({"owner": ["g_user", "owner"], "type": "obj"})));
+    assert!(db.read_from_key("owned", &user).is_err());
+
+    // Keys created after loading have their owners replayed from the journal
+    db.write_to_key("new", serde_json::json!("new"), &owner).unwrap();
+    drop(db);
+
+    let db = database_from_disk(&opt.path, "owners", &opt).unwrap();
+    assert_eq!(db.get_metadata("new", &user), Ok(serde_json::json!({"owner": ["g_user", "owner"], "type": "string"})));
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}
+
+#[test]
+pub fn integration_test_database_default_permissions()
+{
+    let mut opt = aci_server::args::Arguments::from_iter(Vec::<String>::new());
+    opt.allow_autoload_errors = true;
+    opt.path = std::env::temp_dir().join("aci-test-default-permissions");
+    opt.journal = true;
+
+    let db = DatabaseInterface::new(Database::new("status"), chashmap::CHashMap::new());
+    let controller = UserAuthentication{is_authed: true, name: "controller".to_string(), domain:"a_auth".to_string()};
+    let user = UserAuthentication{is_authed: true, name: "user".to_string(), domain:"a_auth".to_string()};
+
+    db.set_default_permissions(DefaultPermissions::new(
+        Some(&serde_json::json!({"read": [["a_user", "any"]], "write": [["a_user", "controller"]]})),
+        Some(&serde_json::json!({"public.": {"read": [["a_user", "any"]], "write": [["a_user", "authed"]]}}))).unwrap());
+
+    // New keys can only be created by users the inherited permissions allow to write
+    assert!(db.write_to_key("state", serde_json::json!("idle"), &user).is_err());
+    db.write_to_key("state", serde_json::json!("idle"), &controller).unwrap();
+    assert!(db.write_to_key("state", serde_json::json!("busy"), &user).is_err());
+    assert_eq!(db.read_from_key("state", &user), Ok(serde_json::json!("idle")));
+
+    db.write_to_key("public.note", serde_json::json!("hello"), &user).unwrap();
+    db.write_to_key("public.note", serde_json::json!("hi"), &controller).unwrap();
+
+    database_to_disk(&opt.path, db, &opt).unwrap();
+
+    // The rules are kept in the database file
+    let db = database_from_disk(&opt.path, "status", &opt).unwrap();
+    assert!(db.write_to_key("other", serde_json::json!(0), &user).is_err());
+    db.write_to_key("public.other", serde_json::json!(0), &user).unwrap();
+    drop(db);
+
+    let db = database_from_disk(&opt.path, "status", &opt).unwrap();
+    assert_eq!(db.get_permissions("public.other", &user), Ok(serde_json::json!({"read": [["a_user", "any"]],
+                "write": [["a_user", "authed"]], "admin": []})));
+
+    std::fs::remove_dir_all(&opt.path).unwrap();
+}